@@ -94,6 +94,14 @@ void presage_handle_contact(PurpleConnection *connection, const char *uuid, cons
     purple_blist_node_set_string(&buddy->node, "phone_number", phone_number);
 }
 
+void presage_handle_contacts(PurpleConnection *connection, const Contact *contacts, size_t length) {
+    g_return_if_fail(contacts != NULL || length == 0);
+
+    for (size_t i = 0; i < length; i++) {
+        presage_handle_contact(connection, contacts[i].uuid, contacts[i].name, contacts[i].phone_number);
+    }
+}
+
 void presage_tooltip_text(PurpleBuddy *buddy, PurpleNotifyUserInfo *info, gboolean full) {
     const char *server_alias = purple_blist_node_get_string(&buddy->node, "server_alias");
     if (server_alias != NULL) {
@@ -103,4 +111,5 @@ void presage_tooltip_text(PurpleBuddy *buddy, PurpleNotifyUserInfo *info, gboole
     if (phone_number != NULL) {
         purple_notify_user_info_add_pair(info, "Number", phone_number);
     }
+    purple_notify_user_info_add_pair(info, "Safety number", presage_safety_number_verified(buddy) ? "verified" : "not verified");
 }
\ No newline at end of file
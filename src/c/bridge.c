@@ -1,4 +1,5 @@
 #include "presage.h"
+#include <glib/gstdio.h>
 
 /////////////////////////////////////////////////////////////////////
 //                                                                 //
@@ -30,68 +31,175 @@ static int account_exists(PurpleAccount *account)
     return exists;
 }
 
-void free_message(Presage * message) {
+void free_message(PresageEvent * message) {
     // release all the memory
-    presage_rust_free_string(message->qrcode);
-    presage_rust_free_string(message->uuid);
     presage_rust_free_string(message->who);
     presage_rust_free_string(message->name);
     presage_rust_free_string(message->group);
     presage_rust_free_string(message->title);
     presage_rust_free_string(message->body);
+    presage_rust_free_attachment(message->attachment);
+    presage_rust_free_groups(message->groups, message->size);
+    presage_rust_free_contacts(message->contacts, message->size);
     // message->blob is not released here – it must be released by the xfer callback
-    // TODO: free message->groups here // presage_rust_strfreev(message->members, message->size);
-
 }
 
 /*
- * Handle a message according to its content.
+ * Handle a message according to its kind.
  */
-static void handle_message(Presage * message) {
-    //purple_debug_info(PLUGIN_NAME, "handle_message({.account=%p, .qrcode=„%s“, .uuid=„%s“, .who=„%s“, .name=„%s“, .group=„%s“, .title=„%s“, .body=„%s“})\n", message->account, message->qrcode, message->uuid, message->who, message->name, message->group, message->title, message->body);
+static void handle_message(PresageEvent * message) {
+    //purple_debug_info(PLUGIN_NAME, "handle_message({.kind=%d, .account=%p, .who=„%s“, .name=„%s“, .group=„%s“, .title=„%s“, .body=„%s“})\n", message->kind, message->account, message->who, message->name, message->group, message->title, message->body);
 
-    if (message->debug >= 0) {
+    if (message->kind == PRESAGE_EVENT_KIND_LOG) {
         // log messages do not need an active connection
-        purple_debug(message->debug, PLUGIN_NAME, "%s", message->body);
+        purple_debug(message->level, PLUGIN_NAME, "%s", message->body);
         free_message(message);
         return;
     }
-    if (account_exists(message->account) == 0) {
-        purple_debug_warning(PLUGIN_NAME, "No account %p. Ignoring message.\n", message->account);
+    PurpleAccount *account = (PurpleAccount *)message->account;
+    PurpleConnection *connection = NULL;
+    Presage *presage = NULL;
+    if (account_exists(account)) {
+        connection = purple_account_get_connection(account);
+    }
+    if (connection != NULL) {
+        presage = purple_connection_get_protocol_data(connection);
+    }
+
+    if (message->kind == PRESAGE_EVENT_KIND_CLOSED) {
+        // the back-end has stopped, the channel must not be used any more
+        if (presage != NULL && presage->tx_ptr == message->tx_ptr) {
+            presage->tx_ptr = NULL;
+        }
+        presage_rust_free_channel(message->tx_ptr);
         free_message(message);
         return;
     }
-    PurpleConnection *connection = purple_account_get_connection(message->account);
-    if (connection == NULL) {
-        purple_debug_warning(PLUGIN_NAME, "No active connection for account %p. Ignoring message.\n", message->account);
+    if (presage == NULL) {
+        if (message->kind == PRESAGE_EVENT_KIND_CHANNEL) {
+            // the connection has been closed before the back-end was ready, tell it to stop
+            presage_rust_exit(message->tx_ptr);
+        }
+        if (message->kind == PRESAGE_EVENT_KIND_ATTACHMENT_DOWNLOADED && message->name != NULL && (message->flags & 1)) {
+            // nobody is going to pick up the file
+            g_remove(message->name);
+        }
+        purple_debug_warning(PLUGIN_NAME, "No active connection for account %p. Ignoring message.\n", account);
         free_message(message);
         return;
     }
 
-    Presage *presage = purple_connection_get_protocol_data(connection);
-    if (message->tx_ptr != NULL) {
-        presage->tx_ptr = message->tx_ptr; // store tx_ptr for use throughout the connection lifetime
-        presage_rust_whoami(rust_runtime, presage->tx_ptr);
-    } else if (message->qrcode != NULL) {
-        presage_handle_qrcode(connection, message->qrcode);
-    } else if (message->uuid != NULL) {
-        presage_handle_uuid(connection, message->uuid);
-    } else if (message->connected >= 0) {
-        // backend says, connection has been set-up, start receiving
-        // TODO: protect against starting more than one receiver
-        presage_rust_receive(rust_runtime, presage->tx_ptr);
-        purple_connection_set_state(connection, PURPLE_CONNECTION_STATE_CONNECTED);
-        presage_blist_buddies_all_set_online(purple_connection_get_account(connection)); // TODO: make user configurable
-    } else if (message->error >= 0) {
-        purple_connection_error(connection, message->error, message->body);
-    } else if (message->blob != NULL) {
-        presage_handle_attachment(connection, message->who, message->timestamp, message->blob, message->size, message->name);
-    } else if (message->xfer != NULL) {
-        presage_handle_xfer(message->xfer, message->flags, message->body);
-    } else if (message->body != NULL) {
-        presage_handle_text(connection, message->who, message->name, message->group, message->title, message->flags, message->timestamp, message->body);
-    } else if (message->groups != NULL) {
-        presage_handle_groups(connection, message->groups, message->size);
+    switch (message->kind) {
+        case PRESAGE_EVENT_KIND_CHANNEL:
+            presage->tx_ptr = message->tx_ptr; // store tx_ptr for use throughout the connection lifetime
+            if (purple_account_get_string(account, "import-backup", "")[0] != 0) {
+                presage_request_import(connection); // continues with whoami afterwards
+            } else {
+                presage_check_status(connection, presage_rust_whoami(presage->tx_ptr));
+            }
+            break;
+        case PRESAGE_EVENT_KIND_QR_CODE:
+            presage_handle_qrcode(connection, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_UUID:
+            presage_handle_uuid(connection, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_CONNECTED:
+            // backend says, connection has been set-up, start receiving
+            // the back-end makes sure there is only one receiver
+            if (!presage_check_status(connection, presage_rust_receive(presage->tx_ptr))) {
+                break;
+            }
+            purple_connection_set_state(connection, PURPLE_CONNECTION_STATE_CONNECTED);
+            presage_blist_buddies_all_set_online(account); // TODO: make user configurable
+            break;
+        case PRESAGE_EVENT_KIND_ERROR:
+            if (message->request_id != 0) {
+                // only this request failed, the connection is fine
+                purple_debug_warning(PLUGIN_NAME, "request %" G_GUINT64_FORMAT " failed: %s\n", message->request_id, message->body);
+                presage_roomlist_failed(connection, message->request_id);
+                g_free(presage_pending_message_take(presage, message->request_id)); // there will be no result
+                presage_handle_attachment_downloaded(connection, message->request_id, NULL, FALSE, 0, message->body);
+            } else {
+                purple_connection_error(connection, message->level, message->body);
+            }
+            break;
+        case PRESAGE_EVENT_KIND_TEXT:
+            if (message->request_id != 0) {
+                // this is the result of sending a message
+                char *original = presage_pending_message_take(presage, message->request_id);
+                if (original != NULL && (message->flags & PURPLE_MESSAGE_ERROR) && message->body != NULL) {
+                    // tell the user which of their messages could not be sent
+                    char *body = g_strdup_printf("Message „%s“ could not be sent: %s", original, message->body);
+                    presage_handle_text(connection, message->who, message->name, message->group, message->title, message->flags, message->timestamp, body);
+                    g_free(body);
+                    g_free(original);
+                    break;
+                }
+                g_free(original);
+            }
+            if (message->body != NULL) {
+                presage_handle_text(connection, message->who, message->name, message->group, message->title, message->flags, message->timestamp, message->body);
+            }
+            break;
+        case PRESAGE_EVENT_KIND_ATTACHMENT:
+            presage_handle_attachment(connection, message->who, message->name, message->group, message->title, message->flags, message->timestamp, message->attachment, message->blob, message->size, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_XFER_RESULT:
+            presage_handle_xfer((PurpleXfer *)message->xfer, message->flags, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_GROUPS:
+            presage_handle_groups(connection, message->request_id, message->groups, message->size);
+            break;
+        case PRESAGE_EVENT_KIND_CONTACTS:
+            presage_handle_contacts(connection, message->contacts, message->size);
+            break;
+        case PRESAGE_EVENT_KIND_MEMBERS:
+            if (message->size == 1) {
+                presage_handle_members(connection, message->groups[0].key, message->groups[0].members, message->groups[0].population);
+            }
+            break;
+        case PRESAGE_EVENT_KIND_PASSPHRASE_CHANGED:
+            presage_handle_passphrase_changed(connection, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_MIGRATION_CONFLICT:
+            presage_handle_migration_conflict(connection);
+            break;
+        case PRESAGE_EVENT_KIND_STORE_RESET:
+            presage_handle_store_reset(connection, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_BACKUP_EXPORTED:
+            presage_handle_backup_exported(connection, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_BACKUP_IMPORTED:
+            presage_handle_backup_imported(connection, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_UNLINKED:
+            presage_handle_unlinked(connection, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_IDENTITY_CHANGED:
+            presage_handle_identity_changed(connection, message->who, message->flags & 1, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_IDENTITY_ACCEPTED:
+            presage_handle_identity_accepted(connection, message->who, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_SAFETY_NUMBER:
+            presage_handle_safety_number(connection, message->who, message->body, message->blob, message->size);
+            break;
+        case PRESAGE_EVENT_KIND_ATTACHMENT_DOWNLOADED:
+            presage_handle_attachment_downloaded(connection, message->request_id, message->name, message->flags & 1, message->size, message->body);
+            break;
+        case PRESAGE_EVENT_KIND_DOWNLOAD_PROGRESS:
+            presage_handle_download_progress(connection, message->request_id, message->size);
+            break;
+        case PRESAGE_EVENT_KIND_TYPING:
+            presage_handle_typing(connection, message->who, message->group, message->flags & 1);
+            break;
+        case PRESAGE_EVENT_KIND_RECEIPT:
+            presage_handle_receipt(connection, message->who, message->name, message->group, (PresageReceiptKind)message->flags, message->timestamp, message->body);
+            break;
+        default:
+            purple_debug_warning(PLUGIN_NAME, "Ignoring message of unknown kind %d.\n", message->kind);
     }
     free_message(message);
 }
@@ -105,15 +213,57 @@ static void handle_message(Presage * message) {
  */
 static gboolean process_message(gpointer data) {
     g_return_val_if_fail(data != NULL, FALSE);
-    Presage * message = (Presage *)data;
+    PresageEvent * message = (PresageEvent *)data;
     handle_message(message);
     g_free(message);
     return FALSE;
 }
 
-#if !(GLIB_CHECK_VERSION(2, 67, 3))
-#define g_memdup2 g_memdup
-#endif
+/*
+ * Human readable description of a status returned by rust.
+ */
+const char * presage_status_message(PresageStatus status) {
+    switch (status) {
+        case PRESAGE_STATUS_OK:
+            return "Success.";
+        case PRESAGE_STATUS_INVALID_ARGUMENT:
+            return "Invalid argument. Please see the debug log for details.";
+        case PRESAGE_STATUS_NOT_CONNECTED:
+            return "Not connected to the back-end.";
+        case PRESAGE_STATUS_CHANNEL_CLOSED:
+            return "The back-end has stopped.";
+        case PRESAGE_STATUS_QUEUE_FULL:
+            return "The back-end is busy. Please try again later.";
+        case PRESAGE_STATUS_NO_THREAD:
+            return "Could not start the back-end for this account.";
+        case PRESAGE_STATUS_PANIC:
+            return "The back-end has crashed. Please see the debug log for details.";
+        default:
+            return "Unknown status.";
+    }
+}
+
+/*
+ * Closes the connection in case a call into rust has failed.
+ *
+ * @return Whether the call succeeded.
+ */
+gboolean presage_check_status(PurpleConnection *connection, PresageStatus status) {
+    if (status == PRESAGE_STATUS_OK) {
+        return TRUE;
+    }
+    purple_connection_error(connection, PURPLE_CONNECTION_ERROR_OTHER_ERROR, presage_status_message(status));
+    return FALSE;
+}
+
+/*
+ * Generates an identifier for a command.
+ * Rust echoes it in the events resulting from the command.
+ * Zero is reserved for events which have not been requested.
+ */
+uint64_t presage_next_request_id(Presage *presage) {
+    return ++presage->last_request_id;
+}
 
 /*
  * Handler for a message received by rust.
@@ -121,8 +271,8 @@ static gboolean process_message(gpointer data) {
  * 
  * Yes, this is indeed neccessary – we checked.
  */
-void presage_append_message(const Presage *message_rust) {
-    Presage *message_heap = g_memdup2(message_rust, sizeof *message_rust);
+void presage_append_message(const PresageEvent *message_rust) {
+    PresageEvent *message_heap = g_memdup2(message_rust, sizeof *message_rust);
     purple_timeout_add(
         0, // schedule for immediate execution
         process_message, // handle message in main thread
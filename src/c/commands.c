@@ -0,0 +1,59 @@
+#include "presage.h"
+
+/*
+ * Commands which can be typed into a conversation.
+ */
+
+static PurpleCmdId accept_identity_command = 0;
+static PurpleCmdId safety_number_command = 0;
+
+static Presage * conversation_get_presage(PurpleConversation *conv) {
+    PurpleConnection *connection = purple_account_get_connection(purple_conversation_get_account(conv));
+    return connection == NULL ? NULL : purple_connection_get_protocol_data(connection);
+}
+
+static PurpleCmdRet accept_identity(PurpleConversation *conv, const gchar *cmd, gchar **args, gchar **error, void *data) {
+    Presage *presage = conversation_get_presage(conv);
+    if (presage == NULL) {
+        *error = g_strdup("The account is not connected.");
+        return PURPLE_CMD_RET_FAILED;
+    }
+    PresageStatus status = presage_rust_accept_identity(presage->tx_ptr, presage_next_request_id(presage), purple_conversation_get_name(conv));
+    if (status != PRESAGE_STATUS_OK) {
+        *error = g_strdup(presage_status_message(status));
+        return PURPLE_CMD_RET_FAILED;
+    }
+    // the back-end reports the outcome, see presage_handle_identity_accepted
+    return PURPLE_CMD_RET_OK;
+}
+
+static PurpleCmdRet safety_number(PurpleConversation *conv, const gchar *cmd, gchar **args, gchar **error, void *data) {
+    Presage *presage = conversation_get_presage(conv);
+    if (presage == NULL) {
+        *error = g_strdup("The account is not connected.");
+        return PURPLE_CMD_RET_FAILED;
+    }
+    PresageStatus status = presage_rust_get_safety_number(presage->tx_ptr, presage_next_request_id(presage), purple_conversation_get_name(conv));
+    if (status != PRESAGE_STATUS_OK) {
+        *error = g_strdup(presage_status_message(status));
+        return PURPLE_CMD_RET_FAILED;
+    }
+    // the back-end reports the safety number, see presage_handle_safety_number
+    return PURPLE_CMD_RET_OK;
+}
+
+void presage_register_commands(const char *protocol_id) {
+    accept_identity_command = purple_cmd_register(
+        "accept-identity", "", PURPLE_CMD_P_PROTOCOL, PURPLE_CMD_FLAG_IM | PURPLE_CMD_FLAG_PROTOCOL_ONLY, protocol_id,
+        accept_identity, "accept-identity: Accept the new safety number of this contact.", NULL);
+    safety_number_command = purple_cmd_register(
+        "safety-number", "", PURPLE_CMD_P_PROTOCOL, PURPLE_CMD_FLAG_IM | PURPLE_CMD_FLAG_PROTOCOL_ONLY, protocol_id,
+        safety_number, "safety-number: Show the safety number shared with this contact for verification.", NULL);
+}
+
+void presage_unregister_commands() {
+    purple_cmd_unregister(accept_identity_command);
+    accept_identity_command = 0;
+    purple_cmd_unregister(safety_number_command);
+    safety_number_command = 0;
+}
@@ -1,23 +1,5 @@
 #include "presage.h"
 
-void presage_rust_main(void *, PurpleAccount *, char *);
-
-#ifdef WIN32
-#include <windows.h>
-DWORD WINAPI
-#else
-void * 
-#endif 
-rust_main(void* account) {
-    const char *user_dir = purple_config_dir();
-    const char *username = purple_account_get_username(account);
-    char *store_path = g_strdup_printf("%s/presage/%s", user_dir, username);
-    presage_rust_main(rust_runtime, account, store_path);
-    printf("presage_rust_main has finished.\n");
-    g_free(store_path);
-    return 0;
-}
-
 void presage_login(PurpleAccount *account) {
     purple_debug_info(PLUGIN_NAME, "login for account: %p\n", account);
     g_return_if_fail(rust_runtime != NULL);
@@ -25,33 +7,63 @@ void presage_login(PurpleAccount *account) {
     PurpleConnection *connection = purple_account_get_connection(account);
     // this protocol does not support anything special right now
     PurpleConnectionFlags pc_flags = purple_connection_get_flags(connection);
-    pc_flags |= PURPLE_CONNECTION_FLAG_NO_IMAGES;
     pc_flags |= PURPLE_CONNECTION_FLAG_NO_FONTSIZE;
     pc_flags |= PURPLE_CONNECTION_FLAG_NO_BGCOLOR;
     purple_connection_set_flags(connection, pc_flags);
     purple_connection_set_state(connection, PURPLE_CONNECTION_STATE_CONNECTING);
     Presage *presage = g_new0(Presage, 1);
     presage->account = account;
+    presage->pending_messages = g_hash_table_new_full(g_int64_hash, g_int64_equal, g_free, g_free);
+    presage->downloads = g_hash_table_new_full(g_int64_hash, g_int64_equal, g_free, NULL); // MEMCHECK: the transfers are owned by purple
+    presage->auto_saves = g_hash_table_new_full(g_int64_hash, g_int64_equal, g_free, presage_auto_save_free);
+    presage->chat_typing = g_hash_table_new_full(g_str_hash, g_str_equal, g_free, presage_chat_typing_free); // MEMCHECK: also removes the timeouts
+    presage->pending_receipts = g_hash_table_new_full(g_str_hash, g_str_equal, g_free, presage_pending_receipt_free); // MEMCHECK: also removes the timeouts
     purple_connection_set_protocol_data(connection, presage);
-    #ifdef WIN32
-    HANDLE thread = CreateThread(NULL, 0, rust_main, account, 0, NULL);
-    // TODO: detach and handle non-happy path
-    #else
-    pthread_t presage_thread;
-    int err = pthread_create(&presage_thread, NULL, rust_main, (void *)account);
-    if (err == 0) {
-        // detach thread so it is "free'd" as soon it terminates
-        pthread_detach(presage_thread);
+    if (purple_account_get_bool(account, "encrypt-store", FALSE)) {
+        presage_request_passphrase(connection); // starts the back-end once the user has entered the passphrase
     } else {
-        gchar *errmsg = g_strdup_printf("Could not create thread for connecting in background: %s", strerror(err));
-        purple_connection_error(connection, PURPLE_CONNECTION_ERROR_OTHER_ERROR, errmsg);
-        g_free(errmsg);
+        presage_start(connection, NULL);
     }
-    #endif
+}
+
+/*
+ * Starts the back-end for this connection.
+ * The passphrase may be NULL for an unencrypted store.
+ */
+void presage_start(PurpleConnection *connection, const char *passphrase) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    const char *user_dir = purple_config_dir();
+    const char *username = purple_account_get_username(account);
+    char *store_path = g_strdup_printf("%s/presage/%s", user_dir, username); // each account has a store of its own
+    const char *log_filter = purple_account_get_string(account, "log-filter", "");
+    gboolean redact = purple_account_get_bool(account, "redact-log", TRUE);
+    gboolean trust_new_identities = !purple_account_get_bool(account, "reject-new-identities", FALSE);
+    PresageStatus status = presage_rust_start(rust_runtime, account, store_path, passphrase, trust_new_identities, log_filter, redact); // the back-end runs in the background
+    g_free(store_path);
+    presage_check_status(connection, status);
 }
 
 void presage_close(PurpleConnection *connection) {
     Presage *presage = purple_connection_get_protocol_data(connection);
-    presage_rust_exit(rust_runtime, presage->tx_ptr);
-    // TODO: deallocate protocol data (including rust channel)
+    g_return_if_fail(presage != NULL); // login did not get far enough
+    purple_request_close_with_handle(connection); // e.g. the passphrase prompt
+    PresageStatus status = presage_rust_exit(presage->tx_ptr);
+    if (status != PRESAGE_STATUS_OK) {
+        // the back-end may have stopped already
+        purple_debug_info(PLUGIN_NAME, "Unable to request back-end to exit: %s\n", presage_status_message(status));
+    }
+    // the back-end shuts down in the background and releases the channel, see PRESAGE_EVENT_KIND_CLOSED
+    purple_connection_set_protocol_data(connection, NULL);
+    if (presage->roomlist != NULL) {
+        // the list of groups will not arrive any more
+        purple_roomlist_set_in_progress(presage->roomlist, FALSE);
+        purple_roomlist_unref(presage->roomlist);
+    }
+    g_hash_table_destroy(presage->pending_messages);
+    g_hash_table_destroy(presage->downloads); // the back-end aborts the downloads when exiting
+    g_hash_table_destroy(presage->auto_saves);
+    g_hash_table_destroy(presage->chat_typing);
+    g_hash_table_destroy(presage->pending_receipts); // receipts which have not been shown yet are dropped
+    g_free(presage->uuid);
+    g_free(presage);
 }
@@ -81,7 +81,10 @@ void presage_join_chat(PurpleConnection *connection, GHashTable *data) {
                 PurpleConvChat *conv_chat = purple_conversation_get_chat_data(conv);
                 purple_conv_chat_set_nick(conv_chat, purple_account_get_username(account));
                 // request list of participants
-                presage_rust_get_group_members(rust_runtime, presage->tx_ptr, identifier);
+                PresageStatus status = presage_rust_get_group_members(presage->tx_ptr, presage_next_request_id(presage), identifier);
+                if (status != PRESAGE_STATUS_OK) {
+                    purple_debug_warning(PLUGIN_NAME, "Unable to request members of group %s: %s\n", identifier, presage_status_message(status));
+                }
             }
         }
     }
@@ -93,27 +96,27 @@ void presage_join_chat(PurpleConnection *connection, GHashTable *data) {
  * NOTE: We cannot selectively add missing users since it looks like on Spectrum 
  * only a remove-readd-cycle will trigger the display name resolution.
  */
-void presage_handle_members(PurpleConnection *connection, const char *group, char **members, uint64_t length) {
-    g_return_if_fail(members != NULL);
+void presage_handle_members(PurpleConnection *connection, const char *group, char **members, size_t length) {
+    g_return_if_fail(members != NULL || length == 0);
 
     PurpleConversation *conv = purple_find_chat(connection, g_str_hash(group));
     if (conv != NULL) {
         PurpleConvChat *conv_chat = purple_conversation_get_chat_data(conv);
         purple_conv_chat_clear_users(conv_chat);
-        for (uint64_t i = 0; i < length; i++) {
+        for (size_t i = 0; i < length; i++) {
             PurpleConvChatBuddyFlags flags = 0;
             purple_conv_chat_add_user(conv_chat, members[i], NULL, flags, FALSE);
         }
     }
 }
 
-void presage_roomlist_populate(PurpleConnection *connection, const Group *groups, uint64_t length) {
+void presage_roomlist_populate(PurpleConnection *connection, const Group *groups, size_t length) {
     g_return_if_fail(groups != NULL || length == 0);
 
     Presage *presage = purple_connection_get_protocol_data(connection);
     PurpleRoomlist *roomlist = presage->roomlist;
     if (roomlist != NULL) {
-        for (uint64_t i = 0; i < length; i++) {
+        for (size_t i = 0; i < length; i++) {
             PurpleRoomlistRoom *room = purple_roomlist_room_new(PURPLE_ROOMLIST_ROOMTYPE_ROOM, groups[i].key, NULL); // MEMCHECK: roomlist will take ownership 
             // purple_roomlist_room_new sets the room's identifier
             purple_roomlist_room_add_field(roomlist, room, groups[i].title); // MEMCHECK: value is strdup'ed in callee
@@ -126,21 +129,28 @@ void presage_roomlist_populate(PurpleConnection *connection, const Group *groups
     }
 }
 
-void presage_handle_groups(PurpleConnection *connection, const Group *groups, uint64_t length) {
+/*
+ * The groups for the roomlist could not be fetched. The list is left empty.
+ */
+void presage_roomlist_failed(PurpleConnection *connection, uint64_t request_id) {
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    if (request_id == presage->roomlist_request_id) {
+        presage_roomlist_populate(connection, NULL, 0);
+    }
+}
+
+void presage_handle_groups(PurpleConnection *connection, uint64_t request_id, const Group *groups, size_t length) {
     g_return_if_fail(groups != NULL || length == 0);
 
-    presage_roomlist_populate(connection, groups, length);
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    if (request_id != 0 && request_id == presage->roomlist_request_id) {
+        // this is the list requested by presage_roomlist_get_list
+        presage_roomlist_populate(connection, groups, length);
+    }
 
     // TODO: add group to blist
-    for (uint64_t i = 0; i < length; i++) {
-        purple_debug_warning(PLUGIN_NAME, "got group %s „%s“ with %ld members\n", groups[i].key, groups[i].title, groups[i].population);
-        if (groups[i].population == 0) {
-            // An empty group. This is not a group, but rather a contact.
-            // TODO: Declare an addtional type for more clarity. Use separate code-paths.
-            presage_handle_contact(connection, groups[i].key, groups[i].title, groups[i].description);
-        } else if (groups[i].members != NULL) {
-            presage_handle_members(connection, groups[i].key, groups[i].members, groups[i].population);
-        }
+    for (size_t i = 0; i < length; i++) {
+        purple_debug_warning(PLUGIN_NAME, "got group %s „%s“ with %" G_GSIZE_FORMAT " members\n", groups[i].key, groups[i].title, groups[i].population);
     }
 }
 
@@ -170,6 +180,12 @@ PurpleRoomlist * presage_roomlist_get_list(PurpleConnection *connection) {
     purple_roomlist_set_fields(roomlist, fields);
     Presage *presage = purple_connection_get_protocol_data(connection);
     presage->roomlist = roomlist; // store the pointer to the roomlist so presage_handle_groups can write to it
-    presage_rust_list_groups(rust_runtime, presage->tx_ptr);
+    presage->roomlist_request_id = presage_next_request_id(presage);
+    PresageStatus status = presage_rust_list_groups(presage->tx_ptr, presage->roomlist_request_id);
+    if (status != PRESAGE_STATUS_OK) {
+        purple_debug_warning(PLUGIN_NAME, "Unable to request list of groups: %s\n", presage_status_message(status));
+        purple_roomlist_set_in_progress(roomlist, FALSE);
+        presage->roomlist = NULL;
+    }
     return roomlist;
 }
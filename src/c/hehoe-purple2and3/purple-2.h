@@ -5,6 +5,10 @@
 // blist
 #define purple_blist_find_buddies purple_find_buddies
 
+// cmds
+#define PURPLE_CMD_P_PROTOCOL PURPLE_CMD_P_PRPL
+#define PURPLE_CMD_FLAG_PROTOCOL_ONLY PURPLE_CMD_FLAG_PRPL_ONLY
+
 // connection
 #define purple_connection_error purple_connection_error_reason
 #define PURPLE_CONNECTION_FLAG_NO_BGCOLOR PURPLE_CONNECTION_NO_BGCOLOR
@@ -21,6 +25,10 @@
 
 // im_conversation
 #define purple_im_conversation_new(account, from) purple_conversation_new(PURPLE_CONV_TYPE_IM, account, from)
+#define PurpleIMTypingState PurpleTypingState
+#define PURPLE_IM_NOT_TYPING PURPLE_NOT_TYPING
+#define PURPLE_IM_TYPING PURPLE_TYPING
+#define PURPLE_IM_TYPED PURPLE_TYPED
 
 // protocol
 #define purple_protocol_got_user_status purple_prpl_got_user_status
@@ -32,6 +40,8 @@
 #define purple_serv_got_im serv_got_im
 #define purple_serv_got_chat_in serv_got_chat_in
 #define purple_serv_got_alias serv_got_alias
+#define purple_serv_got_typing serv_got_typing
+#define purple_serv_got_typing_stopped serv_got_typing_stopped
 
 // blist
 #define purple_blist_find_group purple_find_group
@@ -18,6 +18,8 @@ void purple_conv_im_write(PurpleConversation *conv, const char *who, const char
 #define purple_request_fields_add_group purple_request_page_add_group
 #define purple_request_field_group_new purple_request_group_new
 #define purple_request_field_group_add_field purple_request_group_add_field
+#define purple_request_fields_get_string purple_request_page_get_string
+#define purple_request_fields_get_bool purple_request_page_get_bool
 
 // timeout
 #define purple_timeout_add g_timeout_add
@@ -0,0 +1,121 @@
+#include "presage.h"
+
+/*
+ * Contacts may change their identity (safety number), e.g. when re-installing Signal.
+ * Depending on the account settings, the new identity must be accepted before sending messages.
+ *
+ * The user may compare safety numbers with a contact and mark them as verified.
+ * The safety number the user has marked as verified is stored with the buddy, next to the current one.
+ * It counts as verified only while the two are the same.
+ */
+
+typedef struct {
+    PurpleConnection *connection;
+    char *who;
+    char *digits;
+} SafetyNumber;
+
+static void safety_number_free(SafetyNumber *safety_number) {
+    g_free(safety_number->who);
+    g_free(safety_number->digits);
+    g_free(safety_number);
+}
+
+static void safety_number_verified(SafetyNumber *safety_number, PurpleRequestFields *fields) {
+    PurpleAccount *account = purple_connection_get_account(safety_number->connection);
+    PurpleBuddy *buddy = purple_blist_find_buddy(account, safety_number->who);
+    if (buddy != NULL) {
+        purple_blist_node_set_string(&buddy->node, "safety-number-verified", safety_number->digits);
+    }
+    safety_number_free(safety_number);
+}
+
+static void safety_number_close(SafetyNumber *safety_number, PurpleRequestFields *fields) {
+    safety_number_free(safety_number);
+}
+
+/*
+ * Remembers the current safety number with a contact.
+ *
+ * A verification of another safety number is forgotten.
+ */
+static void update_safety_number(PurpleConnection *connection, const char *who, const char *digits) {
+    PurpleBuddy *buddy = purple_blist_find_buddy(purple_connection_get_account(connection), who);
+    if (buddy != NULL) {
+        purple_blist_node_set_string(&buddy->node, "safety-number", digits);
+        const char *verified = purple_blist_node_get_string(&buddy->node, "safety-number-verified");
+        if (verified != NULL && !purple_strequal(verified, digits)) {
+            purple_blist_node_remove_setting(&buddy->node, "safety-number-verified");
+        }
+    }
+}
+
+gboolean presage_safety_number_verified(PurpleBuddy *buddy) {
+    const char *verified = purple_blist_node_get_string(&buddy->node, "safety-number-verified");
+    const char *current = purple_blist_node_get_string(&buddy->node, "safety-number");
+    return verified != NULL && purple_strequal(verified, current);
+}
+
+void presage_handle_identity_changed(PurpleConnection *connection, const char *who, gboolean accepted, const char *digits) {
+    update_safety_number(connection, who, digits);
+    uint64_t now_ms = (uint64_t)time(NULL) * 1000;
+    if (accepted) {
+        presage_handle_text(connection, who, NULL, NULL, NULL, PURPLE_MESSAGE_SYSTEM, now_ms,
+            "The safety number with this contact has changed. Type /safety-number to verify it with your contact.");
+    } else {
+        presage_handle_text(connection, who, NULL, NULL, NULL, PURPLE_MESSAGE_SYSTEM, now_ms,
+            "The safety number with this contact has changed. Messages cannot be sent until the new identity has been accepted. Verify the safety number with your contact and type /accept-identity to accept it.");
+    }
+}
+
+void presage_handle_identity_accepted(PurpleConnection *connection, const char *who, const char *error) {
+    uint64_t now_ms = (uint64_t)time(NULL) * 1000;
+    if (error == NULL) {
+        // the safety number stays the same, so does its verification
+        presage_handle_text(connection, who, NULL, NULL, NULL, PURPLE_MESSAGE_SYSTEM, now_ms, "The new identity has been accepted. Messages which failed to send need to be sent again.");
+    } else {
+        presage_handle_text(connection, who, NULL, NULL, NULL, PURPLE_MESSAGE_ERROR, now_ms, error);
+    }
+}
+
+void presage_handle_safety_number(PurpleConnection *connection, const char *who, const char *digits, unsigned char *scannable, size_t size) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    PurpleBuddy *buddy = purple_blist_find_buddy(account, who);
+    // the identity may have changed since it was verified
+    update_safety_number(connection, who, digits);
+
+    gsize qrimglen = 0;
+    gchar *qrimgdata = presage_render_qrcode_data(scannable, size, &qrimglen);
+    presage_rust_free_buffer(scannable, size);
+
+    PurpleRequestFields* fields = purple_request_fields_new();
+    PurpleRequestFieldGroup* group = purple_request_field_group_new(NULL);
+    purple_request_fields_add_group(fields, group);
+    {
+        PurpleRequestField *field = purple_request_field_string_new("digits", "Safety number", digits, FALSE);
+        purple_request_field_string_set_editable(field, FALSE);
+        purple_request_field_group_add_field(group, field);
+    }
+    if (qrimgdata != NULL) {
+        PurpleRequestField *field = purple_request_field_image_new("qr_code", "QR Code", qrimgdata, qrimglen);
+        purple_request_field_group_add_field(group, field);
+    }
+
+    SafetyNumber *safety_number = g_new0(SafetyNumber, 1);
+    safety_number->connection = connection;
+    safety_number->who = g_strdup(who);
+    safety_number->digits = g_strdup(digits);
+    char *primary = g_strdup_printf("Safety number with %s", buddy != NULL ? purple_buddy_get_alias(buddy) : who);
+    const char *secondary = buddy != NULL && presage_safety_number_verified(buddy)
+        ? "You have marked this safety number as verified."
+        : "Compare the numbers with the ones on your contact's phone or let them scan the code. Mark the safety number as verified only if they match.";
+    purple_request_fields(
+        connection, "Signal Protocol", primary, secondary,
+        fields,
+        "Mark as verified", G_CALLBACK(safety_number_verified),
+        "Close", G_CALLBACK(safety_number_close),
+        purple_request_cpar_from_account(account),
+        safety_number);
+    g_free(primary);
+    g_free(qrimgdata);
+}
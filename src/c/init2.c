@@ -34,11 +34,16 @@ static gboolean libpurple2_plugin_load(PurplePlugin *plugin) {
         return FALSE;
     }
     rust_runtime = presage_rust_init();
+    if (rust_runtime == NULL) {
+        return FALSE;
+    }
+    presage_register_commands(plugin->info->id);
     return TRUE;
 }
 
 static gboolean libpurple2_plugin_unload(PurplePlugin *plugin) {
     purple_signals_disconnect_by_handle(plugin);
+    presage_unregister_commands();
     if (rust_runtime != NULL) {
         presage_rust_destroy(rust_runtime);
     }
@@ -46,8 +51,24 @@ static gboolean libpurple2_plugin_unload(PurplePlugin *plugin) {
     return TRUE;
 }
 
-static void plugin_init(PurplePlugin *plugin) {
-    // nothing to do
+static void change_passphrase_action(PurplePluginAction *action) {
+    presage_change_passphrase(action->context);
+}
+
+static void export_backup_action(PurplePluginAction *action) {
+    presage_export_backup(action->context);
+}
+
+static void unlink_action(PurplePluginAction *action) {
+    presage_unlink(action->context);
+}
+
+static GList * presage_actions(PurplePlugin *plugin, gpointer context) {
+    GList *actions = NULL;
+    actions = g_list_append(actions, purple_plugin_action_new("Change store passphrase…", change_passphrase_action));
+    actions = g_list_append(actions, purple_plugin_action_new("Export account backup…", export_backup_action));
+    actions = g_list_append(actions, purple_plugin_action_new("Unlink this device…", unlink_action));
+    return actions;
 }
 
 static PurplePluginProtocolInfo prpl_info = {
@@ -58,6 +79,7 @@ static PurplePluginProtocolInfo prpl_info = {
     .login = presage_login,
     .close = presage_close,
     .send_im = presage_send_im,
+    .send_typing = presage_send_typing,
     .add_buddy = presage_add_buddy,
     .tooltip_text = presage_tooltip_text,
     // group chat related
@@ -89,6 +111,11 @@ static PurplePluginInfo info = {
     .load = libpurple2_plugin_load,
     .unload = libpurple2_plugin_unload,
     .extra_info = &prpl_info,
+    .actions = presage_actions,
 };
 
+static void plugin_init(PurplePlugin *plugin) {
+    prpl_info.protocol_options = presage_add_account_options(prpl_info.protocol_options);
+}
+
 PURPLE_INIT_PLUGIN(presage, plugin_init, info);
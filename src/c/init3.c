@@ -37,6 +37,10 @@ int presage_protocol_send_im(PurpleProtocolIM *im, PurpleConnection *gc, PurpleM
     return presage_send_im(gc, who, message, flags);
 }
 
+static guint presage_protocol_send_typing(PurpleProtocolIM *im, PurpleConnection *gc, const gchar *who, PurpleIMTypingState state) {
+    return presage_send_typing(gc, who, state);
+}
+
 /*
  * Initialize the protocol instance. See protocol.h for more information.
  */
@@ -78,7 +82,7 @@ static void presage_protocol_server_iface_init(PurpleProtocolServerInterface *se
 
 static void presage_protocol_im_iface_init(PurpleProtocolIMInterface *im_iface) {
   im_iface->send        = presage_protocol_send_im;
-  //im_iface->send_typing = presage_send_typing;
+  im_iface->send_typing = presage_protocol_send_typing;
 }
 
 static void presage_protocol_chat_iface_init(PurpleProtocolChatInterface *chat_iface) {
@@ -168,11 +172,18 @@ static gboolean presage_load(GPluginPlugin *plugin, GError **error) {
       return FALSE;
     }
     rust_runtime = presage_rust_init();
+    if (rust_runtime == NULL) {
+      purple_protocol_manager_unregister(manager, presage_protocol, NULL);
+      g_clear_object(&presage_protocol);
+      return FALSE;
+    }
   }
+  presage_register_commands("prpl-presage");
   return TRUE;
 }
 
 static gboolean presage_unload(GPluginPlugin *plugin, gboolean shutdown, GError **error) {
+  presage_unregister_commands();
   // TODO: move this to separate function
   {
     if (rust_runtime != NULL) {
@@ -1,5 +1,15 @@
 #include "presage.h"
 
+/*
+ * A choice for a list option.
+ */
+static PurpleKeyValuePair * key_value_pair_new(const char *label, const char *value) {
+    PurpleKeyValuePair *pair = g_new0(PurpleKeyValuePair, 1); // MEMCHECK: released by the option
+    pair->key = g_strdup(label);
+    pair->value = g_strdup(value);
+    return pair;
+}
+
 GList * presage_add_account_options(GList *account_options) {
     PurpleAccountOption *option;
 
@@ -9,6 +19,87 @@ GList * presage_add_account_options(GList *account_options) {
                 g_get_host_name() // strdup happens internally
                 );
     account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_string_new(
+                "Log filter (e.g. „debug“ or „warn,purple_presage_backend=debug“, empty for default)",
+                "log-filter",
+                ""
+                );
+    account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_bool_new(
+                "Remove personal data (uuids, phone numbers, message texts) from debug log",
+                "redact-log",
+                TRUE
+                );
+    account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_bool_new(
+                "Encrypt local store with a passphrase (asked for on login, must be set before linking)",
+                "encrypt-store",
+                FALSE
+                );
+    account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_bool_new(
+                "Reject new identities of contacts until accepted",
+                "reject-new-identities",
+                FALSE
+                );
+    account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_string_new(
+                "Save attachments automatically to this directory (empty: ask for each attachment)",
+                "attachment-directory",
+                ""
+                );
+    account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_string_new(
+                "File name for saved attachments (placeholders: {date}, {time}, {sender}, {group}, {filename})",
+                "attachment-filename",
+                PRESAGE_DEFAULT_ATTACHMENT_FILENAME
+                );
+    account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_bool_new(
+                "Show received images in the conversation (otherwise offered as file transfers)",
+                "inline-images",
+                TRUE
+                );
+    account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_int_new(
+                "Largest image to show in the conversation (KiB)",
+                "inline-images-max-size",
+                PRESAGE_DEFAULT_INLINE_IMAGES_MAX_SIZE
+                );
+    account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_bool_new(
+                "Send and show typing notifications",
+                "typing-notifications",
+                TRUE
+                );
+    account_options = g_list_append(account_options, option);
+
+    GList *receipt_choices = NULL;
+    receipt_choices = g_list_append(receipt_choices, key_value_pair_new("Read and viewed", "read"));
+    receipt_choices = g_list_append(receipt_choices, key_value_pair_new("Received, read and viewed", "all"));
+    receipt_choices = g_list_append(receipt_choices, key_value_pair_new("None", "none"));
+    option = purple_account_option_list_new(
+                "Show receipts for sent messages",
+                "receipts",
+                receipt_choices // MEMCHECK: the option takes ownership, the first choice is the default
+                );
+    account_options = g_list_append(account_options, option);
+
+    option = purple_account_option_string_new(
+                "Import backup on next login (path to the file)",
+                "import-backup",
+                ""
+                );
+    account_options = g_list_append(account_options, option);
     
     return account_options;
 }
@@ -2,82 +2,100 @@
 
 #include "hehoe-purple2and3/purple.h"
 #include <stdint.h>
+#include <stdbool.h>
+#include "purple_presage_backend.h" // generated from the rust sources
 
 #define PLUGIN_NAME "presage"
 
+// placeholders are explained in the account option
+#define PRESAGE_DEFAULT_ATTACHMENT_FILENAME "{date}_{time}_{sender}_{filename}"
+#define PRESAGE_DEFAULT_INLINE_IMAGES_MAX_SIZE 4096 // KiB
+#define PRESAGE_DEFAULT_RECEIPTS "read" // see account option for the choices
+
 // https://github.com/LLNL/lbann/issues/117#issuecomment-334333286
 #define MAKE_STR(x) _MAKE_STR(x)
 #define _MAKE_STR(x) #x
 
-// these should be supplied by rust in some way
-// TODO: uint64_t should actually correspond to rust's usize
-typedef struct _RustRuntime * RustRuntimePtr;
-typedef struct _RustChannelTx * RustChannelPtr;
-RustRuntimePtr presage_rust_init();
-void presage_rust_destroy(RustRuntimePtr);
-void presage_rust_link(RustRuntimePtr, RustChannelPtr, const char *);
-void presage_rust_whoami(RustRuntimePtr, RustChannelPtr);
-void presage_rust_initial_sync(RustRuntimePtr, RustChannelPtr);
-void presage_rust_receive(RustRuntimePtr, RustChannelPtr);
-void presage_rust_exit(RustRuntimePtr, RustChannelPtr);
-void presage_rust_send_contact(RustRuntimePtr, RustChannelPtr, const char *, const char *, PurpleXfer *);
-void presage_rust_send_group(RustRuntimePtr, RustChannelPtr, const char *, const char *, PurpleXfer *);
-void presage_rust_get_group_members(RustRuntimePtr, RustChannelPtr, const char *);
-void presage_rust_list_groups(RustRuntimePtr, RustChannelPtr);
-void presage_rust_free_string(char *);
-void presage_rust_free_buffer(char *, uint64_t);
-void presage_rust_strfreev(char **, uint64_t);
+// the back-end's functions are declared in the generated header
+typedef RustRuntime * RustRuntimePtr;
+typedef RustChannel * RustChannelPtr;
 
 extern RustRuntimePtr rust_runtime;
 
-// TODO: generate this struct declaration automatically from rust declaration
-typedef struct {
-    char *key;
-    char *title;
-    char *description;
-    uint32_t revision;
-    char **members;
-    uint64_t population;
-} Group;
+// bridge
+const char * presage_status_message(PresageStatus status);
+gboolean presage_check_status(PurpleConnection *connection, PresageStatus status);
+
+// protocol data of a connection
 typedef struct {
     PurpleAccount *account;
     RustChannelPtr tx_ptr;
-    char *qrcode;
     char *uuid;
-    const int32_t debug;
-    const int32_t error;
-    const int32_t connected;
-    const int32_t padding;
-    const uint64_t timestamp;
-    const uint64_t flags;
-    char *who;
-    char *name;
-    char *group;
-    char *title;
-    char *body;
-    void *blob;
-    uint64_t size;
-    Group *groups;
     PurpleRoomlist *roomlist;
-    PurpleXfer *xfer;
+    uint64_t roomlist_request_id; // the request which is going to populate the roomlist
+    uint64_t last_request_id; // for correlating commands with their results
+    GHashTable *pending_messages; // request id → text of a message which is being sent
+    GHashTable *downloads; // request id → PurpleXfer of an attachment which is being downloaded
+    GHashTable *auto_saves; // request id → attachment which is being saved without asking
+    GHashTable *chat_typing; // group and member → member who is typing in a group chat
+    GHashTable *pending_receipts; // conversation, message and kind → receipts which are about to be shown
 } Presage;
 
+uint64_t presage_next_request_id(Presage *presage);
+
 // procotol properties
 GList * presage_status_types(PurpleAccount *account);
+GList * presage_add_account_options(GList *account_options);
 
 // connection
 void presage_login(PurpleAccount *account);
 void presage_close(PurpleConnection *pc);
+void presage_start(PurpleConnection *connection, const char *passphrase);
+
+// store encryption and maintenance
+void presage_request_passphrase(PurpleConnection *connection);
+void presage_change_passphrase(PurpleConnection *connection);
+void presage_handle_passphrase_changed(PurpleConnection *connection, const char *error);
+void presage_handle_migration_conflict(PurpleConnection *connection);
+void presage_handle_store_reset(PurpleConnection *connection, const char *backup_path);
+void presage_export_backup(PurpleConnection *connection);
+void presage_handle_backup_exported(PurpleConnection *connection, const char *error);
+void presage_request_import(PurpleConnection *connection);
+void presage_handle_backup_imported(PurpleConnection *connection, const char *error);
+void presage_unlink(PurpleConnection *connection);
+void presage_handle_unlinked(PurpleConnection *connection, const char *error);
+
+// identities
+void presage_handle_identity_changed(PurpleConnection *connection, const char *who, gboolean accepted, const char *digits);
+gboolean presage_safety_number_verified(PurpleBuddy *buddy);
+void presage_handle_identity_accepted(PurpleConnection *connection, const char *who, const char *error);
+void presage_handle_safety_number(PurpleConnection *connection, const char *who, const char *digits, unsigned char *scannable, size_t size);
+
+// commands
+void presage_register_commands(const char *protocol_id);
+void presage_unregister_commands();
 
 // qrcode (linking and identification)
 void presage_handle_qrcode(PurpleConnection * connection, const char *qrcode);
 void presage_request_qrcode(PurpleConnection *connection);
 void presage_handle_uuid(PurpleConnection *connection, const char *uuid);
+gchar * presage_render_qrcode_data(const unsigned char *data, size_t length, gsize *qrimglen);
 
 // text messages
+void presage_handle_html(PurpleConnection *connection, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags flags, uint64_t timestamp_ms, const char *text);
 void presage_handle_text(PurpleConnection *connection, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags sent, uint64_t timestamp_ms, const char *body);
 int presage_send_im(PurpleConnection *connection, const char *who, const char *message, PurpleMessageFlags flags);
 int presage_send_chat(PurpleConnection *connection, int id, const gchar *message, PurpleMessageFlags flags);
+char * presage_pending_message_take(Presage *presage, uint64_t request_id);
+
+// typing notifications
+void presage_handle_typing(PurpleConnection *connection, const char *who, const char *group, gboolean started);
+unsigned int presage_send_typing(PurpleConnection *connection, const char *who, PurpleIMTypingState state);
+void presage_chat_typing_free(gpointer data);
+
+// receipts
+void presage_handle_receipt(PurpleConnection *connection, const char *who, const char *name, const char *group, PresageReceiptKind kind, uint64_t timestamp, const char *body);
+void presage_pending_receipt_free(gpointer data);
 
 // contact management
 void presage_add_buddy(PurpleConnection *connection, PurpleBuddy *buddy, PurpleGroup *group);
@@ -93,12 +111,17 @@ void presage_tooltip_text(PurpleBuddy *buddy, PurpleNotifyUserInfo *info, gboole
 void presage_set_chat_topic(PurpleConnection *connection, int id, const char *topic);
 GList * presage_chat_info(PurpleConnection *connection);
 void presage_join_chat(PurpleConnection *connection, GHashTable *data);
-void presage_handle_groups(PurpleConnection *connection, const Group *groups, uint64_t length);
-//void presage_handle_members(PurpleConnection *connection, const char *group, char **members, uint64_t length);
+void presage_handle_groups(PurpleConnection *connection, uint64_t request_id, const Group *groups, size_t length);
+void presage_handle_contacts(PurpleConnection *connection, const Contact *contacts, size_t length);
+void presage_handle_members(PurpleConnection *connection, const char *group, char **members, size_t length);
 PurpleRoomlist * presage_roomlist_get_list(PurpleConnection *connection);
+void presage_roomlist_failed(PurpleConnection *connection, uint64_t request_id);
 
 // attachments
-void presage_handle_attachment(PurpleConnection *connection, const char *who, uint64_t timestamp, void *blob, uint64_t blobsize, const char *filename);
+void presage_handle_attachment(PurpleConnection *connection, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags flags, uint64_t timestamp, const Attachment *attachment, unsigned char *pointer, size_t pointer_size, const char *filename);
+void presage_handle_attachment_downloaded(PurpleConnection *connection, uint64_t request_id, const char *path, gboolean temporary, size_t size, const char *error);
+void presage_handle_download_progress(PurpleConnection *connection, uint64_t request_id, size_t size);
+void presage_auto_save_free(gpointer data);
 void presage_send_file(PurpleConnection *connection, const gchar *who, const gchar *filename);
 void presage_chat_send_file(PurpleConnection *connection, int id, const char *filename);
 void presage_handle_xfer(PurpleXfer *xfer, PurpleMessageFlags flags, const char* error);
\ No newline at end of file
@@ -35,9 +35,11 @@ static void show_qrcode(PurpleConnection *connection, const char *qrstring, gcha
         connection);
 }
 
-static void generate_and_show_qrcode(PurpleConnection *connection, const char *data) {
-    g_return_if_fail(data != NULL);
-    QRcode * qrcode = QRcode_encodeString(data, 0, QR_ECLEVEL_L, QR_MODE_8, 1);
+/*
+ * Renders a QR code as image (PBM) for displaying in a request field.
+ * Releases the QR code. Returns NULL if there is no QR code. The caller takes ownership of the image.
+ */
+static gchar * render_qrcode(QRcode *qrcode, gsize *qrimglen) {
     if (qrcode != NULL) {
         int border = 4;
         int zoom = 6;
@@ -46,8 +48,8 @@ static void generate_and_show_qrcode(PurpleConnection *connection, const char *d
         // poor man's PBM encoder
         gchar *head = g_strdup_printf("P1 %d %d ", imgwidth, imgwidth);
         int headlen = strlen(head);
-        const gsize qrimglen = headlen+imgwidth*2*imgwidth*2;
-        gchar * qrimgdata = g_strndup(head, qrimglen);
+        *qrimglen = headlen+imgwidth*2*imgwidth*2;
+        gchar * qrimgdata = g_strndup(head, *qrimglen);
         g_free(head);
         gchar *imgptr = qrimgdata+headlen;
         // inspired by printQr in https://github.com/nayuki/QR-Code-generator/blob/master/c/qrcodegen-demo.c
@@ -64,10 +66,24 @@ static void generate_and_show_qrcode(PurpleConnection *connection, const char *d
             }
         }
         QRcode_free(qrcode);
-        show_qrcode(connection, data, qrimgdata, qrimglen);
-        g_free(qrimgdata);
+        return qrimgdata;
     } else {
         purple_debug_info(PLUGIN_NAME, "qrcodegen failed.\n");
+        return NULL;
+    }
+}
+
+gchar * presage_render_qrcode_data(const unsigned char *data, size_t length, gsize *qrimglen) {
+    return render_qrcode(QRcode_encodeData(length, data, 0, QR_ECLEVEL_L), qrimglen);
+}
+
+static void generate_and_show_qrcode(PurpleConnection *connection, const char *data) {
+    g_return_if_fail(data != NULL);
+    gsize qrimglen = 0;
+    gchar *qrimgdata = render_qrcode(QRcode_encodeString(data, 0, QR_ECLEVEL_L, QR_MODE_8, 1), &qrimglen);
+    if (qrimgdata != NULL) {
+        show_qrcode(connection, data, qrimgdata, qrimglen);
+        g_free(qrimgdata);
     }
 }
 
@@ -85,7 +101,7 @@ void presage_handle_qrcode(PurpleConnection *connection, const char *data) {
         // empty string means "linking has finished"
         purple_request_close_with_handle(connection); // close request displaying the QR code
         Presage *presage = purple_connection_get_protocol_data(connection);
-        presage_rust_whoami(rust_runtime, presage->tx_ptr); // now that linking is done, get own uuid
+        presage_check_status(connection, presage_rust_whoami(presage->tx_ptr)); // now that linking is done, get own uuid
     } else {
         PurpleRequestUiOps *ui_ops = purple_request_get_ui_ops();
         if (ui_ops && ui_ops->request_fields) {
@@ -101,7 +117,7 @@ void presage_handle_qrcode(PurpleConnection *connection, const char *data) {
 void presage_request_qrcode(PurpleConnection *connection) {
     Presage *presage = purple_connection_get_protocol_data(connection);
     const char * device_name = purple_account_get_string(presage->account, "device-name", g_get_host_name());
-    presage_rust_link(rust_runtime, presage->tx_ptr, device_name);
+    presage_check_status(connection, presage_rust_link(presage->tx_ptr, device_name));
 }
 
 // TODO: maybe move this into connection.c?
@@ -114,13 +130,14 @@ void presage_handle_uuid(PurpleConnection *connection, const char *uuid) {
         const char *username = purple_account_get_username(account);
         if (purple_strequal(username, uuid)) {
             Presage *presage = purple_connection_get_protocol_data(connection);
+            g_free(presage->uuid); // whoami is repeated after the store has been re-opened
             presage->uuid = g_strdup(uuid);
             purple_request_close_with_handle(connection); // close request displaying the QR code
             /* 
             Now that we established correctness of the uuid, start initial sync (and then receive). The presage docs state: 
             „As a client, it is heavily recommended to run this once in `ReceivingMode::InitialSync` once before enabling the possiblity of sending messages.“
             */
-            presage_rust_initial_sync(rust_runtime, presage->tx_ptr);
+            presage_check_status(connection, presage_rust_initial_sync(presage->tx_ptr));
         } else {
             char * errmsg = g_strdup_printf("Username for this account must be '%s'.", uuid);
             purple_connection_error(connection, PURPLE_CONNECTION_ERROR_OTHER_ERROR, errmsg);
@@ -0,0 +1,142 @@
+#include "presage.h"
+
+/*
+ * Receipts about the same message arriving within this many seconds are shown in one line.
+ * In groups, many members tend to receive a message at about the same time.
+ */
+#define RECEIPT_DELAY 3
+
+/*
+ * Receipts about a message which are about to be shown.
+ */
+typedef struct {
+    PurpleConnection *connection;
+    const char *key; // owned by the hash table
+    char *who; // the contact in a direct conversation
+    char *group; // NULL in a direct conversation
+    PresageReceiptKind kind;
+    char *body;
+    GPtrArray *names; // of the contacts who sent a receipt
+    guint timeout;
+} PendingReceipt;
+
+void presage_pending_receipt_free(gpointer data) {
+    PendingReceipt *receipt = data;
+    if (receipt->timeout != 0) {
+        purple_timeout_remove(receipt->timeout);
+    }
+    g_free(receipt->who);
+    g_free(receipt->group);
+    g_free(receipt->body);
+    g_ptr_array_free(receipt->names, TRUE);
+    g_free(receipt);
+}
+
+/*
+ * Whether the user wants to see receipts of this kind.
+ */
+static gboolean receipt_wanted(PurpleAccount *account, PresageReceiptKind kind) {
+    const char *receipts = purple_account_get_string(account, "receipts", PRESAGE_DEFAULT_RECEIPTS);
+    if (purple_strequal(receipts, "all")) {
+        return TRUE;
+    }
+    if (purple_strequal(receipts, "read")) {
+        return kind != PRESAGE_RECEIPT_KIND_DELIVERED;
+    }
+    return FALSE;
+}
+
+/*
+ * Joins the names like "Alice, Bob and Carol".
+ * The caller takes ownership of the result.
+ */
+static char * join_names(GPtrArray *names) {
+    GString *joined = g_string_new(NULL);
+    for (guint i = 0; i < names->len; i++) {
+        if (i > 0) {
+            g_string_append(joined, i + 1 < names->len ? ", " : " and ");
+        }
+        g_string_append(joined, g_ptr_array_index(names, i));
+    }
+    return g_string_free(joined, FALSE);
+}
+
+/*
+ * Writes a line about the receipts into the conversation.
+ * Receipts do not open a conversation, so nothing is shown if the conversation is not open.
+ */
+static gboolean show_receipts(gpointer data) {
+    PendingReceipt *receipt = data;
+    receipt->timeout = 0; // the timer ends by returning FALSE
+
+    PurpleConnection *connection = receipt->connection;
+    PurpleAccount *account = purple_connection_get_account(connection);
+    PurpleConversation *conv = NULL;
+    if (receipt->group == NULL) {
+        conv = purple_conversation_find_im_by_name(receipt->who, account);
+    } else {
+        conv = purple_find_chat(connection, g_str_hash(receipt->group));
+    }
+    if (conv != NULL) {
+        const char *verb = "Received by";
+        if (receipt->kind == PRESAGE_RECEIPT_KIND_READ) {
+            verb = "Read by";
+        } else if (receipt->kind == PRESAGE_RECEIPT_KIND_VIEWED) {
+            verb = "Viewed by";
+        }
+        char *names = join_names(receipt->names);
+        char *text = NULL;
+        if (receipt->body != NULL) {
+            text = g_strdup_printf("%s %s: „%s“", verb, names, receipt->body);
+        } else {
+            text = g_strdup_printf("%s %s.", verb, names);
+        }
+        char *html = purple_markup_escape_text(text, -1);
+        purple_conversation_write(conv, NULL, html, PURPLE_MESSAGE_SYSTEM | PURPLE_MESSAGE_NO_LOG, time(NULL));
+        g_free(html);
+        g_free(text);
+        g_free(names);
+    }
+
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    g_hash_table_remove(presage->pending_receipts, receipt->key); // MEMCHECK: releases receipt
+    return FALSE;
+}
+
+/*
+ * A contact has received, read or viewed a message sent by the user.
+ *
+ * The receipt is shown after a short delay so receipts from other members of a group can be shown along with it.
+ */
+void presage_handle_receipt(PurpleConnection *connection, const char *who, const char *name, const char *group, PresageReceiptKind kind, uint64_t timestamp, const char *body) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    if (!receipt_wanted(account, kind)) {
+        return;
+    }
+
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    char *key = g_strdup_printf("%s %" G_GUINT64_FORMAT " %d", group != NULL ? group : who, timestamp, kind);
+    PendingReceipt *receipt = g_hash_table_lookup(presage->pending_receipts, key);
+    if (receipt == NULL) {
+        receipt = g_new0(PendingReceipt, 1); // MEMCHECK: released by the hash table
+        receipt->connection = connection;
+        receipt->key = key;
+        receipt->who = g_strdup(who);
+        receipt->group = g_strdup(group);
+        receipt->kind = kind;
+        receipt->body = g_strdup(body);
+        receipt->names = g_ptr_array_new_with_free_func(g_free);
+        receipt->timeout = purple_timeout_add(RECEIPT_DELAY * 1000, show_receipts, receipt);
+        g_hash_table_insert(presage->pending_receipts, key, receipt); // MEMCHECK: the hash table takes ownership of key
+    } else {
+        g_free(key);
+    }
+
+    const char *display_name = name != NULL ? name : who;
+    for (guint i = 0; i < receipt->names->len; i++) {
+        if (purple_strequal(g_ptr_array_index(receipt->names, i), display_name)) {
+            return; // e.g. the contact has more than one device
+        }
+    }
+    g_ptr_array_add(receipt->names, g_strdup(display_name));
+}
@@ -1,57 +1,451 @@
 #include "presage.h"
+#include <glib/gstdio.h>
+
+/*
+ * The downloaded file is handed to purple in pieces of this size.
+ */
+#define READ_CHUNK_SIZE (64 * 1024)
+
+/*
+ * State of a received attachment. Stored in the transfer's data.
+ *
+ * The back-end downloads the attachment into a temporary file once the user has accepted the transfer.
+ * The file is then handed to purple chunk by chunk so the progress is shown.
+ */
+typedef struct {
+    unsigned char *pointer; // identifies the attachment in the back-end, see presage_rust_download_attachment
+    size_t pointer_size;
+    uint64_t request_id; // of the download while it is running, zero otherwise
+    char *path; // temporary file
+    FILE *file;
+    guint continue_timer;
+} Download;
+
+static Presage * xfer_get_presage(PurpleXfer *xfer) {
+    PurpleConnection *connection = purple_account_get_connection(purple_xfer_get_account(xfer));
+    return connection == NULL ? NULL : purple_connection_get_protocol_data(connection);
+}
 
 static void xfer_init_fnc(PurpleXfer *xfer) {
     purple_xfer_start(xfer, -1, NULL, 0); // invokes start_fnc
 }
 
 static void xfer_start_fnc(PurpleXfer * xfer) {
-    // TODO: it would be nice to download just now, in a streaming fashion, not beforehand as a single big blob
-    purple_xfer_prpl_ready(xfer); // invokes do_transfer which invokes read_fnc
+    Download *download = xfer->data;
+    Presage *presage = xfer_get_presage(xfer);
+    if (download == NULL || presage == NULL) {
+        purple_xfer_cancel_local(xfer);
+        return;
+    }
+    uint64_t request_id = presage_next_request_id(presage);
+    PresageStatus status = presage_rust_download_attachment(presage->tx_ptr, request_id, download->pointer, download->pointer_size, NULL); // into a temporary file, handed to purple afterwards
+    if (status != PRESAGE_STATUS_OK) {
+        purple_xfer_error(purple_xfer_get_type(xfer), purple_xfer_get_account(xfer), purple_xfer_get_remote_user(xfer), presage_status_message(status));
+        purple_xfer_cancel_local(xfer);
+        return;
+    }
+    download->request_id = request_id;
+    gint64 *key = g_new(gint64, 1); // MEMCHECK: released by the hash table
+    *key = request_id;
+    g_hash_table_insert(presage->downloads, key, xfer);
+    // continues in presage_handle_attachment_downloaded
 }
 
 static gssize xfer_read_fnc(guchar **buffer, PurpleXfer * xfer) {
-    // entire attachment already is in memory.
-    // just forward the pointer to the destination buffer.
-    *buffer = xfer->data;
-    xfer->data = NULL; // MEMCHECK: not our memory to free any more
-    return purple_xfer_get_size(xfer);
+    Download *download = xfer->data;
+    g_return_val_if_fail(download != NULL && download->file != NULL, -1);
+    *buffer = g_malloc(READ_CHUNK_SIZE); // MEMCHECK: released by purple
+    size_t bytes_read = fread(*buffer, 1, READ_CHUNK_SIZE, download->file);
+    if (ferror(download->file)) {
+        return -1; // purple cancels the transfer
+    }
+    return bytes_read;
+}
+
+static gboolean continue_transfer(gpointer data) {
+    PurpleXfer *xfer = data;
+    Download *download = xfer->data;
+    download->continue_timer = 0;
+    purple_xfer_prpl_ready(xfer); // invokes do_transfer which invokes read_fnc
+    return FALSE;
 }
 
 static void xfer_ack_fnc(PurpleXfer * xfer, const guchar * buffer, size_t bytes_read) {
     // This is called after each time xfer_read_fnc returned a positive value.
-    // We only do one read, so the transfer is complete now.
-    #if PURPLE_VERSION_CHECK(2,14,10)
-    purple_xfer_set_completed(xfer, TRUE);
-    #endif
+    // purple marks the transfer as completed once all bytes have been read.
+    Download *download = xfer->data;
+    if (download != NULL && bytes_read > 0 && !purple_xfer_is_completed(xfer)) {
+        // read the next chunk once the UI had a chance to show the progress
+        download->continue_timer = purple_timeout_add(0, continue_transfer, xfer);
+    }
 }
 
-static void xfer_release_blob(PurpleXfer * xfer) {
-    presage_rust_free_buffer(xfer->data, xfer->size);
+/*
+ * Releases everything related to the attachment.
+ * Stops the download if it is still running.
+ */
+static void xfer_release_download(PurpleXfer * xfer) {
+    Download *download = xfer->data;
+    if (download == NULL) {
+        return; // released already
+    }
     xfer->data = NULL;
+    if (download->request_id != 0) {
+        Presage *presage = xfer_get_presage(xfer);
+        if (presage != NULL) {
+            gint64 key = download->request_id;
+            g_hash_table_remove(presage->downloads, &key);
+            PresageStatus status = presage_rust_abort_download(presage->tx_ptr, download->request_id);
+            if (status != PRESAGE_STATUS_OK) {
+                purple_debug_info(PLUGIN_NAME, "Unable to abort download: %s\n", presage_status_message(status));
+            }
+        }
+    }
+    if (download->continue_timer != 0) {
+        purple_timeout_remove(download->continue_timer);
+    }
+    if (download->file != NULL) {
+        fclose(download->file);
+    }
+    if (download->path != NULL) {
+        g_remove(download->path);
+        g_free(download->path);
+    }
+    presage_rust_free_buffer(download->pointer, download->pointer_size);
+    g_free(download);
+}
+
+/*
+ * Describes an attachment for the conversation, e.g. "image/jpeg, 1920×1080, 2.3 MB".
+ * The caller takes ownership of the result.
+ */
+static char * describe_attachment(const Attachment *attachment) {
+    GString *description = g_string_new(attachment->content_type);
+    if (attachment->width > 0 && attachment->height > 0) {
+        g_string_append_printf(description, ", %u×%u", attachment->width, attachment->height);
+    }
+    if (attachment->size > 0) {
+        char *size = purple_str_size_to_units(attachment->size);
+        g_string_append_printf(description, ", %s", size);
+        g_free(size);
+    }
+    return g_string_free(description, FALSE);
+}
+
+/*
+ * What the sender did, e.g. "Sent a voice message".
+ */
+static const char * attachment_verb(const Attachment *attachment) {
+    return attachment->voice_note ? "Sent a voice message" : "Sent the file";
+}
+
+/*
+ * An attachment which is fetched without asking, either because it is saved automatically or shown in the conversation.
+ * Remembers the conversation until the back-end has downloaded the file.
+ */
+typedef struct {
+    char *who;
+    char *name;
+    char *group;
+    char *title;
+    PurpleMessageFlags flags;
+    uint64_t timestamp;
+    char *filename;
+    char *description;
+    const char *verb; // static
+    gboolean show_inline;
+} AutoSave;
+
+void presage_auto_save_free(gpointer data) {
+    AutoSave *save = data;
+    g_free(save->who);
+    g_free(save->name);
+    g_free(save->group);
+    g_free(save->title);
+    g_free(save->filename);
+    g_free(save->description);
+    g_free(save);
+}
+
+/*
+ * Makes a value usable as part of a file name.
+ * The caller takes ownership of the result.
+ */
+static char * sanitize(const char *value) {
+    char *sanitized = g_strdup(value != NULL ? value : "");
+    g_strdelimit(sanitized, "/\\:*?\"<>|", '_');
+    if (sanitized[0] == '.') {
+        sanitized[0] = '_'; // must not refer to the parent directory or be hidden
+    }
+    return sanitized;
+}
+
+/*
+ * Replaces the placeholders in the file name template.
+ * The caller takes ownership of the result.
+ */
+static char * expand_template(const char *template, const char *sender, const char *title, uint64_t timestamp_ms, const char *filename) {
+    GDateTime *datetime = g_date_time_new_from_unix_local(timestamp_ms / 1000);
+    char *date = g_date_time_format(datetime, "%Y-%m-%d");
+    char *clock = g_date_time_format(datetime, "%H%M%S");
+    g_date_time_unref(datetime);
+    char *values[] = {date, clock, sanitize(sender), sanitize(title), sanitize(filename)};
+    const char *placeholders[] = {"{date}", "{time}", "{sender}", "{group}", "{filename}"};
+    const size_t count = sizeof(placeholders) / sizeof(placeholders[0]);
+
+    GString *result = g_string_new(NULL);
+    const char *rest = template;
+    while (*rest != 0) {
+        gboolean replaced = FALSE;
+        for (size_t i = 0; i < count && !replaced; i++) {
+            if (g_str_has_prefix(rest, placeholders[i])) {
+                g_string_append(result, values[i]);
+                rest += strlen(placeholders[i]);
+                replaced = TRUE;
+            }
+        }
+        if (!replaced) {
+            g_string_append_c(result, *rest);
+            rest++;
+        }
+    }
+    for (size_t i = 0; i < count; i++) {
+        g_free(values[i]);
+    }
+    return g_string_free(result, FALSE);
+}
+
+/*
+ * Has the back-end download an attachment without asking.
+ * If there is no destination, it ends up in a temporary file.
+ * The conversation learns about the file once it has been downloaded.
+ */
+static void fetch(PurpleConnection *connection, const char *destination, gboolean show_inline, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags flags, uint64_t timestamp, const Attachment *attachment, unsigned char *pointer, size_t pointer_size, const char *filename) {
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    uint64_t request_id = presage_next_request_id(presage);
+    PresageStatus status = presage_rust_download_attachment(presage->tx_ptr, request_id, pointer, pointer_size, destination);
+    presage_rust_free_buffer(pointer, pointer_size); // not needed any more
+    if (status != PRESAGE_STATUS_OK) {
+        char *text = g_strdup_printf("Unable to download the file „%s“: %s", filename, presage_status_message(status));
+        presage_handle_text(connection, who, name, group, title, flags | PURPLE_MESSAGE_ERROR, timestamp, text);
+        g_free(text);
+        return;
+    }
+    AutoSave *save = g_new0(AutoSave, 1); // MEMCHECK: released by the hash table
+    save->who = g_strdup(who);
+    save->name = g_strdup(name);
+    save->group = g_strdup(group);
+    save->title = g_strdup(title);
+    save->flags = flags;
+    save->timestamp = timestamp;
+    save->filename = g_strdup(filename);
+    save->description = describe_attachment(attachment);
+    save->verb = attachment_verb(attachment);
+    save->show_inline = show_inline;
+    gint64 *key = g_new(gint64, 1); // MEMCHECK: released by the hash table
+    *key = request_id;
+    g_hash_table_insert(presage->auto_saves, key, save);
+}
+
+/*
+ * Where to save an attachment in the configured directory.
+ * The caller takes ownership of the result.
+ */
+static char * auto_save_destination(PurpleConnection *connection, const char *directory, const char *who, const char *name, const char *title, PurpleMessageFlags flags, uint64_t timestamp, const char *filename) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    const char *template = purple_account_get_string(account, "attachment-filename", PRESAGE_DEFAULT_ATTACHMENT_FILENAME);
+    const char *sender = (flags & PURPLE_MESSAGE_SEND) ? purple_account_get_username(account) : (name != NULL ? name : who);
+    char *relative = expand_template(template, sender, title, timestamp, filename);
+    char *destination = g_build_filename(directory, relative, NULL);
+    g_free(relative);
+    return destination;
+}
+
+/*
+ * Whether an attachment is an image which is to be shown in the conversation.
+ */
+static gboolean is_inline_image(PurpleAccount *account, const Attachment *attachment) {
+    if (!purple_account_get_bool(account, "inline-images", TRUE) || !g_str_has_prefix(attachment->content_type, "image/")) {
+        return FALSE;
+    }
+    // the size is checked before downloading, so an image of unknown size is not shown
+    uint64_t max_size = (uint64_t)purple_account_get_int(account, "inline-images-max-size", PRESAGE_DEFAULT_INLINE_IMAGES_MAX_SIZE) * 1024;
+    return attachment->size > 0 && attachment->size <= max_size;
+}
+
+/*
+ * Shows an image in the conversation.
+ * Takes ownership of the data.
+ */
+static void write_image(PurpleConnection *connection, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags flags, uint64_t timestamp, gpointer data, size_t size, const char *filename) {
+    #if PURPLE_VERSION_CHECK(3,0,0)
+    PurpleImage *image = purple_image_new_take_data(data, size);
+    guint id = purple_image_store_add(image);
+    char *html = g_strdup_printf("<img src=\"" PURPLE_IMAGE_STORE_PROTOCOL "%u\">", id);
+    presage_handle_html(connection, who, name, group, title, flags | PURPLE_MESSAGE_IMAGES, timestamp, html);
+    g_object_unref(image);
+    #else
+    int id = purple_imgstore_add_with_id(data, size, filename); // MEMCHECK: the store takes ownership of the data
+    char *html = g_strdup_printf("<img id=\"%d\">", id);
+    presage_handle_html(connection, who, name, group, title, flags | PURPLE_MESSAGE_IMAGES, timestamp, html);
+    purple_imgstore_unref_by_id(id); // the conversation holds a reference of its own
+    #endif
+    g_free(html);
+}
+
+/*
+ * Posts a downloaded attachment into the conversation, either as an image or as a link to the saved file.
+ * Temporary files are removed afterwards.
+ */
+static void handle_auto_saved(PurpleConnection *connection, AutoSave *save, const char *path, gboolean temporary, const char *error) {
+    if (error != NULL) {
+        char *text = g_strdup_printf("Unable to download the file „%s“: %s", save->filename, error);
+        presage_handle_text(connection, save->who, save->name, save->group, save->title, save->flags | PURPLE_MESSAGE_ERROR, save->timestamp, text);
+        g_free(text);
+        return;
+    }
+    if (save->show_inline) {
+        gchar *data = NULL;
+        gsize size = 0;
+        if (g_file_get_contents(path, &data, &size, NULL)) {
+            write_image(connection, save->who, save->name, save->group, save->title, save->flags, save->timestamp, data, size, save->filename);
+        } else {
+            purple_debug_warning(PLUGIN_NAME, "Unable to read downloaded image.\n");
+        }
+    }
+    if (temporary) {
+        g_remove(path);
+        return;
+    }
+    char *uri = g_filename_to_uri(path, NULL, NULL);
+    char *basename = g_path_get_basename(path); // a number may have been added to the name
+    char *label = purple_markup_escape_text(basename, -1);
+    char *details = purple_markup_escape_text(save->description, -1);
+    char *html = g_strdup_printf("%s <a href=\"%s\">%s</a> (%s).", save->verb, uri != NULL ? uri : "", label, details);
+    g_free(details);
+    presage_handle_html(connection, save->who, save->name, save->group, save->title, save->flags, save->timestamp, html);
+    g_free(html);
+    g_free(label);
+    g_free(basename);
+    g_free(uri);
 }
 
-static void presage_xfer_announce(PurpleConnection *connection, const char *who, const char *filename) {
-    // resolve identifier for displaying name
-    const char * alias = who; // use the identifier by default
-    PurpleBuddy * buddy = purple_find_buddy(purple_connection_get_account(connection), who);
-    if (buddy != NULL) {
-        alias = purple_buddy_get_contact_alias(buddy);
+/*
+ * The back-end has downloaded an attachment.
+ * A temporary file is handed to purple now. An attachment fetched without asking is posted into the conversation.
+ */
+void presage_handle_attachment_downloaded(PurpleConnection *connection, uint64_t request_id, const char *path, gboolean temporary, size_t size, const char *error) {
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    gint64 key = request_id;
+    AutoSave *save = g_hash_table_lookup(presage->auto_saves, &key);
+    if (save != NULL) {
+        handle_auto_saved(connection, save, path, temporary, error);
+        g_hash_table_remove(presage->auto_saves, &key);
+        return;
+    }
+    PurpleXfer *xfer = g_hash_table_lookup(presage->downloads, &key);
+    if (xfer == NULL) {
+        // the transfer has been cancelled in the mean-time
+        if (path != NULL && temporary) {
+            g_remove(path);
+        }
+        return;
+    }
+    g_hash_table_remove(presage->downloads, &key);
+    Download *download = xfer->data;
+    download->request_id = 0; // the download is not running any more
+    if (error != NULL) {
+        purple_xfer_error(purple_xfer_get_type(xfer), purple_xfer_get_account(xfer), purple_xfer_get_remote_user(xfer), error);
+        purple_xfer_cancel_remote(xfer);
+        return;
     }
-    char * text = g_strdup_printf("Preparing to store \"%s\" sent by %s...", filename, alias); // MEMCHECK: is released here
+    download->path = g_strdup(path); // temporary, removed together with the transfer
+    download->file = g_fopen(path, "rb");
+    if (download->file == NULL) {
+        purple_xfer_error(purple_xfer_get_type(xfer), purple_xfer_get_account(xfer), purple_xfer_get_remote_user(xfer), "Unable to open the downloaded file.");
+        purple_xfer_cancel_local(xfer);
+        return;
+    }
+    purple_xfer_set_size(xfer, size);
+    purple_xfer_set_bytes_sent(xfer, 0); // purple counts the bytes again while it reads the file, see presage_handle_download_progress
+    purple_xfer_prpl_ready(xfer); // invokes do_transfer which invokes read_fnc
+}
+
+/*
+ * Shows how far the back-end has got with downloading an attachment.
+ *
+ * purple takes the file only after the download has finished. Reading a local file is quick,
+ * so the progress shown while the back-end is downloading is what the user waits for.
+ */
+void presage_handle_download_progress(PurpleConnection *connection, uint64_t request_id, size_t size) {
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    gint64 key = request_id;
+    PurpleXfer *xfer = g_hash_table_lookup(presage->downloads, &key);
+    if (xfer == NULL || size > purple_xfer_get_size(xfer)) {
+        // e.g. saved automatically, there is no transfer to show the progress in
+        return;
+    }
+    purple_xfer_set_bytes_sent(xfer, size);
+    purple_xfer_update_progress(xfer);
+}
+
+/*
+ * Tells the group chat about the attachment.
+ * A transfer is always tied to a contact, so it does not show up in the chat by itself.
+ */
+static void presage_xfer_announce(PurpleConnection *connection, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags flags, uint64_t timestamp, const Attachment *attachment, const char *filename) {
+    char * description = describe_attachment(attachment);
+    char * text = g_strdup_printf("%s „%s“ (%s).", attachment_verb(attachment), filename, description); // MEMCHECK: is released here
+    g_free(description);
+    presage_handle_text(connection, who, name, group, title, flags, timestamp, text);
     g_free(text);
-    // TODO: actually display message
 }
 
-void presage_handle_attachment(PurpleConnection *connection, const char *who, uint64_t timestamp, void *blob, uint64_t blobsize, const char *filename) {
+/*
+ * Offers a received attachment for download.
+ *
+ * If the user configured a directory, the attachment is saved there without asking.
+ * Images are shown in the conversation (unless the user turned that off or they are too large).
+ * Otherwise, the transfer is offered by the contact in the respective conversation.
+ * For a synced attachment, this is the recipient. In a group, this is the sender.
+ */
+void presage_handle_attachment(PurpleConnection *connection, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags flags, uint64_t timestamp, const Attachment *attachment, unsigned char *pointer, size_t pointer_size, const char *filename) {
     g_return_if_fail(connection != NULL);
     PurpleAccount *account = purple_connection_get_account(connection);
 
-    presage_xfer_announce(connection, who, filename);
+    gboolean show_inline = is_inline_image(account, attachment);
+    if (!show_inline && attachment->thumbnail != NULL && purple_account_get_bool(account, "inline-images", TRUE)) {
+        // e.g. a video, show the preview while the attachment itself is handled below
+        write_image(connection, who, name, group, title, flags, timestamp, g_memdup2(attachment->thumbnail, attachment->thumbnail_size), attachment->thumbnail_size, filename);
+    }
+
+    const char *directory = purple_account_get_string(account, "attachment-directory", "");
+    if (directory[0] != 0) {
+        char *destination = auto_save_destination(connection, directory, who, name, title, flags, timestamp, filename);
+        fetch(connection, destination, show_inline, who, name, group, title, flags, timestamp, attachment, pointer, pointer_size, filename);
+        g_free(destination);
+        return;
+    }
+    if (show_inline) {
+        fetch(connection, NULL, TRUE, who, name, group, title, flags, timestamp, attachment, pointer, pointer_size, filename);
+        return;
+    }
+
+    if (group != NULL) {
+        presage_xfer_announce(connection, who, name, group, title, flags, timestamp, attachment, filename);
+    }
+    if (who == NULL) {
+        // the back-end does not include the username for sync messages in groups
+        who = purple_account_get_username(account);
+    }
     
     PurpleXfer * xfer = purple_xfer_new(account, PURPLE_XFER_RECEIVE, who);
     purple_xfer_set_filename(xfer, filename);
-    purple_xfer_set_size(xfer, blobsize);
-    xfer->data = blob;
+    purple_xfer_set_size(xfer, attachment->size); // the actual size is known once the download has finished
+    Download *download = g_new0(Download, 1); // MEMCHECK: released by xfer_release_download
+    download->pointer = pointer;
+    download->pointer_size = pointer_size;
+    xfer->data = download;
     
     purple_xfer_set_init_fnc(xfer, xfer_init_fnc);
     purple_xfer_set_start_fnc(xfer, xfer_start_fnc);
@@ -59,9 +453,9 @@ void presage_handle_attachment(PurpleConnection *connection, const char *who, ui
     purple_xfer_set_ack_fnc(xfer, xfer_ack_fnc);
     
     // be very sure to release the data no matter what
-    purple_xfer_set_end_fnc(xfer, xfer_release_blob);
-    purple_xfer_set_request_denied_fnc(xfer, xfer_release_blob);
-    purple_xfer_set_cancel_recv_fnc(xfer, xfer_release_blob);
+    purple_xfer_set_end_fnc(xfer, xfer_release_download);
+    purple_xfer_set_request_denied_fnc(xfer, xfer_release_download);
+    purple_xfer_set_cancel_recv_fnc(xfer, xfer_release_download);
     
     purple_xfer_request(xfer);
     // MEMCHECK NOTE: purple_xfer_unref calls purple_xfer_destroy which MAY call purple_xfer_cancel_local if (purple_xfer_get_status(xfer) == PURPLE_XFER_STATUS_STARTED) which calls cancel_recv and cancel_local
@@ -2,16 +2,16 @@
 
 #include "presage.h"
 
-void presage_handle_text(PurpleConnection *connection, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags flags, uint64_t timestamp_ms, const char *body) {
+/*
+ * Writes a message into the respective conversation.
+ * The text must be HTML already.
+ */
+void presage_handle_html(PurpleConnection *connection, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags flags, uint64_t timestamp_ms, const char *text) {
     PurpleAccount *account = purple_connection_get_account(connection);
 
     // in Signal, timestamps are milliseconds, but purple wants seconds
     time_t timestamp_seconds = timestamp_ms/1000;
 
-    // Signal is a plain-text protocol, but Pidgin expects HTML
-    // NOTE: This turns newlines into br-tags which may mess up textual representation of QR-codes
-    gchar *text = purple_markup_escape_text(body, -1);
-    
     if (group == NULL) {
         // direct message
         presage_blist_update_buddy(account, who, name); // add to blist first for aliasing
@@ -44,6 +44,12 @@ void presage_handle_text(PurpleConnection *connection, const char *who, const ch
         }
         purple_serv_got_chat_in(connection, g_str_hash(group), who, flags, text, timestamp_seconds);
     }
+}
 
+void presage_handle_text(PurpleConnection *connection, const char *who, const char *name, const char *group, const char *title, PurpleMessageFlags flags, uint64_t timestamp_ms, const char *body) {
+    // Signal is a plain-text protocol, but Pidgin expects HTML
+    // NOTE: This turns newlines into br-tags which may mess up textual representation of QR-codes
+    gchar *text = purple_markup_escape_text(body, -1);
+    presage_handle_html(connection, who, name, group, title, flags, timestamp_ms, text);
     g_free(text);
 }
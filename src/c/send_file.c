@@ -6,10 +6,14 @@ static void xfer_start_fnc(PurpleXfer *xfer) {
     PurpleAccount *account = purple_xfer_get_account(xfer);
     PurpleConnection *connection = purple_account_get_connection(account);
     Presage *presage = purple_connection_get_protocol_data(connection);
+    PresageStatus status = PRESAGE_STATUS_OK;
     if (destination_type == PURPLE_CONV_TYPE_IM) {
-        presage_rust_send_contact(rust_runtime, presage->tx_ptr, xfer->who, NULL, xfer);
+        status = presage_rust_send_contact(presage->tx_ptr, presage_next_request_id(presage), xfer->who, NULL, xfer);
     } else if (destination_type == PURPLE_CONV_TYPE_CHAT) {
-        presage_rust_send_group(rust_runtime, presage->tx_ptr, xfer->who, NULL, xfer);
+        status = presage_rust_send_group(presage->tx_ptr, presage_next_request_id(presage), xfer->who, NULL, xfer);
+    }
+    if (status != PRESAGE_STATUS_OK) {
+        presage_handle_xfer(xfer, PURPLE_MESSAGE_ERROR, presage_status_message(status));
     }
 }
 
@@ -2,12 +2,60 @@
 
 #include "presage.h"
 
+/*
+ * Remembers the text of a message while it is being sent.
+ *
+ * @return The identifier of the request for sending the message.
+ */
+static uint64_t pending_message_add(Presage *presage, const char *msg) {
+    uint64_t request_id = presage_next_request_id(presage);
+    gint64 *key = g_new(gint64, 1); // MEMCHECK: released by the hash table
+    *key = request_id;
+    g_hash_table_insert(presage->pending_messages, key, g_strdup(msg));
+    return request_id;
+}
+
+/*
+ * Retrieves and forgets the text of a message which was being sent.
+ *
+ * @return The text of the message (caller must g_free it) or NULL if the request is unknown.
+ */
+char * presage_pending_message_take(Presage *presage, uint64_t request_id) {
+    g_return_val_if_fail(presage->pending_messages != NULL, NULL);
+    gint64 key = request_id;
+    char *text = g_strdup(g_hash_table_lookup(presage->pending_messages, &key));
+    g_hash_table_remove(presage->pending_messages, &key);
+    return text;
+}
+
+/*
+ * Checks whether the message contains inline images.
+ *
+ * Stripping the HTML would drop them silently. They can only be sent as files.
+ * Some UIs write the tags in upper case.
+ */
+static gboolean contains_image(const char *message) {
+    return purple_strcasestr(message, "<img") != NULL;
+}
+
+static const char *IMAGES_NOT_SUPPORTED = "Images cannot be sent inline. Please send them as files.";
+
 int presage_send_im(PurpleConnection *connection, const char *who, const char *message, PurpleMessageFlags flags) {
+    if (contains_image(message)) {
+        presage_handle_text(connection, who, NULL, NULL, NULL, PURPLE_MESSAGE_ERROR, (uint64_t)time(NULL)*1000, IMAGES_NOT_SUPPORTED);
+        return 0;
+    }
     // strip HTML similar to these reasons: https://github.com/majn/telegram-purple/issues/12 and https://github.com/majn/telegram-purple/commit/fffe751
     char *msg = purple_markup_strip_html(message); // NOTE: This turns newlines into spaces and <br> tags into newlines
     Presage *presage = purple_connection_get_protocol_data(connection);
-    presage_rust_send_contact(rust_runtime, presage->tx_ptr, who, msg, NULL);
+    uint64_t request_id = pending_message_add(presage, msg);
+    PresageStatus status = presage_rust_send_contact(presage->tx_ptr, request_id, who, msg, NULL);
     g_free(msg);
+    if (status != PRESAGE_STATUS_OK) {
+        g_free(presage_pending_message_take(presage, request_id)); // there will be no result
+        // report the error in the conversation
+        presage_handle_text(connection, who, NULL, NULL, NULL, PURPLE_MESSAGE_ERROR, (uint64_t)time(NULL)*1000, presage_status_message(status));
+    }
     return 0; // do not report an error here; also no local echo since the rust part is expected inject the message
 }
 
@@ -16,11 +64,19 @@ int presage_send_chat(PurpleConnection *connection, int id, const gchar *message
     PurpleConversation *conv = purple_find_chat(connection, id);
     if (conv != NULL) {
         gchar *group = (gchar *)purple_conversation_get_data(conv, "name");
-        if (group != NULL) {
+        if (group != NULL && contains_image(message)) {
+            purple_serv_got_chat_in(connection, id, PLUGIN_NAME, PURPLE_MESSAGE_ERROR, IMAGES_NOT_SUPPORTED, time(NULL));
+        } else if (group != NULL) {
             // strip HTML similar to these reasons: https://github.com/majn/telegram-purple/issues/12 and https://github.com/majn/telegram-purple/commit/fffe751
             char *msg = purple_markup_strip_html(message); // NOTE: This turns newlines into spaces and <br> tags into newlines
-            presage_rust_send_group(rust_runtime, presage->tx_ptr, group, msg, NULL);
+            uint64_t request_id = pending_message_add(presage, msg);
+            PresageStatus status = presage_rust_send_group(presage->tx_ptr, request_id, group, msg, NULL);
             g_free(msg);
+            if (status != PRESAGE_STATUS_OK) {
+                g_free(presage_pending_message_take(presage, request_id)); // there will be no result
+                // report the error in the conversation
+                purple_serv_got_chat_in(connection, id, PLUGIN_NAME, PURPLE_MESSAGE_ERROR, presage_status_message(status), time(NULL));
+            }
         }
     }
     return 0; // do not report an error here; also no local echo since the rust part is expected inject the message
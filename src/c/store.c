@@ -0,0 +1,315 @@
+#include "presage.h"
+
+/*
+ * The local store can be encrypted with a passphrase.
+ * The passphrase is not saved. The user is asked for it on every login.
+ *
+ * If the store cannot be upgraded to the current version, the user decides what to do with it.
+ *
+ * The store can be exported into an encrypted backup file. A backup is imported on login, before this device is linked.
+ * An encrypted store stays encrypted with its own passphrase within the backup.
+ *
+ * Unlinking deletes the store.
+ */
+
+static void notify(PurpleConnection *connection, PurpleNotifyMsgType type, const char *primary, const char *secondary) {
+    #if PURPLE_VERSION_CHECK(3,0,0)
+    purple_notify_message(connection, type, "Signal Protocol", primary, secondary, purple_request_cpar_from_connection(connection), NULL, NULL);
+    #else
+    purple_notify_message(connection, type, "Signal Protocol", primary, secondary, NULL, NULL);
+    #endif
+}
+
+static void unlock_ok(PurpleConnection *connection, const char *passphrase) {
+    if (passphrase == NULL || passphrase[0] == 0) {
+        purple_connection_error(connection, PURPLE_CONNECTION_ERROR_AUTHENTICATION_IMPOSSIBLE, "A passphrase is needed to unlock the store.");
+    } else {
+        presage_start(connection, passphrase);
+    }
+}
+
+static void unlock_cancel(PurpleConnection *connection, const char *passphrase) {
+    purple_connection_error(connection, PURPLE_CONNECTION_ERROR_AUTHENTICATION_IMPOSSIBLE, "Unlocking the store was cancelled.");
+}
+
+void presage_request_passphrase(PurpleConnection *connection) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    purple_request_input(
+        connection, "Signal Protocol", "Unlock store",
+        "Enter the passphrase for the local store. A new store is encrypted with this passphrase.",
+        NULL, FALSE, TRUE, NULL,
+        "Unlock", G_CALLBACK(unlock_ok),
+        "Cancel", G_CALLBACK(unlock_cancel),
+        purple_request_cpar_from_account(account),
+        connection);
+}
+
+static void change_passphrase_ok(PurpleConnection *connection, PurpleRequestFields *fields) {
+    const char *passphrase = purple_request_fields_get_string(fields, "passphrase");
+    const char *confirmation = purple_request_fields_get_string(fields, "confirmation");
+    if (passphrase == NULL || passphrase[0] == 0) {
+        notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Passphrase not changed", "The passphrase must not be empty.");
+    } else if (!purple_strequal(passphrase, confirmation)) {
+        notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Passphrase not changed", "The passphrases do not match.");
+    } else {
+        Presage *presage = purple_connection_get_protocol_data(connection);
+        PresageStatus status = presage_rust_change_passphrase(presage->tx_ptr, presage_next_request_id(presage), passphrase);
+        if (status != PRESAGE_STATUS_OK) {
+            notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Passphrase not changed", presage_status_message(status));
+        }
+    }
+}
+
+static void change_passphrase_cancel(PurpleConnection *connection, PurpleRequestFields *fields) {
+    // nothing to do
+}
+
+void presage_change_passphrase(PurpleConnection *connection) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    if (!purple_account_get_bool(account, "encrypt-store", FALSE)) {
+        notify(connection, PURPLE_NOTIFY_MSG_ERROR, "The store is not encrypted", "Encryption can only be enabled for a new store, before linking.");
+        return;
+    }
+
+    PurpleRequestFields* fields = purple_request_fields_new();
+    PurpleRequestFieldGroup* group = purple_request_field_group_new(NULL);
+    purple_request_fields_add_group(fields, group);
+    {
+        PurpleRequestField *field = purple_request_field_string_new("passphrase", "New passphrase", NULL, FALSE);
+        purple_request_field_string_set_masked(field, TRUE);
+        purple_request_field_group_add_field(group, field);
+    }
+    {
+        PurpleRequestField *field = purple_request_field_string_new("confirmation", "Repeat new passphrase", NULL, FALSE);
+        purple_request_field_string_set_masked(field, TRUE);
+        purple_request_field_group_add_field(group, field);
+    }
+
+    purple_request_fields(
+        connection, "Signal Protocol", "Change store passphrase",
+        "The account is re-connected after the passphrase has been changed.",
+        fields,
+        "Change", G_CALLBACK(change_passphrase_ok),
+        "Cancel", G_CALLBACK(change_passphrase_cancel),
+        purple_request_cpar_from_account(account),
+        connection);
+}
+
+void presage_handle_passphrase_changed(PurpleConnection *connection, const char *error) {
+    if (error == NULL) {
+        notify(connection, PURPLE_NOTIFY_MSG_INFO, "Passphrase changed", "The store is now encrypted with the new passphrase.");
+    } else {
+        notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Passphrase not changed", error);
+    }
+}
+
+enum {
+    MIGRATION_BACKUP_AND_RELINK,
+    MIGRATION_DROP,
+    MIGRATION_ABORT,
+};
+
+static void migration_conflict_action(PurpleConnection *connection, int action) {
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    switch (action) {
+        case MIGRATION_BACKUP_AND_RELINK:
+            presage_check_status(connection, presage_rust_resolve_migration_conflict(presage->tx_ptr, TRUE));
+            break;
+        case MIGRATION_DROP:
+            presage_check_status(connection, presage_rust_resolve_migration_conflict(presage->tx_ptr, FALSE));
+            break;
+        default:
+            // the store is left as it is, the back-end is stopped when the connection is closed
+            purple_connection_error(connection, PURPLE_CONNECTION_ERROR_OTHER_ERROR, "The store could not be upgraded and was left as it is.");
+            break;
+    }
+}
+
+void presage_handle_migration_conflict(PurpleConnection *connection) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    purple_request_action(
+        connection, "Signal Protocol", "The local store cannot be upgraded",
+        "The store was written by an incompatible version of presage. It can be dropped so this device can be linked again. Sessions and message history will be lost.",
+        MIGRATION_BACKUP_AND_RELINK,
+        purple_request_cpar_from_account(account),
+        connection,
+        3,
+        "Back up and re-link", G_CALLBACK(migration_conflict_action),
+        "Drop", G_CALLBACK(migration_conflict_action),
+        "Abort", G_CALLBACK(migration_conflict_action));
+}
+
+void presage_handle_store_reset(PurpleConnection *connection, const char *backup_path) {
+    if (backup_path != NULL) {
+        char *secondary = g_strdup_printf("A backup has been written to %s. This device needs to be linked again.", backup_path);
+        notify(connection, PURPLE_NOTIFY_MSG_INFO, "The local store has been reset", secondary);
+        g_free(secondary);
+    } else {
+        notify(connection, PURPLE_NOTIFY_MSG_INFO, "The local store has been reset", "This device needs to be linked again.");
+    }
+}
+
+static void import_ok(PurpleConnection *connection, const char *passphrase) {
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    PurpleAccount *account = purple_connection_get_account(connection);
+    const char *path = purple_account_get_string(account, "import-backup", "");
+    presage_check_status(connection, presage_rust_import_backup(presage->tx_ptr, presage_next_request_id(presage), path, passphrase == NULL ? "" : passphrase));
+    // the back-end reports the outcome, see presage_handle_backup_imported
+}
+
+static void import_cancel(PurpleConnection *connection, const char *passphrase) {
+    // carry on without importing
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    presage_check_status(connection, presage_rust_whoami(presage->tx_ptr));
+}
+
+void presage_request_import(PurpleConnection *connection) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    char *secondary = g_strdup_printf("Enter the passphrase for the backup in %s.\n\nIf the backup has been exported from an encrypted store, the store stays encrypted with its old passphrase. That passphrase must have been entered for unlocking the store on this login.", purple_account_get_string(account, "import-backup", ""));
+    purple_request_input(
+        connection, "Signal Protocol", "Import account backup",
+        secondary,
+        NULL, FALSE, TRUE, NULL,
+        "Import", G_CALLBACK(import_ok),
+        "Skip", G_CALLBACK(import_cancel),
+        purple_request_cpar_from_account(account),
+        connection);
+    g_free(secondary);
+}
+
+void presage_handle_backup_imported(PurpleConnection *connection, const char *error) {
+    if (error == NULL) {
+        // do not import again on the next login
+        purple_account_set_string(purple_connection_get_account(connection), "import-backup", "");
+        notify(connection, PURPLE_NOTIFY_MSG_INFO, "Backup imported", "The account has been restored from the backup.");
+    } else {
+        notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Backup not imported", error);
+    }
+    // carry on as usual, the back-end does not do this on its own after an import
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    presage_check_status(connection, presage_rust_whoami(presage->tx_ptr));
+}
+
+// the export dialog asks for the file first, then for the passphrase
+typedef struct {
+    PurpleConnection *connection;
+    char *path;
+} ExportRequest;
+
+static void export_ok(ExportRequest *request, PurpleRequestFields *fields) {
+    PurpleConnection *connection = request->connection;
+    const char *passphrase = purple_request_fields_get_string(fields, "passphrase");
+    const char *confirmation = purple_request_fields_get_string(fields, "confirmation");
+    gboolean history = purple_request_fields_get_bool(fields, "history");
+    if (passphrase == NULL || passphrase[0] == 0) {
+        notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Backup not exported", "The passphrase must not be empty.");
+    } else if (!purple_strequal(passphrase, confirmation)) {
+        notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Backup not exported", "The passphrases do not match.");
+    } else {
+        Presage *presage = purple_connection_get_protocol_data(connection);
+        PresageStatus status = presage_rust_export_backup(presage->tx_ptr, presage_next_request_id(presage), request->path, passphrase, history);
+        if (status != PRESAGE_STATUS_OK) {
+            notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Backup not exported", presage_status_message(status));
+        }
+    }
+    g_free(request->path);
+    g_free(request);
+}
+
+static void export_cancel(ExportRequest *request, PurpleRequestFields *fields) {
+    g_free(request->path);
+    g_free(request);
+}
+
+static void export_file_ok(PurpleConnection *connection, const char *path) {
+    ExportRequest *request = g_new0(ExportRequest, 1);
+    request->connection = connection;
+    request->path = g_strdup(path);
+
+    PurpleRequestFields* fields = purple_request_fields_new();
+    PurpleRequestFieldGroup* group = purple_request_field_group_new(NULL);
+    purple_request_fields_add_group(fields, group);
+    {
+        PurpleRequestField *field = purple_request_field_string_new("passphrase", "Passphrase", NULL, FALSE);
+        purple_request_field_string_set_masked(field, TRUE);
+        purple_request_field_group_add_field(group, field);
+    }
+    {
+        PurpleRequestField *field = purple_request_field_string_new("confirmation", "Repeat passphrase", NULL, FALSE);
+        purple_request_field_string_set_masked(field, TRUE);
+        purple_request_field_group_add_field(group, field);
+    }
+    {
+        PurpleRequestField *field = purple_request_field_bool_new("history", "Include messages", FALSE);
+        purple_request_field_group_add_field(group, field);
+    }
+
+    PurpleAccount *account = purple_connection_get_account(connection);
+    GString *secondary = g_string_new("The backup contains the keys of this device. Anyone who has the file and the passphrase can read and send messages in your name. The account is re-connected after the backup has been written.");
+    if (purple_account_get_bool(account, "encrypt-store", FALSE)) {
+        // the store's files are put into the backup as they are
+        g_string_append(secondary, "\n\nThe local store is encrypted and stays encrypted within the backup. Importing the backup needs the store's current passphrase, too: Enable encryption of the local store for the importing account and enter that passphrase when asked for it on login.");
+    }
+    purple_request_fields(
+        connection, "Signal Protocol", "Export account backup",
+        secondary->str,
+        fields,
+        "Export", G_CALLBACK(export_ok),
+        "Cancel", G_CALLBACK(export_cancel),
+        purple_request_cpar_from_account(account),
+        request);
+    g_string_free(secondary, TRUE);
+}
+
+static void export_file_cancel(PurpleConnection *connection, const char *path) {
+    // nothing to do
+}
+
+void presage_export_backup(PurpleConnection *connection) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    purple_request_file(
+        connection, "Export account backup", "signal-backup.bin", TRUE,
+        G_CALLBACK(export_file_ok), G_CALLBACK(export_file_cancel),
+        purple_request_cpar_from_account(account),
+        connection);
+}
+
+void presage_handle_backup_exported(PurpleConnection *connection, const char *error) {
+    if (error == NULL) {
+        notify(connection, PURPLE_NOTIFY_MSG_INFO, "Backup exported", "The backup has been written.");
+    } else {
+        notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Backup not exported", error);
+    }
+}
+
+static void unlink_action(PurpleConnection *connection, int action) {
+    if (action == 0) {
+        Presage *presage = purple_connection_get_protocol_data(connection);
+        PresageStatus status = presage_rust_unlink(presage->tx_ptr, presage_next_request_id(presage));
+        if (status != PRESAGE_STATUS_OK) {
+            notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Device not unlinked", presage_status_message(status));
+        }
+    }
+}
+
+void presage_unlink(PurpleConnection *connection) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    purple_request_action(
+        connection, "Signal Protocol", "Unlink this device?",
+        "All data of this account is deleted from this computer, including keys and messages. The device needs to be linked again for further use.",
+        1, // default to cancel
+        purple_request_cpar_from_account(account),
+        connection,
+        2,
+        "Unlink", G_CALLBACK(unlink_action),
+        "Cancel", G_CALLBACK(unlink_action));
+}
+
+void presage_handle_unlinked(PurpleConnection *connection, const char *error) {
+    if (error == NULL) {
+        // do not start linking again right away
+        purple_connection_error(connection, PURPLE_CONNECTION_ERROR_OTHER_ERROR, "This device has been unlinked. Please also remove it in the Signal app under „Linked devices“. Enable the account to link again.");
+    } else {
+        notify(connection, PURPLE_NOTIFY_MSG_ERROR, "Device not unlinked", error);
+    }
+}
@@ -0,0 +1,123 @@
+#include "presage.h"
+
+/*
+ * Signal clients repeat the started action every few seconds while the user is typing.
+ * If neither that nor the stopped action arrives, the contact is assumed to have stopped after this many seconds.
+ */
+#define TYPING_TIMEOUT 15
+
+/*
+ * While typing, Signal clients repeat the started action after this many seconds.
+ */
+#define TYPING_REPEAT 10
+
+/*
+ * A member of a group who is typing.
+ * purple does not time out typing in chats, so this is done here.
+ */
+typedef struct {
+    PurpleConnection *connection;
+    const char *key; // owned by the hash table
+    char *group;
+    char *who;
+    guint timeout;
+} ChatTyping;
+
+void presage_chat_typing_free(gpointer data) {
+    ChatTyping *typing = data;
+    if (typing->timeout != 0) {
+        purple_timeout_remove(typing->timeout);
+    }
+    g_free(typing->group);
+    g_free(typing->who);
+    g_free(typing);
+}
+
+/*
+ * Marks a member as typing (or not) in the member list of a group chat.
+ * Nothing happens if the chat is not open or the member is not listed.
+ */
+static void chat_set_typing(PurpleConnection *connection, const char *group, const char *who, gboolean typing) {
+    PurpleConversation *conv = purple_find_chat(connection, g_str_hash(group));
+    if (conv == NULL) {
+        return;
+    }
+    PurpleConvChat *conv_chat = purple_conversation_get_chat_data(conv);
+    if (purple_conv_chat_find_user(conv_chat, who) == NULL) {
+        return;
+    }
+    PurpleConvChatBuddyFlags flags = purple_conv_chat_user_get_flags(conv_chat, who);
+    if (typing) {
+        flags |= PURPLE_CBFLAGS_TYPING;
+    } else {
+        flags &= ~PURPLE_CBFLAGS_TYPING;
+    }
+    purple_conv_chat_user_set_flags(conv_chat, who, flags);
+}
+
+static gboolean chat_typing_expired(gpointer data) {
+    ChatTyping *typing = data;
+    typing->timeout = 0; // the timer ends by returning FALSE
+    chat_set_typing(typing->connection, typing->group, typing->who, FALSE);
+    Presage *presage = purple_connection_get_protocol_data(typing->connection);
+    g_hash_table_remove(presage->chat_typing, typing->key); // MEMCHECK: releases typing
+    return FALSE;
+}
+
+/*
+ * A contact started or stopped typing.
+ * In a direct conversation, purple takes care of the rest. In a group chat, the member is marked in the member list.
+ */
+void presage_handle_typing(PurpleConnection *connection, const char *who, const char *group, gboolean started) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    if (!purple_account_get_bool(account, "typing-notifications", TRUE)) {
+        return;
+    }
+
+    if (group == NULL) {
+        if (started) {
+            purple_serv_got_typing(connection, who, TYPING_TIMEOUT, PURPLE_IM_TYPING);
+        } else {
+            purple_serv_got_typing_stopped(connection, who);
+        }
+        return;
+    }
+
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    char *key = g_strdup_printf("%s %s", group, who);
+    g_hash_table_remove(presage->chat_typing, key); // forget about the previous timeout
+    if (started) {
+        ChatTyping *typing = g_new0(ChatTyping, 1); // MEMCHECK: released by the hash table
+        typing->connection = connection;
+        typing->key = key;
+        typing->group = g_strdup(group);
+        typing->who = g_strdup(who);
+        typing->timeout = purple_timeout_add(TYPING_TIMEOUT * 1000, chat_typing_expired, typing);
+        g_hash_table_insert(presage->chat_typing, key, typing); // MEMCHECK: the hash table takes ownership of key
+    } else {
+        g_free(key);
+    }
+    chat_set_typing(connection, group, who, started);
+}
+
+/*
+ * The user started or stopped typing in a direct conversation.
+ * purple has no notion of typing in group chats, so nothing is sent there.
+ *
+ * @return The number of seconds after which purple should tell again that the user is still typing.
+ */
+unsigned int presage_send_typing(PurpleConnection *connection, const char *who, PurpleIMTypingState state) {
+    PurpleAccount *account = purple_connection_get_account(connection);
+    if (!purple_account_get_bool(account, "typing-notifications", TRUE)) {
+        return 0;
+    }
+    Presage *presage = purple_connection_get_protocol_data(connection);
+    // Signal does not know about pausing, the official clients send "stopped" instead
+    gboolean started = state == PURPLE_IM_TYPING;
+    PresageStatus status = presage_rust_send_typing(presage->tx_ptr, who, NULL, started);
+    if (status != PRESAGE_STATUS_OK) {
+        // not worth bothering the user about
+        purple_debug_info(PLUGIN_NAME, "Unable to send typing notification: %s\n", presage_status_message(status));
+    }
+    return started ? TYPING_REPEAT : 0;
+}
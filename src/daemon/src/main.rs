@@ -0,0 +1,244 @@
+/*
+ * A headless front-end for the presage back-end.
+ *
+ * Reads JSON-RPC 2.0 requests from stdin, one per line, and maps them onto the back-end's commands.
+ * Writes responses and events (as notifications with method "event") to stdout, one per line.
+ * Log messages go to stderr.
+ *
+ * Usage: presage-daemon <store path>
+ * If the store is encrypted, the passphrase is read from the environment variable PRESAGE_PASSPHRASE.
+ * New identities of contacts are trusted unless the environment variable PRESAGE_REJECT_NEW_IDENTITIES is set.
+ */
+
+use tokio::io::AsyncBufReadExt; // for lines()
+
+use purple_presage_backend::structs::{Cmd, Event, Recipient};
+
+/*
+ * Writes one JSON value per line to stdout.
+ */
+fn write_line(value: serde_json::Value) {
+    use std::io::Write;
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{value}");
+    let _ = stdout.flush();
+}
+
+fn error_response(
+    id: serde_json::Value,
+    code: i32,
+    message: String,
+) -> serde_json::Value {
+    serde_json::json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+/*
+ * Forwards events to stdout.
+ *
+ * Also drives the connection set-up the same way the libpurple front-end does:
+ * whoami → (link →) initial sync → receive.
+ */
+struct JsonSink {
+    tx: tokio::sync::mpsc::Sender<Cmd>,
+}
+
+impl JsonSink {
+    /*
+     * Queues a command in reaction to an event.
+     *
+     * The queue may be full while the back-end is busy, so the command is sent from a task of its own
+     * which waits for room. Should the back-end have stopped, the client is told by an error without id.
+     */
+    fn follow_up(
+        &self,
+        cmd: Cmd,
+    ) {
+        let tx = self.tx.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(err) = tx.send(cmd).await {
+                write_line(error_response(serde_json::Value::Null, -32603, format!("unable to queue follow-up command {}: back-end has stopped", err.0.name())));
+            }
+        });
+    }
+}
+
+impl purple_presage_backend::sink::EventSink for JsonSink {
+    fn append(
+        &self,
+        event: Event,
+    ) {
+        if let Event::Log { level, message } = &event {
+            eprint!("presage ({level}): {message}");
+            return;
+        }
+        let follow_up = match &event {
+            // empty string means "linking has finished", now get own uuid
+            Event::QrCode(url) if url.is_empty() => Some(Cmd::Whoami),
+            // this device is linked, synchronize before sending anything
+            Event::Uuid(uuid) if !uuid.is_empty() => Some(Cmd::InitialSync),
+            Event::Connected => Some(Cmd::Receive),
+            // continue as usual, whether the backup was imported or not
+            Event::BackupImported { .. } => Some(Cmd::Whoami),
+            _ => None,
+        };
+        write_line(serde_json::json!({"jsonrpc": "2.0", "method": "event", "params": event}));
+        if let Some(cmd) = follow_up {
+            self.follow_up(cmd);
+        }
+    }
+}
+
+fn parse_recipient(value: &serde_json::Value) -> Result<presage::libsignal_service::prelude::Uuid, (i32, String)> {
+    let uuid = value.as_str().ok_or((-32602, String::from("recipient is required")))?;
+    presage::libsignal_service::prelude::Uuid::parse_str(uuid).map_err(|err| (-32602, format!("recipient is not a uuid: {err}")))
+}
+
+fn parse_group(value: &serde_json::Value) -> Result<presage::libsignal_service::zkgroup::GroupMasterKeyBytes, (i32, String)> {
+    let key = value.as_str().ok_or((-32602, String::from("group is required")))?;
+    purple_presage_backend::structs::parse_group_master_key(key).map_err(|err| (-32602, format!("group: {err}")))
+}
+
+fn parse_recipient_or_group(params: &serde_json::Value) -> Result<Recipient, (i32, String)> {
+    if params["recipient"].is_string() {
+        Ok(Recipient::Contact(parse_recipient(&params["recipient"])?))
+    } else if params["group"].is_string() {
+        Ok(Recipient::Group(parse_group(&params["group"])?))
+    } else {
+        Err((-32602, String::from("either recipient or group is required")))
+    }
+}
+
+/*
+ * Maps a JSON-RPC method and its parameters onto a command.
+ *
+ * The request id is echoed by the back-end in the events resulting from the command.
+ */
+fn parse_request(
+    method: &str,
+    params: &serde_json::Value,
+    request_id: u64,
+) -> Result<Cmd, (i32, String)> {
+    match method {
+        "link" => Ok(Cmd::LinkDevice {
+            servers: presage::libsignal_service::configuration::SignalServers::Production,
+            device_name: params["device_name"].as_str().unwrap_or("presage-daemon").to_owned(),
+        }),
+        "whoami" => Ok(Cmd::Whoami),
+        "sync" => Ok(Cmd::InitialSync),
+        "receive" => Ok(Cmd::Receive),
+        "send" => Ok(Cmd::Send {
+            request_id: request_id,
+            recipient: parse_recipient_or_group(params)?,
+            message: params["message"].as_str().map(str::to_owned),
+            attachment: params["attachment"].as_str().map(str::to_owned),
+            xfer: std::ptr::null(),
+        }),
+        "typing" => Ok(Cmd::SendTyping {
+            recipient: parse_recipient_or_group(params)?,
+            started: params["started"].as_bool().unwrap_or(true),
+        }),
+        "list_groups" => Ok(Cmd::ListGroups { request_id: request_id }),
+        "members" => Ok(Cmd::GetGroupMembers {
+            request_id: request_id,
+            master_key_bytes: parse_group(&params["group"])?,
+        }),
+        "change_passphrase" => Ok(Cmd::ChangePassphrase {
+            request_id: request_id,
+            passphrase: params["passphrase"].as_str().filter(|passphrase| !passphrase.is_empty()).ok_or((-32602, String::from("passphrase is required")))?.to_owned(),
+        }),
+        "resolve_migration_conflict" => Ok(Cmd::ResolveMigrationConflict {
+            backup: params["backup"].as_bool().unwrap_or(true),
+        }),
+        "export_backup" => Ok(Cmd::ExportBackup {
+            request_id: request_id,
+            path: params["path"].as_str().ok_or((-32602, String::from("path is required")))?.to_owned(),
+            passphrase: params["passphrase"].as_str().filter(|passphrase| !passphrase.is_empty()).ok_or((-32602, String::from("passphrase is required")))?.to_owned(),
+            history: params["history"].as_bool().unwrap_or(false),
+        }),
+        "import_backup" => Ok(Cmd::ImportBackup {
+            request_id: request_id,
+            path: params["path"].as_str().ok_or((-32602, String::from("path is required")))?.to_owned(),
+            passphrase: params["passphrase"].as_str().ok_or((-32602, String::from("passphrase is required")))?.to_owned(),
+        }),
+        "unlink" => Ok(Cmd::Unlink { request_id: request_id }),
+        "accept_identity" => Ok(Cmd::AcceptIdentity {
+            request_id: request_id,
+            uuid: parse_recipient(&params["recipient"])?,
+        }),
+        "safety_number" => Ok(Cmd::GetSafetyNumber {
+            request_id: request_id,
+            uuid: parse_recipient(&params["recipient"])?,
+        }),
+        "download" => {
+            let pointer = params["pointer"].as_str().ok_or((-32602, String::from("pointer is required")))?;
+            let pointer = hex::decode(pointer).map_err(|err| (-32602, format!("pointer: {err}")))?;
+            Ok(Cmd::DownloadAttachment {
+                request_id: request_id,
+                pointer: purple_presage_backend::structs::parse_attachment_pointer(&pointer).map_err(|err| (-32602, format!("pointer: {err}")))?,
+                destination: params["path"].as_str().map(str::to_owned),
+            })
+        }
+        "abort_download" => Ok(Cmd::AbortDownload {
+            request_id: params["download"].as_u64().ok_or((-32602, String::from("download is required")))?,
+        }),
+        "exit" => Ok(Cmd::Exit),
+        method => Err((-32601, format!("unknown method {method}"))),
+    }
+}
+
+/*
+ * Reads requests from stdin until end of input. Then stops the back-end.
+ */
+async fn read_requests(tx: tokio::sync::mpsc::Sender<Cmd>) {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let mut last_request_id: u64 = 0;
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                write_line(error_response(serde_json::Value::Null, -32700, err.to_string()));
+                continue;
+            }
+        };
+        let id = request["id"].clone();
+        last_request_id += 1;
+        let response = match parse_request(request["method"].as_str().unwrap_or(""), &request["params"], last_request_id) {
+            Ok(cmd) => match tx.send(cmd).await {
+                Ok(()) => serde_json::json!({"jsonrpc": "2.0", "id": id, "result": {"request_id": last_request_id}}),
+                Err(_) => error_response(id.clone(), -32603, String::from("back-end has stopped")),
+            },
+            Err((code, message)) => error_response(id.clone(), code, message),
+        };
+        // requests without id are notifications which must not be answered
+        if !id.is_null() {
+            write_line(response);
+        }
+    }
+    let _ = tx.send(Cmd::Exit).await;
+}
+
+fn main() {
+    let Some(store_path) = std::env::args().nth(1) else {
+        eprintln!("Usage: presage-daemon <store path>");
+        std::process::exit(2);
+    };
+    let passphrase = std::env::var("PRESAGE_PASSPHRASE").ok();
+    let trust_new_identities = std::env::var_os("PRESAGE_REJECT_NEW_IDENTITIES").is_none();
+    // diagnostics go to stderr, verbosity is controlled via RUST_LOG
+    tracing_subscriber::fmt().with_env_filter(tracing_subscriber::EnvFilter::from_default_env()).with_writer(std::io::stderr).init();
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(32);
+    let sink: purple_presage_backend::sink::Sink = std::rc::Rc::new(JsonSink { tx: tx.clone() });
+    // the back-end spawns local tasks, so it must run inside a LocalSet
+    let local = tokio::task::LocalSet::new();
+    local.block_on(&runtime, async move {
+        tokio::task::spawn_local(read_requests(tx.clone()));
+        // find out whether this device is linked already
+        tx.send(Cmd::Whoami).await.unwrap();
+        purple_presage_backend::core::main(store_path, passphrase, trust_new_identities, rx, sink).await;
+    });
+}
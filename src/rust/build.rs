@@ -0,0 +1,23 @@
+/*
+ * Generates the C header declaring the types and functions shared with the front-end
+ * so the C declarations cannot drift from the rust declarations in bridge.rs and bridge_commands.rs.
+ *
+ * The header is written to PRESAGE_INCLUDE_DIR if set (CMake passes the directory it includes from),
+ * into OUT_DIR otherwise.
+ */
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/bridge.rs");
+    println!("cargo:rerun-if-changed=src/bridge_commands.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=PRESAGE_INCLUDE_DIR");
+    let include_dir = std::env::var("PRESAGE_INCLUDE_DIR").unwrap_or_else(|_| format!("{}/include", std::env::var("OUT_DIR").unwrap()));
+    let config = cbindgen::Config::from_file(format!("{crate_dir}/cbindgen.toml")).expect("Unable to read cbindgen.toml.");
+    cbindgen::Builder::new()
+        .with_src(format!("{crate_dir}/src/bridge.rs"))
+        .with_src(format!("{crate_dir}/src/bridge_commands.rs"))
+        .with_config(config)
+        .generate()
+        .expect("Unable to generate C header.")
+        .write_to_file(format!("{include_dir}/purple_presage_backend.h"));
+}
@@ -0,0 +1,230 @@
+/*
+ * Decryption of attachments while they are being downloaded.
+ *
+ * Layout of an encrypted attachment: IV, AES-256-CBC ciphertext (PKCS#7 padded), HMAC-SHA256 over IV and ciphertext.
+ * The key in the attachment pointer is 64 bytes: the AES key followed by the HMAC key.
+ * The pointer also carries the SHA-256 digest of the entire encrypted attachment.
+ * Signal clients pad the plaintext further. The pointer's size tells how much of it is the actual file.
+ *
+ * The MAC and digest can only be checked at the very end. Until then, the plaintext must be treated as unverified.
+ *
+ * This is the same scheme libsignal-service implements. Its implementation only decrypts an attachment as a whole, though.
+ */
+
+use aes::cipher::{BlockDecryptMut, KeyIvInit}; // for cbc::Decryptor::new() and decrypt_block_mut()
+use hmac::Mac; // for Hmac::new_from_slice(), update() and verify_slice()
+use sha2::Digest; // for Sha256::update() and finalize()
+
+const KEY_LENGTH: usize = 64;
+const IV_LENGTH: usize = 16;
+const BLOCK_LENGTH: usize = 16;
+const MAC_LENGTH: usize = 32;
+
+pub struct Decryptor {
+    aes_key: [u8; 32],
+    cipher: Option<cbc::Decryptor<aes::Aes256>>, // None until the IV has arrived
+    mac: hmac::Hmac<sha2::Sha256>,
+    digest: sha2::Sha256,
+    expected_digest: Option<Vec<u8>>,
+    pending: Vec<u8>,         // received, but not decrypted, yet
+    remaining: Option<usize>, // bytes of the file which have not been handed out, yet
+}
+
+/*
+ * Hands out no more than the size of the file.
+ */
+fn limit(
+    remaining: &mut Option<usize>,
+    mut plaintext: Vec<u8>,
+) -> Vec<u8> {
+    if let Some(remaining) = remaining.as_mut() {
+        plaintext.truncate(*remaining);
+        *remaining -= plaintext.len();
+    }
+    plaintext
+}
+
+impl Decryptor {
+    /*
+     * Prepares decryption according to an attachment pointer's key, digest and size.
+     */
+    pub fn new(
+        key: &[u8],
+        expected_digest: Option<&[u8]>,
+        size: Option<usize>,
+    ) -> Result<Self, String> {
+        if key.len() != KEY_LENGTH {
+            return Err(format!("The attachment's key has {} bytes instead of {KEY_LENGTH}.", key.len()));
+        }
+        let (aes_key, mac_key) = key.split_at(32);
+        Ok(Self {
+            aes_key: aes_key.try_into().unwrap(),
+            cipher: None,
+            mac: hmac::Hmac::new_from_slice(mac_key).unwrap(), // HMAC takes keys of any length
+            digest: sha2::Sha256::new(),
+            expected_digest: expected_digest.map(<[u8]>::to_vec),
+            pending: vec![],
+            remaining: size,
+        })
+    }
+
+    /*
+     * Decrypts the next piece of the download.
+     *
+     * Returns as much plaintext as can be decrypted so far. The last block and the MAC are held back for `finish`.
+     */
+    pub fn update(
+        &mut self,
+        data: &[u8],
+    ) -> Vec<u8> {
+        self.digest.update(data);
+        self.pending.extend_from_slice(data);
+        if self.cipher.is_none() {
+            if self.pending.len() < IV_LENGTH {
+                return vec![];
+            }
+            let iv: Vec<u8> = self.pending.drain(..IV_LENGTH).collect();
+            self.mac.update(&iv);
+            self.cipher = Some(cbc::Decryptor::new(&self.aes_key.into(), iv.as_slice().into()));
+        }
+        // the padding in the last block can only be removed at the end
+        let available = self.pending.len().saturating_sub(MAC_LENGTH + BLOCK_LENGTH);
+        let mut blocks: Vec<u8> = self.pending.drain(..available - available % BLOCK_LENGTH).collect();
+        self.mac.update(&blocks);
+        let cipher = self.cipher.as_mut().unwrap();
+        for block in blocks.chunks_exact_mut(BLOCK_LENGTH) {
+            cipher.decrypt_block_mut(block.into());
+        }
+        limit(&mut self.remaining, blocks)
+    }
+
+    /*
+     * Checks the integrity of the entire download and returns the rest of the plaintext.
+     *
+     * If this fails, everything handed out before must be discarded.
+     */
+    pub fn finish(mut self) -> Result<Vec<u8>, String> {
+        if self.pending.len() != BLOCK_LENGTH + MAC_LENGTH || self.cipher.is_none() {
+            return Err(String::from("The attachment is truncated."));
+        }
+        if let Some(expected_digest) = &self.expected_digest {
+            if self.digest.finalize().as_slice() != expected_digest.as_slice() {
+                return Err(String::from("The attachment's digest does not match. It is damaged or has been tampered with."));
+            }
+        }
+        let mut last_block = self.pending[..BLOCK_LENGTH].to_vec();
+        self.mac.update(&last_block);
+        self.mac.verify_slice(&self.pending[BLOCK_LENGTH..]).map_err(|_| String::from("The attachment's MAC does not match. It is damaged or has been tampered with."))?;
+        self.cipher.as_mut().unwrap().decrypt_block_mut(last_block.as_mut_slice().into());
+        let padding = last_block[BLOCK_LENGTH - 1] as usize;
+        if padding == 0 || padding > BLOCK_LENGTH || !last_block[BLOCK_LENGTH - padding..].iter().all(|&byte| byte as usize == padding) {
+            return Err(String::from("The attachment's padding is invalid."));
+        }
+        last_block.truncate(BLOCK_LENGTH - padding);
+        let plaintext = limit(&mut self.remaining, last_block);
+        match self.remaining {
+            Some(remaining) if remaining > 0 => Err(String::from("The attachment is smaller than announced.")),
+            _ => Ok(plaintext),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::BlockEncryptMut; // for encrypt_padded_vec_mut()
+
+    /*
+     * Encrypts like a Signal client does, padding the plaintext to `padded_length`.
+     */
+    fn encrypt(
+        key: &[u8; 64],
+        plaintext: &[u8],
+        padded_length: usize,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let iv = [7u8; 16];
+        let mut padded = plaintext.to_vec();
+        padded.resize(padded_length, 0);
+        let ciphertext = <cbc::Encryptor<aes::Aes256> as aes::cipher::KeyIvInit>::new(key[..32].into(), &iv.into()).encrypt_padded_vec_mut::<aes::cipher::block_padding::Pkcs7>(&padded);
+        let mut attachment = iv.to_vec();
+        attachment.extend_from_slice(&ciphertext);
+        let mut mac = <hmac::Hmac<sha2::Sha256> as hmac::Mac>::new_from_slice(&key[32..]).unwrap();
+        hmac::Mac::update(&mut mac, &attachment);
+        attachment.extend_from_slice(&hmac::Mac::finalize(mac).into_bytes());
+        let digest = <sha2::Sha256 as sha2::Digest>::digest(&attachment).to_vec();
+        (attachment, digest)
+    }
+
+    /*
+     * Decrypts the attachment handed over in pieces of the given length.
+     */
+    fn decrypt(
+        key: &[u8; 64],
+        attachment: &[u8],
+        digest: &[u8],
+        size: usize,
+        piece_length: usize,
+    ) -> Result<Vec<u8>, String> {
+        let mut decryptor = super::Decryptor::new(key, Some(digest), Some(size))?;
+        let mut plaintext = vec![];
+        for piece in attachment.chunks(piece_length) {
+            plaintext.extend(decryptor.update(piece));
+        }
+        plaintext.extend(decryptor.finish()?);
+        Ok(plaintext)
+    }
+
+    fn key() -> [u8; 64] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn pieces_of_any_length() {
+        let plaintext: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let (attachment, digest) = encrypt(&key(), &plaintext, 1200);
+        for piece_length in [1, 15, 16, 17, 48, 100, attachment.len()] {
+            assert_eq!(decrypt(&key(), &attachment, &digest, plaintext.len(), piece_length).unwrap(), plaintext, "pieces of {piece_length} bytes");
+        }
+    }
+
+    #[test]
+    fn empty_file() {
+        let (attachment, digest) = encrypt(&key(), &[], 0);
+        assert_eq!(decrypt(&key(), &attachment, &digest, 0, 10).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn tampering() {
+        let plaintext = b"attack at dawn".repeat(10);
+        let (attachment, digest) = encrypt(&key(), &plaintext, 200);
+        for position in [0, 20, attachment.len() - 1] {
+            let mut tampered = attachment.clone();
+            tampered[position] ^= 0x01;
+            // the digest is checked first
+            assert!(decrypt(&key(), &tampered, &digest, plaintext.len(), 64).unwrap_err().contains("digest"), "byte {position}");
+            let tampered_digest = <sha2::Sha256 as sha2::Digest>::digest(&tampered).to_vec();
+            assert!(decrypt(&key(), &tampered, &tampered_digest, plaintext.len(), 64).unwrap_err().contains("MAC"), "byte {position}");
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let plaintext = b"attack at dawn".repeat(10);
+        let (attachment, digest) = encrypt(&key(), &plaintext, 200);
+        assert_eq!(decrypt(&key(), &attachment[..attachment.len() - 20], &digest, plaintext.len(), 64).unwrap_err(), "The attachment is truncated.");
+        assert_eq!(decrypt(&key(), &attachment[..10], &digest, plaintext.len(), 64).unwrap_err(), "The attachment is truncated.");
+        assert_eq!(decrypt(&key(), &attachment, &digest, 300, 64).unwrap_err(), "The attachment is smaller than announced.");
+    }
+
+    #[test]
+    fn truncated_by_whole_blocks() {
+        // the length still looks right, only the integrity checks notice
+        let plaintext = b"attack at dawn".repeat(10);
+        let (attachment, digest) = encrypt(&key(), &plaintext, 200);
+        for missing in [16, 32, 48] {
+            let truncated = &attachment[..attachment.len() - missing];
+            assert!(decrypt(&key(), truncated, &digest, plaintext.len(), 64).unwrap_err().contains("digest"), "{missing} bytes missing");
+            let truncated_digest = <sha2::Sha256 as sha2::Digest>::digest(truncated).to_vec();
+            assert!(decrypt(&key(), truncated, &truncated_digest, plaintext.len(), 64).unwrap_err().contains("MAC"), "{missing} bytes missing");
+        }
+    }
+}
@@ -0,0 +1,296 @@
+/*
+ * Account backups: the store's files in a single archive, encrypted with a passphrase.
+ *
+ * Layout of an archive: magic, version, salt, nonce, encrypted payload.
+ * The payload is a sequence of files, each being the length of the relative path, the path, the length of the contents and the contents.
+ * The encryption is authenticated (with the header as associated data), so a damaged or manipulated archive is rejected as a whole.
+ *
+ * If the store itself is encrypted, the files stay encrypted with the store's passphrase.
+ */
+
+use chacha20poly1305::aead::{Aead, KeyInit}; // for XChaCha20Poly1305::new() and encrypt()/decrypt()
+use rand::RngCore; // for fill_bytes()
+
+const MAGIC: &[u8] = b"PRESAGE-BACKUP";
+const VERSION: u8 = 1;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const KDF_ROUNDS: u32 = 600_000;
+const HEADER_LENGTH: usize = MAGIC.len() + 1 + SALT_LENGTH + NONCE_LENGTH;
+
+fn cipher(
+    passphrase: &str,
+    salt: &[u8],
+) -> chacha20poly1305::XChaCha20Poly1305 {
+    let mut key = chacha20poly1305::Key::default();
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(passphrase.as_bytes(), salt, KDF_ROUNDS, &mut key);
+    chacha20poly1305::XChaCha20Poly1305::new(&key)
+}
+
+/*
+ * Appends all files below `directory` to the payload.
+ */
+fn pack(
+    root: &std::path::Path,
+    directory: &std::path::Path,
+    payload: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            pack(root, &path, payload)?;
+        } else {
+            let relative = path.strip_prefix(root).expect("path must be below root");
+            // always use forward slashes so archives can be moved between operating systems
+            let name = relative.components().map(|component| component.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+            let contents = std::fs::read(&path)?;
+            payload.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            payload.extend_from_slice(name.as_bytes());
+            payload.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            payload.extend_from_slice(&contents);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Removes `length` bytes from the front of `rest`.
+ */
+fn take<'a>(
+    rest: &mut &'a [u8],
+    length: usize,
+) -> Result<&'a [u8], String> {
+    if rest.len() < length {
+        return Err(String::from("The backup is truncated."));
+    }
+    let (taken, remaining) = rest.split_at(length);
+    *rest = remaining;
+    Ok(taken)
+}
+
+/*
+ * Splits the payload into files.
+ *
+ * Rejects paths which would end up outside of the store.
+ */
+fn unpack(payload: &[u8]) -> Result<Vec<(std::path::PathBuf, &[u8])>, String> {
+    let mut files = vec![];
+    let mut rest = payload;
+    while !rest.is_empty() {
+        let name_length = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap()) as usize;
+        let name = std::str::from_utf8(take(&mut rest, name_length)?).map_err(|_| String::from("The backup contains an invalid file name."))?;
+        let path: std::path::PathBuf = name.split('/').collect();
+        // pack never writes empty parts, so a leading slash or a doubled one is as suspicious as ".."
+        if name.split('/').any(|part| part.is_empty() || part == "." || part == "..") || !path.components().all(|component| matches!(component, std::path::Component::Normal(_))) {
+            return Err(format!("The backup contains a file outside of the store: {name}"));
+        }
+        let contents_length = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap()) as usize;
+        files.push((path, take(&mut rest, contents_length)?));
+    }
+    if files.is_empty() {
+        return Err(String::from("The backup is empty."));
+    }
+    Ok(files)
+}
+
+/*
+ * Writes the store's files into an encrypted archive.
+ *
+ * The store must be closed. Unless `history` is set, messages are left out.
+ * For this, the store is copied and the messages are removed from the copy.
+ */
+pub fn export(
+    store_path: &str,
+    store_passphrase: Option<&str>,
+    archive_path: &str,
+    passphrase: &str,
+    history: bool,
+) -> Result<(), String> {
+    if passphrase.is_empty() {
+        return Err(String::from("The passphrase must not be empty."));
+    }
+    let mut source = std::path::PathBuf::from(store_path);
+    let mut copy = None;
+    if !history {
+        let copy_path = std::path::PathBuf::from(format!("{store_path}.export"));
+        let _ = std::fs::remove_dir_all(&copy_path); // left-over from an earlier attempt
+        crate::store::copy_dir(&source, &copy_path).map_err(|err| format!("Unable to copy the store: {err}"))?;
+        copy = Some(copy_path.clone());
+        let cleared = presage_store_sled::SledStore::open_with_passphrase(
+            &copy_path,
+            store_passphrase,
+            presage_store_sled::MigrationConflictStrategy::Raise,
+            presage_store_sled::OnNewIdentity::Trust,
+        )
+        .map_err(|err| format!("Unable to open the copy of the store: {err}"))
+        .and_then(|mut copied_store| presage::store::ContentsStore::clear_messages(&mut copied_store).map_err(|err| format!("Unable to remove the messages: {err}")));
+        if let Err(err) = cleared {
+            let _ = std::fs::remove_dir_all(&copy_path);
+            return Err(err);
+        }
+        source = copy_path;
+    }
+
+    let mut payload = vec![];
+    let packed = pack(&source, &source, &mut payload).map_err(|err| format!("Unable to read the store: {err}"));
+    if let Some(copy_path) = copy {
+        let _ = std::fs::remove_dir_all(copy_path);
+    }
+    packed?;
+
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+    header.extend_from_slice(MAGIC);
+    header.push(VERSION);
+    let mut salt = [0u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+    header.extend_from_slice(&salt);
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    header.extend_from_slice(&nonce);
+    let encrypted = cipher(passphrase, &salt)
+        .encrypt(
+            chacha20poly1305::XNonce::from_slice(&nonce),
+            chacha20poly1305::aead::Payload {
+                msg: &payload,
+                aad: &header,
+            },
+        )
+        .map_err(|err| format!("Unable to encrypt the backup: {err}"))?;
+
+    let mut archive = header;
+    archive.extend_from_slice(&encrypted);
+    std::fs::write(archive_path, archive).map_err(|err| format!("Unable to write the backup to {archive_path}: {err}"))
+}
+
+/*
+ * Replaces the store with the contents of an encrypted archive.
+ *
+ * The store must be closed. The archive is verified completely before the store is touched.
+ */
+pub fn import(
+    store_path: &str,
+    archive_path: &str,
+    passphrase: &str,
+) -> Result<(), String> {
+    let archive = std::fs::read(archive_path).map_err(|err| format!("Unable to read the backup from {archive_path}: {err}"))?;
+    if archive.len() < HEADER_LENGTH || !archive.starts_with(MAGIC) {
+        return Err(String::from("The file is not a backup."));
+    }
+    let (header, encrypted) = archive.split_at(HEADER_LENGTH);
+    if header[MAGIC.len()] != VERSION {
+        return Err(format!("The backup has version {}, but only version {VERSION} is supported.", header[MAGIC.len()]));
+    }
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LENGTH];
+    let nonce = &header[MAGIC.len() + 1 + SALT_LENGTH..];
+    let payload = cipher(passphrase, salt)
+        .decrypt(
+            chacha20poly1305::XNonce::from_slice(nonce),
+            chacha20poly1305::aead::Payload {
+                msg: encrypted,
+                aad: header,
+            },
+        )
+        .map_err(|_| String::from("Unable to decrypt the backup. The passphrase is wrong or the file is damaged."))?;
+    let files = unpack(&payload)?;
+
+    // write to a new directory first so a failure does not leave a half-written store behind
+    let staging_path = std::path::PathBuf::from(format!("{store_path}.import"));
+    let _ = std::fs::remove_dir_all(&staging_path); // left-over from an earlier attempt
+    let written: std::io::Result<()> = files.iter().try_for_each(|(path, contents)| {
+        let target = staging_path.join(path);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(target, contents)
+    });
+    if let Err(err) = written {
+        let _ = std::fs::remove_dir_all(&staging_path);
+        return Err(format!("Unable to write the store: {err}"));
+    }
+    if std::path::Path::new(store_path).exists() {
+        std::fs::remove_dir_all(store_path).map_err(|err| format!("Unable to remove the old store: {err}"))?;
+    }
+    std::fs::rename(&staging_path, store_path).map_err(|err| format!("Unable to move the imported store into place: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    /*
+     * Creates a small store and exports it with all of its contents.
+     */
+    fn export_store(dir: &crate::testing::TestDir) -> String {
+        let store = dir.path("store");
+        std::fs::create_dir_all(format!("{store}/sub")).unwrap();
+        std::fs::write(format!("{store}/conf"), b"configuration").unwrap();
+        std::fs::write(format!("{store}/sub/db"), [0u8, 1, 2, 255]).unwrap();
+        let archive = dir.path("backup.bin");
+        super::export(&store, None, &archive, "correct horse", true).unwrap();
+        archive
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = crate::testing::TestDir::new("round-trip");
+        let archive = export_store(&dir);
+        let restored = dir.path("restored");
+        std::fs::create_dir_all(&restored).unwrap();
+        std::fs::write(format!("{restored}/stale"), b"replaced by the import").unwrap();
+        super::import(&restored, &archive, "correct horse").unwrap();
+        assert_eq!(std::fs::read(format!("{restored}/conf")).unwrap(), b"configuration");
+        assert_eq!(std::fs::read(format!("{restored}/sub/db")).unwrap(), [0u8, 1, 2, 255]);
+        assert!(!std::path::Path::new(&format!("{restored}/stale")).exists());
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let dir = crate::testing::TestDir::new("wrong-passphrase");
+        let archive = export_store(&dir);
+        let restored = dir.path("restored");
+        let err = super::import(&restored, &archive, "battery staple").unwrap_err();
+        assert!(err.starts_with("Unable to decrypt"), "{err}");
+        assert!(!std::path::Path::new(&restored).exists(), "a failed import must not create a store");
+    }
+
+    #[test]
+    fn tampering() {
+        let dir = crate::testing::TestDir::new("tampering");
+        let archive = export_store(&dir);
+        let original = std::fs::read(&archive).unwrap();
+        // a byte of the salt, which is authenticated as part of the header, and the last byte of the ciphertext
+        for position in [super::MAGIC.len() + 1, original.len() - 1] {
+            let mut tampered = original.clone();
+            tampered[position] ^= 0x01;
+            std::fs::write(&archive, tampered).unwrap();
+            let err = super::import(&dir.path("restored"), &archive, "correct horse").unwrap_err();
+            assert!(err.starts_with("Unable to decrypt"), "byte {position}: {err}");
+        }
+        let mut tampered = original.clone();
+        tampered[super::MAGIC.len()] = super::VERSION + 1;
+        std::fs::write(&archive, tampered).unwrap();
+        assert!(super::import(&dir.path("restored"), &archive, "correct horse").unwrap_err().contains("version"));
+    }
+
+    #[test]
+    fn path_traversal() {
+        for name in ["../outside", "sub/../../outside", "/etc/passwd", "sub//db", ""] {
+            let mut payload = vec![];
+            payload.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            payload.extend_from_slice(name.as_bytes());
+            payload.extend_from_slice(&4u64.to_le_bytes());
+            payload.extend_from_slice(b"evil");
+            let err = super::unpack(&payload).unwrap_err();
+            assert!(err.contains("outside of the store"), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let mut payload = vec![];
+        payload.extend_from_slice(&4u32.to_le_bytes());
+        payload.extend_from_slice(b"conf");
+        payload.extend_from_slice(&100u64.to_le_bytes());
+        payload.extend_from_slice(b"short");
+        assert_eq!(super::unpack(&payload).unwrap_err(), "The backup is truncated.");
+    }
+}
@@ -1,68 +1,467 @@
-// TODO: automatically create C header from this declaration
+/*
+ * The kinds of events the back-end sends to the front-end.
+ *
+ * Each kind uses a subset of the fields in `PresageEvent`.
+ * All other fields are null or zero.
+ * `request_id` refers to the command which caused the event. It is zero for events the front-end did not ask for.
+ */
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PresageEventKind {
+    Channel,    // tx_ptr: the channel for sending commands to the back-end
+    Log,        // level (PurpleDebugLevel), body
+    Error,      // level (PurpleConnectionError), body
+    QrCode,     // body: URL for linking, empty string if linking has finished
+    Uuid,       // body: own uuid, empty string if this device is not registered
+    Connected,  // no payload
+    Text,       // request_id, timestamp, flags, who, name, group, title, body
+    Attachment, // timestamp, flags, who, name, group, title, body (file name), attachment, blob (for presage_rust_download_attachment), size
+    XferResult, // request_id, xfer, flags, body (error message)
+    Groups,     // request_id, groups, size
+    Contacts,   // contacts, size
+    Members,    // request_id, groups (exactly one, with members), size
+    Closed,     // tx_ptr: the channel is not used any more and must be released with presage_rust_free_channel
+    PassphraseChanged, // request_id, body (error message, null on success)
+    MigrationConflict, // no payload, answer with presage_rust_resolve_migration_conflict or presage_rust_exit
+    StoreReset,        // body: path of the backup, null if there is none
+    BackupExported,    // request_id, body (error message, null on success)
+    BackupImported,    // request_id, body (error message, null on success)
+    Unlinked,          // request_id, body (error message, null on success)
+    IdentityChanged,   // who: the contact whose identity has changed, flags (1 if accepted already, 0 if it must be accepted), body (digits of the new safety number)
+    IdentityAccepted,  // request_id, who, body (error message, null on success)
+    SafetyNumber,      // request_id, who, body (digits), blob (for a QR code), size
+    AttachmentDownloaded, // request_id, name (path of the file, null on failure), flags (1 if the file is temporary), size (of the file), body (error message, null on success)
+    Typing,               // who, group (null in a direct conversation), flags (1 if started, 0 if stopped)
+    Receipt,              // timestamp (of the message), flags (PresageReceiptKind), who, name, group (null in a direct conversation), title, body (first line of the message, null if it has no text)
+    DownloadProgress,     // request_id, size (of the file written so far)
+}
+
+/*
+ * What a receipt says about a message sent by the user.
+ */
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PresageReceiptKind {
+    Delivered,
+    Read,
+    Viewed,
+}
+
+/*
+ * The result of a call from the front-end into the back-end.
+ *
+ * Details about failures are written to the debug log.
+ */
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PresageStatus {
+    Ok,
+    InvalidArgument, // e.g. a null pointer, a string which is not UTF-8, a malformed uuid or group key
+    NotConnected,    // the runtime or the channel is missing
+    ChannelClosed,   // the back-end has stopped and does not accept commands any more
+    QueueFull,       // the back-end is busy, the command has been dropped and may be tried again later
+    NoThread,        // the back-end could not start a thread for the account
+    Panic,           // the back-end crashed while handling the call
+}
+
+/*
+ * Number of commands which may be waiting for the back-end.
+ *
+ * The front-end does not wait for a free slot. Commands submitted to a full queue are rejected.
+ */
+pub const COMMAND_QUEUE_LENGTH: usize = 32;
+
+/*
+ * Verbosity of the diagnostics if the user did not configure anything else.
+ *
+ * Syntax is described in https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html
+ */
+pub const DEFAULT_LOG_FILTER: &str = "warn,purple_presage_backend=info";
+
+/*
+ * The tokio runtime shared by all accounts, opaque to the front-end.
+ */
+pub type RustRuntime = tokio::runtime::Runtime;
+
+/*
+ * The channel for sending commands to an account, opaque to the front-end.
+ */
+pub type RustChannel = tokio::sync::mpsc::Sender<crate::structs::Cmd>;
+
+/*
+ * A group handed over in an event. Released with presage_rust_free_groups.
+ */
 #[repr(C)]
 pub struct Group {
-    pub key: *const std::os::raw::c_char,
-    pub title: *const std::os::raw::c_char,
-    pub description: *const std::os::raw::c_char,
-    pub revision: std::os::raw::c_uint, //stdint::uint32_t,
-    pub members: *const *const std::os::raw::c_char,
-    pub population: std::os::raw::c_ulonglong, //stdint::uint64_t, // TODO: chose something guaranteed to be compatible with rust usize
+    pub key: *mut std::os::raw::c_char,
+    pub title: *mut std::os::raw::c_char,
+    pub description: *mut std::os::raw::c_char,
+    pub revision: u32,
+    pub members: *mut *mut std::os::raw::c_char, // NULL or as many as population
+    pub population: usize,
+}
+
+/*
+ * A contact handed over in an event. Released with presage_rust_free_contacts.
+ */
+#[repr(C)]
+pub struct Contact {
+    pub uuid: *mut std::os::raw::c_char,
+    pub name: *mut std::os::raw::c_char,
+    pub phone_number: *mut std::os::raw::c_char,
 }
+
+/*
+ * Details about a received attachment. Released with presage_rust_free_attachment.
+ */
 #[repr(C)]
-pub struct Presage {
+pub struct Attachment {
+    pub content_type: *mut std::os::raw::c_char,
+    pub size: u64,   // in bytes, zero if unknown
+    pub width: u32,  // in pixels, zero if unknown
+    pub height: u32, // in pixels, zero if unknown
+    pub voice_note: bool,
+    pub thumbnail: *mut std::os::raw::c_uchar, // preview image, NULL if there is none
+    pub thumbnail_size: usize,
+}
+
+#[repr(C)]
+pub struct PresageEvent {
+    pub kind: PresageEventKind,
+    pub level: i32,
     pub account: *const std::os::raw::c_void,
     pub tx_ptr: *mut std::os::raw::c_void,
-    pub qrcode: *const std::os::raw::c_char,
-    pub uuid: *const std::os::raw::c_char,
-    pub debug: std::os::raw::c_int,
-    pub error: std::os::raw::c_int,
-    pub connected: std::os::raw::c_int,
-    pub padding: std::os::raw::c_int,
-    // TODO: find out how to use stdint on Windows
-    pub timestamp: std::os::raw::c_ulonglong, //stdint::uint64_t,
-    pub flags: std::os::raw::c_ulonglong,     //stdint::uint64_t,
-    pub who: *const std::os::raw::c_char,
-    pub name: *const std::os::raw::c_char,
-    pub group: *const std::os::raw::c_char,
-    pub title: *const std::os::raw::c_char,
-    pub body: *const std::os::raw::c_char,
-    pub blob: *const std::os::raw::c_uchar,
-    pub size: std::os::raw::c_ulonglong, //stdint::uint64_t, // TODO: chose something guaranteed to be compatible with rust usize
-    pub groups: *const Group,
-    pub roomlist: *const std::os::raw::c_void,
+    pub request_id: u64,
+    pub timestamp: u64,
+    pub flags: u64,
+    pub who: *mut std::os::raw::c_char,
+    pub name: *mut std::os::raw::c_char,
+    pub group: *mut std::os::raw::c_char,
+    pub title: *mut std::os::raw::c_char,
+    pub body: *mut std::os::raw::c_char,
+    pub blob: *mut std::os::raw::c_uchar,
+    pub size: usize,
+    pub groups: *mut Group,
+    pub contacts: *mut Contact,
+    pub attachment: *mut Attachment,
     pub xfer: *const std::os::raw::c_void,
 }
 
-impl Presage {
-    pub fn from_account(account: *const std::os::raw::c_void) -> Self {
+impl PresageEvent {
+    pub fn new(
+        account: *const std::os::raw::c_void,
+        kind: PresageEventKind,
+    ) -> Self {
         Self {
+            kind: kind,
+            level: 0,
             account: account,
             tx_ptr: std::ptr::null_mut(),
-            qrcode: std::ptr::null(),
-            uuid: std::ptr::null(),
-            debug: -1,
-            error: -1,
-            connected: -1,
-            padding: -1,
+            request_id: 0,
             timestamp: 0,
             flags: 0,
-            who: std::ptr::null(),
-            name: std::ptr::null(),
-            group: std::ptr::null(),
-            title: std::ptr::null(),
-            body: std::ptr::null(),
-            blob: std::ptr::null(),
+            who: std::ptr::null_mut(),
+            name: std::ptr::null_mut(),
+            group: std::ptr::null_mut(),
+            title: std::ptr::null_mut(),
+            body: std::ptr::null_mut(),
+            blob: std::ptr::null_mut(),
             size: 0,
-            groups: std::ptr::null(),
-            roomlist: std::ptr::null(),
+            groups: std::ptr::null_mut(),
+            contacts: std::ptr::null_mut(),
+            attachment: std::ptr::null_mut(),
             xfer: std::ptr::null(),
         }
     }
 }
 
+/*
+ * Hands a string over to the front-end.
+ *
+ * C strings cannot contain NUL bytes. A message from a contact may contain them, though. They are dropped.
+ */
+fn into_c_string(s: String) -> *mut std::os::raw::c_char {
+    let c_string = std::ffi::CString::new(s).unwrap_or_else(|err| {
+        let mut bytes = err.into_vec();
+        bytes.retain(|&byte| byte != 0);
+        std::ffi::CString::new(bytes).expect("all NUL bytes have been removed")
+    });
+    c_string.into_raw()
+}
+
+fn into_c_string_or_null(s: Option<String>) -> *mut std::os::raw::c_char {
+    s.map(into_c_string).unwrap_or(std::ptr::null_mut())
+}
+
+fn into_c_group(group: crate::structs::Group) -> Group {
+    let members: Vec<*mut std::os::raw::c_char> = group.members.into_iter().map(into_c_string).collect();
+    debug_assert!(members.is_empty() || members.len() == group.population, "presage_rust_free_groups relies on the population");
+    Group {
+        key: into_c_string(group.key),
+        title: into_c_string(group.title),
+        description: into_c_string(group.description),
+        revision: group.revision,
+        population: group.population,
+        members: if members.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            Box::into_raw(members.into_boxed_slice()) as *mut *mut std::os::raw::c_char
+        },
+    }
+}
+
+/*
+ * Forwards events to libpurple by converting them into the C representation.
+ *
+ * Connection errors often carry a debug dump of the underlying error. purple writes them to the debug log, too.
+ * Hence personal data is removed from them as it is from any other diagnostics, unless the user opted out.
+ */
+pub struct PurpleSink {
+    account: *const std::os::raw::c_void,
+    redact: bool,
+}
+
+impl PurpleSink {
+    pub fn new(
+        account: *const std::os::raw::c_void,
+        redact: bool,
+    ) -> Self {
+        Self {
+            account: account,
+            redact: redact,
+        }
+    }
+}
+
+impl crate::sink::EventSink for PurpleSink {
+    fn append(
+        &self,
+        event: crate::structs::Event,
+    ) {
+        let message = match event {
+            crate::structs::Event::Log { level, message } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Log);
+                event.level = level;
+                event.body = into_c_string(message);
+                event
+            }
+            crate::structs::Event::Error { request_id, level, message } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Error);
+                event.request_id = request_id.unwrap_or(0);
+                event.level = level;
+                event.body = into_c_string(if self.redact { crate::redact::redact(&message) } else { message });
+                event
+            }
+            crate::structs::Event::QrCode(url) => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::QrCode);
+                event.body = into_c_string(url);
+                event
+            }
+            crate::structs::Event::Uuid(uuid) => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Uuid);
+                event.body = into_c_string(uuid);
+                event
+            }
+            crate::structs::Event::Connected => PresageEvent::new(self.account, PresageEventKind::Connected),
+            crate::structs::Event::Text(message) => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Text);
+                event.request_id = message.request_id;
+                event.timestamp = message.timestamp;
+                event.flags = message.flags;
+                event.who = into_c_string_or_null(message.who);
+                event.name = into_c_string_or_null(message.name);
+                event.group = into_c_string_or_null(message.group);
+                event.title = into_c_string_or_null(message.title);
+                event.body = into_c_string(message.body);
+                event
+            }
+            crate::structs::Event::Attachment(attachment) => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Attachment);
+                event.timestamp = attachment.timestamp;
+                event.flags = attachment.flags;
+                event.who = into_c_string_or_null(attachment.who);
+                event.name = into_c_string_or_null(attachment.name);
+                event.group = into_c_string_or_null(attachment.group);
+                event.title = into_c_string_or_null(attachment.title);
+                event.body = into_c_string(attachment.filename);
+                event.attachment = Box::into_raw(Box::new(Attachment {
+                    content_type: into_c_string(attachment.content_type),
+                    size: attachment.size,
+                    width: attachment.width,
+                    height: attachment.height,
+                    voice_note: attachment.voice_note,
+                    thumbnail_size: attachment.thumbnail.len(),
+                    thumbnail: if attachment.thumbnail.is_empty() {
+                        std::ptr::null_mut()
+                    } else {
+                        Box::into_raw(attachment.thumbnail.into_boxed_slice()) as *mut std::os::raw::c_uchar
+                    },
+                }));
+                let boxed_slice = attachment.pointer.into_boxed_slice();
+                event.size = boxed_slice.len();
+                event.blob = Box::into_raw(boxed_slice) as *mut std::os::raw::c_uchar;
+                event
+            }
+            crate::structs::Event::XferResult {
+                request_id,
+                xfer,
+                flags,
+                error,
+            } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::XferResult);
+                event.request_id = request_id;
+                event.xfer = xfer;
+                event.flags = flags;
+                event.body = into_c_string_or_null(error);
+                event
+            }
+            crate::structs::Event::Groups { request_id, groups } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Groups);
+                event.request_id = request_id;
+                let groups: Vec<Group> = groups.into_iter().map(into_c_group).collect();
+                event.size = groups.len();
+                event.groups = Box::into_raw(groups.into_boxed_slice()) as *mut Group;
+                event
+            }
+            crate::structs::Event::Contacts(contacts) => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Contacts);
+                let contacts: Vec<Contact> = contacts
+                    .into_iter()
+                    .map(|contact| Contact {
+                        uuid: into_c_string(contact.uuid),
+                        name: into_c_string_or_null(contact.name),
+                        phone_number: into_c_string_or_null(contact.phone_number),
+                    })
+                    .collect();
+                event.size = contacts.len();
+                event.contacts = Box::into_raw(contacts.into_boxed_slice()) as *mut Contact;
+                event
+            }
+            crate::structs::Event::Members { request_id, group } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Members);
+                event.request_id = request_id;
+                event.size = 1;
+                event.groups = Box::into_raw(vec![into_c_group(group)].into_boxed_slice()) as *mut Group;
+                event
+            }
+            crate::structs::Event::PassphraseChanged { request_id, error } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::PassphraseChanged);
+                event.request_id = request_id;
+                event.body = into_c_string_or_null(error);
+                event
+            }
+            crate::structs::Event::MigrationConflict => PresageEvent::new(self.account, PresageEventKind::MigrationConflict),
+            crate::structs::Event::StoreReset { backup_path } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::StoreReset);
+                event.body = into_c_string_or_null(backup_path);
+                event
+            }
+            crate::structs::Event::BackupExported { request_id, error } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::BackupExported);
+                event.request_id = request_id;
+                event.body = into_c_string_or_null(error);
+                event
+            }
+            crate::structs::Event::BackupImported { request_id, error } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::BackupImported);
+                event.request_id = request_id;
+                event.body = into_c_string_or_null(error);
+                event
+            }
+            crate::structs::Event::Unlinked { request_id, error } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Unlinked);
+                event.request_id = request_id;
+                event.body = into_c_string_or_null(error);
+                event
+            }
+            crate::structs::Event::IdentityChanged { who, accepted, digits } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::IdentityChanged);
+                event.who = into_c_string(who);
+                event.flags = accepted as u64;
+                event.body = into_c_string(digits);
+                event
+            }
+            crate::structs::Event::IdentityAccepted { request_id, who, error } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::IdentityAccepted);
+                event.request_id = request_id;
+                event.who = into_c_string(who);
+                event.body = into_c_string_or_null(error);
+                event
+            }
+            crate::structs::Event::SafetyNumber {
+                request_id,
+                who,
+                digits,
+                scannable,
+            } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::SafetyNumber);
+                event.request_id = request_id;
+                event.who = into_c_string(who);
+                event.body = into_c_string(digits);
+                let boxed_slice = scannable.into_boxed_slice();
+                event.size = boxed_slice.len();
+                event.blob = Box::into_raw(boxed_slice) as *mut std::os::raw::c_uchar;
+                event
+            }
+            crate::structs::Event::DownloadProgress { request_id, size } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::DownloadProgress);
+                event.request_id = request_id;
+                event.size = size;
+                event
+            }
+            crate::structs::Event::AttachmentDownloaded {
+                request_id,
+                path,
+                temporary,
+                size,
+                error,
+            } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::AttachmentDownloaded);
+                event.request_id = request_id;
+                event.name = into_c_string_or_null(path);
+                event.flags = temporary as u64;
+                event.size = size;
+                event.body = into_c_string_or_null(error);
+                event
+            }
+            crate::structs::Event::Typing { who, group, started } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Typing);
+                event.who = into_c_string(who);
+                event.group = into_c_string_or_null(group);
+                event.flags = started as u64;
+                event
+            }
+            crate::structs::Event::Receipt {
+                kind,
+                timestamp,
+                who,
+                name,
+                group,
+                title,
+                body,
+            } => {
+                let mut event = PresageEvent::new(self.account, PresageEventKind::Receipt);
+                event.timestamp = timestamp;
+                event.flags = match kind {
+                    crate::structs::ReceiptKind::Delivered => PresageReceiptKind::Delivered,
+                    crate::structs::ReceiptKind::Read => PresageReceiptKind::Read,
+                    crate::structs::ReceiptKind::Viewed => PresageReceiptKind::Viewed,
+                } as u64;
+                event.who = into_c_string(who);
+                event.name = into_c_string_or_null(name);
+                event.group = into_c_string_or_null(group);
+                event.title = into_c_string_or_null(title);
+                event.body = into_c_string_or_null(body);
+                event
+            }
+        };
+        append_message(&message);
+    }
+}
+
 extern "C" {
     // this is implemented by bridge.c
-    fn presage_append_message(message: *const Presage);
+    fn presage_append_message(message: *const PresageEvent);
 
     // this is implemented by libpurple's ft.c
     // TODO: automatically generate declaration from ft.h
@@ -70,16 +469,84 @@ extern "C" {
 }
 
 // wrapper around unsafe presage_append_message
-pub fn append_message(message: *const Presage) {
+pub fn append_message(message: *const PresageEvent) {
     unsafe {
         presage_append_message(message);
     }
 }
 
 // wrapper around unsafe purple_xfer_get_local_filename
-pub fn xfer_get_local_filename(xfer: *const std::os::raw::c_void) -> String {
-    unsafe {
-        return std::ffi::CStr::from_ptr(purple_xfer_get_local_filename(xfer)).to_str().unwrap().to_owned();
+pub fn xfer_get_local_filename(xfer: *const std::os::raw::c_void) -> Result<String, PresageStatus> {
+    unsafe { from_c_str(purple_xfer_get_local_filename(xfer), "xfer local filename") }
+}
+
+/*
+ * Writes a message to the debug log.
+ *
+ * This does not need an account since the front-end handles log messages without one.
+ */
+pub fn log(
+    level: i32,
+    message: String,
+) {
+    // the message has been redacted by the caller if needed
+    crate::sink::EventSink::debug(&PurpleSink::new(std::ptr::null(), false), level, message);
+}
+
+/*
+ * Writes a message about a failed call to the debug log.
+ *
+ * The message may contain arguments supplied by the front-end. They are redacted
+ * since there is no account (and no account setting) to consult.
+ */
+pub fn log_failure(message: String) {
+    log(4, crate::redact::redact(&message));
+}
+
+/*
+ * Copies a string handed over by the front-end.
+ *
+ * Rejects null pointers and strings which are not valid UTF-8.
+ */
+pub unsafe fn from_c_str(
+    c_str: *const std::os::raw::c_char,
+    what: &str,
+) -> Result<String, PresageStatus> {
+    if c_str.is_null() {
+        log_failure(format!("{what} must not be null.\n"));
+        return Err(PresageStatus::InvalidArgument);
+    }
+    match std::ffi::CStr::from_ptr(c_str).to_str() {
+        Ok(s) => Ok(s.to_owned()),
+        Err(err) => {
+            log_failure(format!("{what} is not valid UTF-8: {err}\n"));
+            Err(PresageStatus::InvalidArgument)
+        }
+    }
+}
+
+/*
+ * Runs the body of a function called by the front-end.
+ *
+ * A panic must not unwind into C since that aborts the entire application.
+ * It is caught here and turned into a status code instead.
+ */
+pub fn catch_unwind<F: FnOnce() -> Result<(), PresageStatus>>(
+    function: &str,
+    f: F,
+) -> PresageStatus {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(())) => PresageStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(panic) => {
+            let reason = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| String::from("unknown reason"));
+            log_failure(format!("{function} panicked: {reason}\n"));
+            PresageStatus::Panic
+        }
     }
 }
 
@@ -90,17 +557,31 @@ pub fn xfer_get_local_filename(xfer: *const std::os::raw::c_void) -> String {
  * https://stackoverflow.com/questions/66196972/ and https://stackoverflow.com/questions/64658556/ are helpful.
  */
 #[no_mangle]
-pub extern "C" fn presage_rust_init() -> *mut tokio::runtime::Runtime {
-    let runtime = tokio::runtime::Builder::new_multi_thread().thread_name("presage Tokio").enable_io().enable_time().build().unwrap();
-    let runtime_box = Box::new(runtime);
-    Box::into_raw(runtime_box)
+pub extern "C" fn presage_rust_init() -> *mut RustRuntime {
+    let mut runtime_ptr = std::ptr::null_mut();
+    catch_unwind("presage_rust_init", || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().thread_name("presage Tokio").enable_io().enable_time().build().map_err(|err| {
+            log_failure(format!("Unable to create tokio runtime: {err}\n"));
+            PresageStatus::Panic
+        })?;
+        let runtime_box = Box::new(runtime);
+        runtime_ptr = Box::into_raw(runtime_box);
+        Ok(())
+    });
+    runtime_ptr // null in case of failure
 }
 
 #[no_mangle]
-pub extern "C" fn presage_rust_destroy(runtime: *mut tokio::runtime::Runtime) {
-    unsafe {
-        drop(Box::from_raw(runtime));
-    }
+pub extern "C" fn presage_rust_destroy(runtime: *mut RustRuntime) -> PresageStatus {
+    catch_unwind("presage_rust_destroy", || {
+        if runtime.is_null() {
+            return Err(PresageStatus::InvalidArgument);
+        }
+        unsafe {
+            drop(Box::from_raw(runtime));
+        }
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -112,27 +593,66 @@ pub extern "C" fn presage_rust_free_string(c_str: *mut std::os::raw::c_char) {
     }
 }
 
-// TODO: types should be aligned with Presage::blob and Presage::blobsize respectively
 #[no_mangle]
 pub extern "C" fn presage_rust_free_buffer(
     c_buf: *mut std::os::raw::c_uchar,
-    len: std::os::raw::c_ulonglong, // this should be the C equivalent of usize
+    len: usize,
 ) {
     if !c_buf.is_null() {
         unsafe {
-            drop(Box::from_raw(std::slice::from_raw_parts_mut(c_buf, len as usize)));
+            drop(Box::from_raw(std::slice::from_raw_parts_mut(c_buf, len)));
         };
     }
 }
 
+#[no_mangle]
+pub extern "C" fn presage_rust_free_attachment(c_attachment: *mut Attachment) {
+    if !c_attachment.is_null() {
+        let attachment = unsafe { Box::from_raw(c_attachment) };
+        presage_rust_free_string(attachment.content_type);
+        presage_rust_free_buffer(attachment.thumbnail, attachment.thumbnail_size);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn presage_rust_free_groups(
+    c_groups: *mut Group,
+    len: usize,
+) {
+    if !c_groups.is_null() {
+        let groups = unsafe { Box::from_raw(std::slice::from_raw_parts_mut(c_groups, len)) };
+        for group in groups.iter() {
+            presage_rust_free_string(group.key);
+            presage_rust_free_string(group.title);
+            presage_rust_free_string(group.description);
+            presage_rust_strfreev(group.members, group.population);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn presage_rust_free_contacts(
+    c_contacts: *mut Contact,
+    len: usize,
+) {
+    if !c_contacts.is_null() {
+        let contacts = unsafe { Box::from_raw(std::slice::from_raw_parts_mut(c_contacts, len)) };
+        for contact in contacts.iter() {
+            presage_rust_free_string(contact.uuid);
+            presage_rust_free_string(contact.name);
+            presage_rust_free_string(contact.phone_number);
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn presage_rust_strfreev(
     c_arr_of_str: *mut *mut std::os::raw::c_char,
-    len: std::os::raw::c_ulonglong, // this should be the C equivalent of usize
+    len: usize,
 ) {
     if !c_arr_of_str.is_null() {
         unsafe {
-            let slice = std::slice::from_raw_parts_mut(c_arr_of_str, len as usize);
+            let slice = std::slice::from_raw_parts_mut(c_arr_of_str, len);
             for c_str in &mut *slice {
                 presage_rust_free_string(*c_str);
             }
@@ -141,32 +661,295 @@ pub extern "C" fn presage_rust_strfreev(
     }
 }
 
+#[no_mangle]
+pub extern "C" fn presage_rust_free_channel(tx: *mut RustChannel) {
+    if !tx.is_null() {
+        unsafe {
+            drop(Box::from_raw(tx));
+        }
+    }
+}
+
 /*
- * Around the core's main function.
+ * Gets the lock guarding the store at the given path.
+ *
+ * A store can be opened only once at a time. When an account is re-enabled,
+ * the new instance must wait for the old one to finish shutting down.
+ */
+fn store_lock(store_path: &str) -> std::sync::Arc<std::sync::Mutex<()>> {
+    static STORE_LOCKS: std::sync::OnceLock<std::sync::Mutex<std::collections::HashMap<String, std::sync::Arc<std::sync::Mutex<()>>>>> = std::sync::OnceLock::new();
+    let mut locks = STORE_LOCKS.get_or_init(Default::default).lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    locks.entry(store_path.to_owned()).or_default().clone()
+}
+
+/*
+ * The front-end's account.
+ *
+ * The back-end never dereferences it. It is only handed back in events,
+ * so it may be passed on to the account's thread.
+ */
+struct AccountPtr(*const std::os::raw::c_void);
+unsafe impl Send for AccountPtr {}
+
+/*
+ * Runs one account in the shared runtime.
  *
  * According to https://docs.rs/tokio/latest/tokio/task/struct.LocalSet.html,
  * the top call must be blocking. So this blocks until the main function finishes.
+ * All of the account's tasks live in its own LocalSet, so accounts do not share any state.
+ * The manager and the store cannot be moved between threads, so the LocalSet is driven by a thread of the account's own
+ * (see `presage_rust_start`). The runtime's workers only do the I/O. An account which is stuck does not hold up the others.
+ *
+ * Should the back-end panic, the connection is closed with an error instead of taking down the front-end.
+ * In any case, the front-end is told when the channel is not used any more.
+ *
+ * Diagnostics are filtered and redacted according to the account's settings. They apply to this thread only.
  */
-#[no_mangle]
-pub unsafe extern "C" fn presage_rust_main(
-    rt: *mut tokio::runtime::Runtime,
+fn run_account(
+    runtime: tokio::runtime::Handle,
     account: *const std::os::raw::c_void,
-    c_store_path: *const std::os::raw::c_char,
-) {
-    let store_path = std::ffi::CStr::from_ptr(c_store_path).to_str().unwrap().to_owned();
+    store_path: String,
+    passphrase: Option<String>,
+    trust_new_identities: bool,
+    log_filter: String,
+    redact: bool,
+) -> PresageStatus {
+    let _tracing_guard = tracing::subscriber::set_default(crate::bridge_logging::subscriber(&log_filter, redact));
+    let sink: crate::sink::Sink = std::rc::Rc::new(PurpleSink::new(account, redact));
+
+    // wait for a previous instance using the same store to finish
+    let store_lock = store_lock(&store_path);
+    let store_guard = match store_lock.try_lock() {
+        Ok(guard) => guard,
+        Err(std::sync::TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(std::sync::TryLockError::WouldBlock) => {
+            tracing::warn!("store {store_path} is still in use, waiting for it to be released…");
+            store_lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+        }
+    };
 
     // create a channel for asynchronous communication of commands c → rust
-    let (tx, rx) = tokio::sync::mpsc::channel(32);
+    let (tx, rx) = tokio::sync::mpsc::channel(COMMAND_QUEUE_LENGTH);
     let tx_ptr = Box::into_raw(Box::new(tx));
-    let mut message = Presage::from_account(account);
+    let mut message = PresageEvent::new(account, PresageEventKind::Channel);
     message.tx_ptr = tx_ptr as *mut std::os::raw::c_void;
     append_message(&message); // let front-end know how to reach us
 
     // now execute the actual program
-    let runtime = rt.as_ref().unwrap();
-    runtime.block_on(async {
-        let local = tokio::task::LocalSet::new();
-        local.run_until(crate::core::main(store_path, None, rx, account)).await;
+    let status = catch_unwind("run_account", || {
+        runtime.block_on(async {
+            let local = tokio::task::LocalSet::new();
+            local.run_until(crate::core::main(store_path, passphrase, trust_new_identities, rx, sink.clone())).await;
+            // dropping the LocalSet drops all tasks which might still be around
+        });
+        Ok(())
     });
-    crate::core::purple_debug(account, 2, String::from("runtime finished.\n"));
+    if status == PresageStatus::Panic {
+        sink.error(16, String::from("The back-end has crashed. Please see the debug log for details."));
+    }
+
+    // let the front-end know it must not use the channel any more
+    let mut message = PresageEvent::new(account, PresageEventKind::Closed);
+    message.tx_ptr = tx_ptr as *mut std::os::raw::c_void;
+    append_message(&message);
+    tracing::info!("account finished with status {status:?}.");
+    drop(store_guard); // only now a new instance may open the store
+    status
+}
+
+/*
+ * Starts the back-end for an account.
+ *
+ * The account runs in a thread of its own. This returns immediately.
+ * The front-end learns about the channel for sending commands via a `PresageEventKind::Channel` event.
+ *
+ * The passphrase may be null for an unencrypted store.
+ * Unless trust_new_identities is true, contacts with a new identity must be accepted before sending to them.
+ * The log filter may be null or empty for using the default.
+ * Personal data is removed from the debug log unless redact is false.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_start(
+    rt: *mut RustRuntime,
+    account: *const std::os::raw::c_void,
+    c_store_path: *const std::os::raw::c_char,
+    c_passphrase: *const std::os::raw::c_char,
+    trust_new_identities: bool,
+    c_log_filter: *const std::os::raw::c_char,
+    redact: bool,
+) -> PresageStatus {
+    catch_unwind("presage_rust_start", || {
+        let store_path = from_c_str(c_store_path, "store path")?;
+        let mut passphrase = None;
+        if !c_passphrase.is_null() {
+            passphrase = Some(from_c_str(c_passphrase, "passphrase")?);
+        }
+        let mut log_filter = String::from(DEFAULT_LOG_FILTER);
+        if !c_log_filter.is_null() {
+            let configured = from_c_str(c_log_filter, "log filter")?;
+            if !configured.is_empty() {
+                log_filter = configured;
+            }
+        }
+        let runtime = rt.as_ref().ok_or(PresageStatus::NotConnected)?.handle().clone();
+        let account = AccountPtr(account);
+        std::thread::Builder::new()
+            .name(String::from("presage account"))
+            .spawn(move || {
+                let account = account; // capture the wrapper, not the raw pointer inside
+                run_account(runtime, account.0, store_path, passphrase, trust_new_identities, log_filter, redact)
+            })
+            .map(|_| ()) // the thread is detached, it finishes on its own
+            .map_err(|err| {
+                log_failure(format!("Unable to start thread for account: {err}\n"));
+                PresageStatus::NoThread
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    /*
+     * What the front-end has been told, by account.
+     */
+    #[derive(Debug, Clone)]
+    struct RecordedEvent {
+        account: usize,
+        kind: super::PresageEventKind,
+        request_id: u64,
+        tx_ptr: usize,
+    }
+
+    static EVENTS: std::sync::Mutex<Vec<RecordedEvent>> = std::sync::Mutex::new(vec![]);
+
+    // the account whose events are not taken, so the account is stuck in the middle of a command
+    static STALLED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    // stands in for bridge.c
+    #[no_mangle]
+    extern "C" fn presage_append_message(message: *const super::PresageEvent) {
+        let message = unsafe { &*message };
+        let account = message.account as usize;
+        EVENTS.lock().unwrap().push(RecordedEvent {
+            account: account,
+            kind: message.kind,
+            request_id: message.request_id,
+            tx_ptr: message.tx_ptr as usize,
+        });
+        while STALLED.load(std::sync::atomic::Ordering::SeqCst) == account {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    // stands in for libpurple's ft.c
+    #[no_mangle]
+    extern "C" fn purple_xfer_get_local_filename(_xfer: *const std::os::raw::c_void) -> *const std::os::raw::c_char {
+        std::ptr::null()
+    }
+
+    /*
+     * Waits for an account's event of the given kind.
+     */
+    fn wait_for(
+        account: usize,
+        kind: super::PresageEventKind,
+    ) -> RecordedEvent {
+        for _ in 0..3000 {
+            if let Some(event) = EVENTS.lock().unwrap().iter().find(|event| event.account == account && event.kind == kind) {
+                return event.clone();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("account {account} did not receive a {kind:?} event");
+    }
+
+    #[test]
+    fn store_lock_per_path() {
+        let lock = super::store_lock("/tmp/presage-test-lock-a");
+        assert!(std::sync::Arc::ptr_eq(&lock, &super::store_lock("/tmp/presage-test-lock-a")));
+        assert!(!std::sync::Arc::ptr_eq(&lock, &super::store_lock("/tmp/presage-test-lock-b")));
+    }
+
+    #[test]
+    fn accounts_are_separate() {
+        let dir = crate::testing::TestDir::new("bridge-accounts");
+        let runtime = super::presage_rust_init();
+        let accounts = [0x1001usize, 0x1002];
+        for account in accounts {
+            let store_path = std::ffi::CString::new(dir.path(&format!("store-{account}"))).unwrap();
+            let status = unsafe { super::presage_rust_start(runtime, account as *const std::os::raw::c_void, store_path.as_ptr(), std::ptr::null(), true, std::ptr::null(), true) };
+            assert_eq!(status, super::PresageStatus::Ok);
+        }
+        // both accounts run at the same time, one store's lock does not hold up the other account
+        let channels = accounts.map(|account| wait_for(account, super::PresageEventKind::Channel).tx_ptr as *mut super::RustChannel);
+        let uuid = std::ffi::CString::new("00000000-0000-0000-0000-000000000001").unwrap();
+        for (account, tx) in accounts.iter().zip(channels) {
+            unsafe {
+                assert_eq!(crate::bridge_commands::presage_rust_whoami(tx), super::PresageStatus::Ok);
+                // the account is used as request id, so the events tell which account handled the request
+                assert_eq!(crate::bridge_commands::presage_rust_accept_identity(tx, *account as u64, uuid.as_ptr()), super::PresageStatus::Ok);
+            }
+        }
+        for account in accounts {
+            wait_for(account, super::PresageEventKind::IdentityAccepted);
+        }
+        for (account, tx) in accounts.iter().zip(channels) {
+            unsafe {
+                assert_eq!(crate::bridge_commands::presage_rust_exit(tx), super::PresageStatus::Ok);
+            }
+            wait_for(*account, super::PresageEventKind::Closed);
+            super::presage_rust_free_channel(tx);
+        }
+
+        let events = EVENTS.lock().unwrap().clone();
+        for account in accounts {
+            let own: Vec<&RecordedEvent> = events.iter().filter(|event| event.account == account).collect();
+            assert_eq!(own.iter().filter(|event| event.kind == super::PresageEventKind::Uuid).count(), 1, "{own:?}");
+            let accepted: Vec<u64> = own.iter().filter(|event| event.kind == super::PresageEventKind::IdentityAccepted).map(|event| event.request_id).collect();
+            assert_eq!(accepted, vec![account as u64], "{own:?}");
+        }
+        assert_eq!(super::presage_rust_destroy(runtime), super::PresageStatus::Ok);
+    }
+
+    #[test]
+    fn stalled_account_does_not_delay_others() {
+        let dir = crate::testing::TestDir::new("bridge-stalled");
+        let runtime = super::presage_rust_init();
+        let (stalled, other) = (0x2001usize, 0x2002usize);
+        for account in [stalled, other] {
+            let store_path = std::ffi::CString::new(dir.path(&format!("store-{account}"))).unwrap();
+            let status = unsafe { super::presage_rust_start(runtime, account as *const std::os::raw::c_void, store_path.as_ptr(), std::ptr::null(), true, std::ptr::null(), true) };
+            assert_eq!(status, super::PresageStatus::Ok);
+        }
+        let stalled_tx = wait_for(stalled, super::PresageEventKind::Channel).tx_ptr as *mut super::RustChannel;
+        let other_tx = wait_for(other, super::PresageEventKind::Channel).tx_ptr as *mut super::RustChannel;
+        let uuid = std::ffi::CString::new("00000000-0000-0000-0000-000000000001").unwrap();
+
+        // the first account gets stuck while handing over the result of whoami
+        STALLED.store(stalled, std::sync::atomic::Ordering::SeqCst);
+        unsafe {
+            assert_eq!(crate::bridge_commands::presage_rust_whoami(stalled_tx), super::PresageStatus::Ok);
+            assert_eq!(crate::bridge_commands::presage_rust_accept_identity(stalled_tx, 1, uuid.as_ptr()), super::PresageStatus::Ok);
+        }
+        wait_for(stalled, super::PresageEventKind::Uuid);
+        // the other account is served nevertheless
+        unsafe {
+            assert_eq!(crate::bridge_commands::presage_rust_whoami(other_tx), super::PresageStatus::Ok);
+            assert_eq!(crate::bridge_commands::presage_rust_accept_identity(other_tx, 2, uuid.as_ptr()), super::PresageStatus::Ok);
+        }
+        wait_for(other, super::PresageEventKind::IdentityAccepted);
+        assert!(!EVENTS.lock().unwrap().iter().any(|event| event.account == stalled && event.kind == super::PresageEventKind::IdentityAccepted));
+
+        // once released, the first account carries on
+        STALLED.store(0, std::sync::atomic::Ordering::SeqCst);
+        wait_for(stalled, super::PresageEventKind::IdentityAccepted);
+        for (account, tx) in [(stalled, stalled_tx), (other, other_tx)] {
+            unsafe {
+                assert_eq!(crate::bridge_commands::presage_rust_exit(tx), super::PresageStatus::Ok);
+            }
+            wait_for(account, super::PresageEventKind::Closed);
+            super::presage_rust_free_channel(tx);
+        }
+        assert_eq!(super::presage_rust_destroy(runtime), super::PresageStatus::Ok);
+    }
 }
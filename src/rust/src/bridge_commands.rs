@@ -1,157 +1,445 @@
 /*
  * Feeds a command into the channel c → rust.
+ *
+ * This is called from within the front-end's main thread and must not block.
+ * If the back-end is busy and the queue is full, the command is rejected.
  */
 unsafe fn send_cmd(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
+    tx: *mut crate::bridge::RustChannel,
     cmd: crate::structs::Cmd,
-) {
-    let command_tx = tx.as_ref().unwrap();
-    let runtime = rt.as_ref().unwrap();
-    match runtime.block_on(command_tx.send(cmd)) {
+) -> Result<(), crate::bridge::PresageStatus> {
+    let command_tx = tx.as_ref().ok_or(crate::bridge::PresageStatus::NotConnected)?;
+    match command_tx.try_send(cmd) {
         Ok(()) => {
-            //println!("rust: command_tx.send OK");
+            let depth = command_tx.max_capacity() - command_tx.capacity();
+            crate::bridge::log(1, format!("command queued, queue depth is {depth}/{}.\n", command_tx.max_capacity()));
+            Ok(())
         }
-        Err(err) => {
-            println!("rust: command_tx.send {err}");
+        Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+            crate::bridge::log_failure(format!("command queue is full ({} commands waiting), dropping command.\n", command_tx.max_capacity()));
+            Err(crate::bridge::PresageStatus::QueueFull)
+        }
+        Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => {
+            crate::bridge::log_failure(String::from("command queue is closed, dropping command.\n"));
+            Err(crate::bridge::PresageStatus::ChannelClosed)
         }
     }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn presage_rust_link(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
-    c_device_name: *const std::os::raw::c_char,
-) {
-    let device_name: String = std::ffi::CStr::from_ptr(c_device_name).to_str().unwrap().to_owned();
-    let server = presage::libsignal_service::configuration::SignalServers::Production;
-    //let server = presage::libsignal_service::configuration::SignalServers::Staging;
-    let cmd = crate::structs::Cmd::LinkDevice {
-        device_name: device_name,
-        servers: server,
-    };
-    send_cmd(rt, tx, cmd);
+/*
+ * Copies the optional message text handed over by the front-end.
+ */
+unsafe fn optional_message(c_message: *const std::os::raw::c_char) -> Result<Option<String>, crate::bridge::PresageStatus> {
+    if c_message.is_null() {
+        Ok(None)
+    } else {
+        crate::bridge::from_c_str(c_message, "message").map(Some)
+    }
+}
+
+/*
+ * Resolves the name of the file to send.
+ *
+ * This is done here since it is called from within the front-end's thread.
+ */
+fn optional_attachment(xfer: *const std::os::raw::c_void) -> Result<Option<String>, crate::bridge::PresageStatus> {
+    if xfer.is_null() {
+        Ok(None)
+    } else {
+        crate::bridge::xfer_get_local_filename(xfer).map(Some)
+    }
+}
+
+unsafe fn parse_uuid(c_uuid: *const std::os::raw::c_char) -> Result<presage::libsignal_service::prelude::Uuid, crate::bridge::PresageStatus> {
+    let uuid = crate::bridge::from_c_str(c_uuid, "uuid")?;
+    presage::libsignal_service::prelude::Uuid::parse_str(&uuid).map_err(|err| {
+        crate::bridge::log_failure(format!("Invalid uuid {uuid}: {err}\n"));
+        crate::bridge::PresageStatus::InvalidArgument
+    })
+}
+
+unsafe fn parse_group(c_group: *const std::os::raw::c_char) -> Result<presage::libsignal_service::zkgroup::GroupMasterKeyBytes, crate::bridge::PresageStatus> {
+    let group = crate::bridge::from_c_str(c_group, "group")?;
+    crate::structs::parse_group_master_key(&group).map_err(|err| {
+        crate::bridge::log_failure(format!("Invalid group {group}: {err}\n"));
+        crate::bridge::PresageStatus::InvalidArgument
+    })
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn presage_rust_stop(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
-) {
-    let cmd = crate::structs::Cmd::Exit {};
-    send_cmd(rt, tx, cmd);
+pub unsafe extern "C" fn presage_rust_link(
+    tx: *mut crate::bridge::RustChannel,
+    c_device_name: *const std::os::raw::c_char,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_link", || {
+        let device_name = crate::bridge::from_c_str(c_device_name, "device name")?;
+        let server = presage::libsignal_service::configuration::SignalServers::Production;
+        //let server = presage::libsignal_service::configuration::SignalServers::Staging;
+        let cmd = crate::structs::Cmd::LinkDevice {
+            device_name: device_name,
+            servers: server,
+        };
+        send_cmd(tx, cmd)
+    })
 }
 
+/*
+ * Tells the back-end to stop.
+ *
+ * Unlike other commands, this must not get lost: The account's thread would keep running and keep the store locked.
+ * If the queue is full, a helper thread waits for room so the front-end is not blocked.
+ */
 #[no_mangle]
 pub unsafe extern "C" fn presage_rust_exit(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
-) {
-    let cmd = crate::structs::Cmd::Exit {};
-    send_cmd(rt, tx, cmd);
+    tx: *mut crate::bridge::RustChannel,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_exit", || {
+        let command_tx = tx.as_ref().ok_or(crate::bridge::PresageStatus::NotConnected)?;
+        match command_tx.try_send(crate::structs::Cmd::Exit) {
+            Ok(()) => Ok(()),
+            Err(tokio::sync::mpsc::error::TrySendError::Full(cmd)) => {
+                crate::bridge::log(2, String::from("command queue is full, exiting once the back-end has caught up.\n"));
+                let command_tx = command_tx.clone();
+                std::thread::Builder::new()
+                    .name(String::from("presage exit"))
+                    .spawn(move || {
+                        // fails only if the back-end has stopped in the meantime, which is what was asked for
+                        let _ = command_tx.blocking_send(cmd);
+                    })
+                    .map(|_| ())
+                    .map_err(|err| {
+                        crate::bridge::log_failure(format!("Unable to spawn thread for exiting: {err}\n"));
+                        crate::bridge::PresageStatus::QueueFull
+                    })
+            }
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => {
+                crate::bridge::log_failure(String::from("command queue is closed, the back-end has stopped already.\n"));
+                Err(crate::bridge::PresageStatus::ChannelClosed)
+            }
+        }
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn presage_rust_whoami(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
-) {
-    let cmd = crate::structs::Cmd::Whoami {};
-    send_cmd(rt, tx, cmd);
+    tx: *mut crate::bridge::RustChannel,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_whoami", || {
+        let cmd = crate::structs::Cmd::Whoami {};
+        send_cmd(tx, cmd)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn presage_rust_initial_sync(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
-) {
-    let cmd = crate::structs::Cmd::InitialSync {};
-    send_cmd(rt, tx, cmd);
+    tx: *mut crate::bridge::RustChannel,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_initial_sync", || {
+        let cmd = crate::structs::Cmd::InitialSync {};
+        send_cmd(tx, cmd)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn presage_rust_receive(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
-) {
-    let cmd = crate::structs::Cmd::Receive {};
-    send_cmd(rt, tx, cmd);
+    tx: *mut crate::bridge::RustChannel,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_receive", || {
+        let cmd = crate::structs::Cmd::Receive {};
+        send_cmd(tx, cmd)
+    })
 }
 
 // TODO: wire this up completely
 #[no_mangle]
 pub unsafe extern "C" fn presage_rust_list_groups(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
-) {
-    let cmd = crate::structs::Cmd::ListGroups {};
-    send_cmd(rt, tx, cmd);
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_list_groups", || {
+        let cmd = crate::structs::Cmd::ListGroups { request_id: request_id };
+        send_cmd(tx, cmd)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn presage_rust_get_group_members(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
     c_group: *const std::os::raw::c_char,
-) {
-    // TODO: add error handling instead of unwrap()
-    let master_key_bytes = parse_group_master_key(std::ffi::CStr::from_ptr(c_group).to_str().unwrap());
-    let cmd = crate::structs::Cmd::GetGroupMembers {
-        master_key_bytes: master_key_bytes,
-    };
-    send_cmd(rt, tx, cmd);
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_get_group_members", || {
+        let master_key_bytes = parse_group(c_group)?;
+        let cmd = crate::structs::Cmd::GetGroupMembers {
+            request_id: request_id,
+            master_key_bytes: master_key_bytes,
+        };
+        send_cmd(tx, cmd)
+    })
+}
+
+/*
+ * Asks the back-end to encrypt the store with a new passphrase.
+ *
+ * The account is disconnected for a moment while the store is being re-opened.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_change_passphrase(
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+    c_passphrase: *const std::os::raw::c_char,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_change_passphrase", || {
+        let passphrase = crate::bridge::from_c_str(c_passphrase, "passphrase")?;
+        if passphrase.is_empty() {
+            crate::bridge::log_failure(String::from("The passphrase must not be empty.\n"));
+            return Err(crate::bridge::PresageStatus::InvalidArgument);
+        }
+        let cmd = crate::structs::Cmd::ChangePassphrase {
+            request_id: request_id,
+            passphrase: passphrase,
+        };
+        send_cmd(tx, cmd)
+    })
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn presage_rust_send_contact(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
     c_uuid: *const std::os::raw::c_char,
     c_message: *const std::os::raw::c_char,
     xfer: *const std::os::raw::c_void,
-) {
-    // TODO: add error handling instead of unwrap()
-    let uuid = presage::libsignal_service::prelude::Uuid::parse_str(std::ffi::CStr::from_ptr(c_uuid).to_str().unwrap()).unwrap();
-    let cmd = crate::structs::Cmd::Send {
-        recipient: crate::structs::Recipient::Contact(uuid),
-        message: if c_message != std::ptr::null() {
-            Some(std::ffi::CStr::from_ptr(c_message).to_str().unwrap().to_owned())
-        } else {
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_send_contact", || {
+        let uuid = parse_uuid(c_uuid)?;
+        let cmd = crate::structs::Cmd::Send {
+            request_id: request_id,
+            recipient: crate::structs::Recipient::Contact(uuid),
+            message: optional_message(c_message)?,
+            attachment: optional_attachment(xfer)?,
+            xfer: xfer,
+        };
+        send_cmd(tx, cmd)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_send_group(
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+    c_group: *const std::os::raw::c_char,
+    c_message: *const std::os::raw::c_char,
+    xfer: *const std::os::raw::c_void,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_send_group", || {
+        let master_key_bytes = parse_group(c_group)?;
+        let cmd_send = crate::structs::Cmd::Send {
+            request_id: request_id,
+            recipient: crate::structs::Recipient::Group(master_key_bytes),
+            message: optional_message(c_message)?,
+            attachment: optional_attachment(xfer)?,
+            xfer: xfer,
+        };
+        send_cmd(tx, cmd_send)
+    })
+}
+
+/*
+ * Tells the back-end to drop a store which cannot be migrated.
+ *
+ * If backup is true, the store is copied next to itself first.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_resolve_migration_conflict(
+    tx: *mut crate::bridge::RustChannel,
+    backup: bool,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_resolve_migration_conflict", || {
+        let cmd = crate::structs::Cmd::ResolveMigrationConflict { backup: backup };
+        send_cmd(tx, cmd)
+    })
+}
+
+/*
+ * Asks the back-end to write the store into an encrypted archive.
+ *
+ * Messages are included only if history is true.
+ * The account is disconnected for a moment while the store is being read.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_export_backup(
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+    c_path: *const std::os::raw::c_char,
+    c_passphrase: *const std::os::raw::c_char,
+    history: bool,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_export_backup", || {
+        let cmd = crate::structs::Cmd::ExportBackup {
+            request_id: request_id,
+            path: crate::bridge::from_c_str(c_path, "path")?,
+            passphrase: crate::bridge::from_c_str(c_passphrase, "passphrase")?,
+            history: history,
+        };
+        send_cmd(tx, cmd)
+    })
+}
+
+/*
+ * Asks the back-end to replace the store with the contents of an encrypted archive.
+ *
+ * This is refused if this device is linked already.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_import_backup(
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+    c_path: *const std::os::raw::c_char,
+    c_passphrase: *const std::os::raw::c_char,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_import_backup", || {
+        let cmd = crate::structs::Cmd::ImportBackup {
+            request_id: request_id,
+            path: crate::bridge::from_c_str(c_path, "path")?,
+            passphrase: crate::bridge::from_c_str(c_passphrase, "passphrase")?,
+        };
+        send_cmd(tx, cmd)
+    })
+}
+
+/*
+ * Asks the back-end to wipe the store.
+ *
+ * The back-end stays running with an empty store.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_unlink(
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_unlink", || {
+        let cmd = crate::structs::Cmd::Unlink { request_id: request_id };
+        send_cmd(tx, cmd)
+    })
+}
+
+/*
+ * Asks the back-end to accept the new identity of a contact.
+ *
+ * Messages can be sent to the contact again afterwards. The outcome is reported with the request id.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_accept_identity(
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+    c_uuid: *const std::os::raw::c_char,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_accept_identity", || {
+        let uuid = parse_uuid(c_uuid)?;
+        let cmd = crate::structs::Cmd::AcceptIdentity {
+            request_id: request_id,
+            uuid: uuid,
+        };
+        send_cmd(tx, cmd)
+    })
+}
+
+/*
+ * Asks the back-end for the safety number shared with a contact.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_get_safety_number(
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+    c_uuid: *const std::os::raw::c_char,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_get_safety_number", || {
+        let uuid = parse_uuid(c_uuid)?;
+        let cmd = crate::structs::Cmd::GetSafetyNumber {
+            request_id: request_id,
+            uuid: uuid,
+        };
+        send_cmd(tx, cmd)
+    })
+}
+
+/*
+ * Asks the back-end to download an attachment.
+ *
+ * The pointer is the blob from the attachment event. It is not released here.
+ * The destination may be null. The attachment is downloaded into a temporary file then.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_download_attachment(
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+    c_pointer: *const std::os::raw::c_uchar,
+    size: usize,
+    c_destination: *const std::os::raw::c_char,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_download_attachment", || {
+        if c_pointer.is_null() {
+            crate::bridge::log_failure(String::from("attachment pointer is null\n"));
+            return Err(crate::bridge::PresageStatus::InvalidArgument);
+        }
+        let pointer = crate::structs::parse_attachment_pointer(std::slice::from_raw_parts(c_pointer, size)).map_err(|err| {
+            crate::bridge::log_failure(format!("Invalid attachment: {err}\n"));
+            crate::bridge::PresageStatus::InvalidArgument
+        })?;
+        let destination = if c_destination.is_null() {
             None
-        },
-        xfer: xfer,
-    };
-    send_cmd(rt, tx, cmd);
+        } else {
+            Some(crate::bridge::from_c_str(c_destination, "destination")?)
+        };
+        let cmd = crate::structs::Cmd::DownloadAttachment {
+            request_id: request_id,
+            pointer: pointer,
+            destination: destination,
+        };
+        send_cmd(tx, cmd)
+    })
 }
 
 /*
- * Taken from presage-cli
+ * Asks the back-end to stop downloading an attachment.
+ *
+ * The event may still arrive if the download has finished already.
  */
-fn parse_group_master_key(value: &str) -> presage::libsignal_service::zkgroup::GroupMasterKeyBytes {
-    // TODO: forward error to front-end
-    let master_key_bytes = hex::decode(value).expect("unable to decode hex string");
-    master_key_bytes.try_into().expect("master key should be 32 bytes long")
+#[no_mangle]
+pub unsafe extern "C" fn presage_rust_abort_download(
+    tx: *mut crate::bridge::RustChannel,
+    request_id: u64,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_abort_download", || {
+        let cmd = crate::structs::Cmd::AbortDownload { request_id: request_id };
+        send_cmd(tx, cmd)
+    })
 }
 
+/*
+ * Tells a contact (identified by uuid) or a group (identified by its key) that the user started or stopped typing.
+ *
+ * Exactly one of uuid and group must be given.
+ */
 #[no_mangle]
-pub unsafe extern "C" fn presage_rust_send_group(
-    rt: *mut tokio::runtime::Runtime,
-    tx: *mut tokio::sync::mpsc::Sender<crate::structs::Cmd>,
+pub unsafe extern "C" fn presage_rust_send_typing(
+    tx: *mut crate::bridge::RustChannel,
+    c_uuid: *const std::os::raw::c_char,
     c_group: *const std::os::raw::c_char,
-    c_message: *const std::os::raw::c_char,
-    xfer: *const std::os::raw::c_void,
-) {
-    // TODO: add error handling instead of using unwrap()
-    let master_key_bytes = parse_group_master_key(std::ffi::CStr::from_ptr(c_group).to_str().unwrap());
-    let cmd_send = crate::structs::Cmd::Send {
-        recipient: crate::structs::Recipient::Group(master_key_bytes),
-        message: if c_message != std::ptr::null() {
-            Some(std::ffi::CStr::from_ptr(c_message).to_str().unwrap().to_owned())
+    started: bool,
+) -> crate::bridge::PresageStatus {
+    crate::bridge::catch_unwind("presage_rust_send_typing", || {
+        let recipient = if !c_group.is_null() {
+            crate::structs::Recipient::Group(parse_group(c_group)?)
         } else {
-            None
-        },
-        xfer: xfer,
-    };
-    send_cmd(rt, tx, cmd_send);
+            let uuid = parse_uuid(c_uuid)?;
+            crate::structs::Recipient::Contact(uuid)
+        };
+        let cmd = crate::structs::Cmd::SendTyping {
+            recipient: recipient,
+            started: started,
+        };
+        send_cmd(tx, cmd)
+    })
 }
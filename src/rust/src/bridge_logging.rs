@@ -0,0 +1,119 @@
+use tracing_subscriber::layer::SubscriberExt; // for Subscriber.with()
+
+/*
+ * Collects the fields of an event or span into a line of text.
+ */
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: String,
+}
+
+impl tracing::field::Visit for FieldVisitor {
+    fn record_debug(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &dyn std::fmt::Debug,
+    ) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        } else {
+            self.fields.push_str(&format!(" {}={value:?}", field.name()));
+        }
+    }
+
+    fn record_str(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &str,
+    ) {
+        if field.name() == "message" {
+            self.message = value.to_owned();
+        } else {
+            self.fields.push_str(&format!(" {}={value}", field.name()));
+        }
+    }
+}
+
+/*
+ * The formatted fields of a span, stored in the span's extensions.
+ */
+struct SpanFields(String);
+
+/*
+ * Forwards tracing events to purple's debug log.
+ *
+ * Each line is prefixed with the spans the event happened in, e.g.
+ * `purple_presage_backend::core: command{name=send request_id=3}: finished.`
+ *
+ * Personal data is removed unless the user opted out of redaction.
+ */
+pub struct PurpleLayer {
+    redact: bool,
+}
+
+impl<S> tracing_subscriber::Layer<S> for PurpleLayer
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields.trim_start().to_owned()));
+        }
+    }
+
+    fn on_event(
+        &self,
+        event: &tracing::Event<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        // see PurpleDebugLevel in crate::sink::EventSink::debug
+        let level = match *event.metadata().level() {
+            tracing::Level::ERROR => 4,
+            tracing::Level::WARN => 3,
+            tracing::Level::INFO => 2,
+            tracing::Level::DEBUG | tracing::Level::TRACE => 1,
+        };
+        let mut line = format!("{}: ", event.metadata().target());
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                match span.extensions().get::<SpanFields>() {
+                    Some(SpanFields(fields)) if !fields.is_empty() => line.push_str(&format!("{}{{{fields}}}: ", span.name())),
+                    _ => line.push_str(&format!("{}: ", span.name())),
+                }
+            }
+        }
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        line.push_str(&visitor.message);
+        line.push_str(&visitor.fields);
+        line.push('\n');
+        if self.redact {
+            line = crate::redact::redact(&line);
+        }
+        crate::bridge::log(level, line);
+    }
+}
+
+/*
+ * Creates the subscriber for one account.
+ *
+ * The filter and redaction come from the account's settings. An invalid filter is reported and replaced by the default.
+ */
+pub fn subscriber(
+    filter: &str,
+    redact: bool,
+) -> impl tracing::Subscriber + Send + Sync {
+    let env_filter = tracing_subscriber::EnvFilter::try_new(filter).unwrap_or_else(|err| {
+        crate::bridge::log_failure(format!("Invalid log filter „{filter}“: {err}. Using „{}“ instead.\n", crate::bridge::DEFAULT_LOG_FILTER));
+        tracing_subscriber::EnvFilter::new(crate::bridge::DEFAULT_LOG_FILTER)
+    });
+    tracing_subscriber::registry().with(env_filter).with(PurpleLayer { redact: redact })
+}
@@ -1,286 +1,817 @@
-// TODO: purple_error and purple_debug should probably be in the bridge module
-/*
-Look at these error levels from Purple:
-
-typedef enum
-{
-    PURPLE_CONNECTION_ERROR_NETWORK_ERROR = 0,
-    PURPLE_CONNECTION_ERROR_INVALID_USERNAME = 1,
-    PURPLE_CONNECTION_ERROR_AUTHENTICATION_FAILED = 2,
-    PURPLE_CONNECTION_ERROR_AUTHENTICATION_IMPOSSIBLE = 3,
-    PURPLE_CONNECTION_ERROR_NO_SSL_SUPPORT = 4,
-    PURPLE_CONNECTION_ERROR_ENCRYPTION_ERROR = 5,
-    PURPLE_CONNECTION_ERROR_NAME_IN_USE = 6,
-    PURPLE_CONNECTION_ERROR_INVALID_SETTINGS = 7,
-    PURPLE_CONNECTION_ERROR_CERT_NOT_PROVIDED = 8,
-    PURPLE_CONNECTION_ERROR_CERT_UNTRUSTED = 9,
-    PURPLE_CONNECTION_ERROR_CERT_EXPIRED = 10,
-    PURPLE_CONNECTION_ERROR_CERT_NOT_ACTIVATED = 11,
-    PURPLE_CONNECTION_ERROR_CERT_HOSTNAME_MISMATCH = 12,
-    PURPLE_CONNECTION_ERROR_CERT_FINGERPRINT_MISMATCH = 13,
-    PURPLE_CONNECTION_ERROR_CERT_SELF_SIGNED = 14,
-    PURPLE_CONNECTION_ERROR_CERT_OTHER_ERROR = 15,
-    PURPLE_CONNECTION_ERROR_OTHER_ERROR = 16
-} PurpleConnectionError;
-
-TODO: Automatically convert from libpurple/connection.h.
-*/
-
-pub fn purple_error(
-    account: *const std::os::raw::c_void,
-    level: i32,
-    msg: String,
-) {
-    let mut message = crate::bridge::Presage::from_account(account);
-    message.error = level;
-    message.body = std::ffi::CString::new(msg).unwrap().into_raw();
-    crate::bridge::append_message(&message);
-}
-
-/*
-Look at these debug levels from Purple:
-
-typedef enum
-{
-    PURPLE_DEBUG_ALL = 0,  /**< All debug levels.              */
-    PURPLE_DEBUG_MISC,     /**< General chatter.               */
-    PURPLE_DEBUG_INFO,     /**< General operation Information. */
-    PURPLE_DEBUG_WARNING,  /**< Warnings.                      */
-    PURPLE_DEBUG_ERROR,    /**< Errors.                        */
-    PURPLE_DEBUG_FATAL     /**< Fatal errors.                  */
-} PurpleDebugLevel;
-
-TODO: Automatically convert from libpurple/debug.h.
-*/
-pub fn purple_debug(
-    account: *const std::os::raw::c_void,
-    level: i32,
-    msg: String,
-) {
-    let mut message = crate::bridge::Presage::from_account(account);
-    message.debug = level;
-    message.body = std::ffi::CString::new(msg).unwrap().into_raw();
-    crate::bridge::append_message(&message);
-}
+use tracing::Instrument; // for Future.instrument()
 
 /*
  * Runs a command.
  *
+ * `manager` is a copy of the shared manager. It shares the store with all other copies.
+ * Returns a manager only if the command established a (new) session, which then replaces the shared one.
+ * Unless `identities` trusts new identities, messages to contacts with a changed identity are rejected until the identity has been accepted.
+ *
  * Based on presage-cli's `run`.
  */
 async fn run<C: presage::store::Store + 'static>(
     subcommand: crate::structs::Cmd,
     config_store: C,
     manager: Option<presage::Manager<C, presage::manager::Registered>>,
-    account: *const std::os::raw::c_void,
-) -> Result<presage::Manager<C, presage::manager::Registered>, presage::Error<<C>::Error>> {
+    identities: &crate::identity::Identities,
+    sink: &crate::sink::Sink,
+) -> Result<Option<presage::Manager<C, presage::manager::Registered>>, presage::Error<<C>::Error>> {
     match subcommand {
         crate::structs::Cmd::LinkDevice {
             servers,
             device_name,
         } => {
             let (provisioning_link_tx, provisioning_link_rx) = futures::channel::oneshot::channel();
+            let link_sink = sink.clone();
             let join_handle = futures::future::join(presage::Manager::link_secondary_device(config_store, servers, device_name.clone(), provisioning_link_tx), async move {
                 match provisioning_link_rx.await {
                     Ok(url) => {
-                        purple_debug(account, 2, String::from("got URL for QR code\n"));
-                        let mut message = crate::bridge::Presage::from_account(account);
-                        message.qrcode = std::ffi::CString::new(url.to_string()).unwrap().into_raw();
-                        crate::bridge::append_message(&message);
+                        tracing::info!("got URL for QR code");
+                        link_sink.append(crate::structs::Event::QrCode(url.to_string()));
                     }
                     Err(err) => {
-                        purple_error(account, 16, format!("Error linking device: {err:?}"));
+                        link_sink.error(16, format!("Error linking device: {err:?}"));
                     }
                 }
             })
             .await;
 
-            let mut message = crate::bridge::Presage::from_account(account);
             let qrcode_done = String::from("");
-            message.qrcode = std::ffi::CString::new(qrcode_done).unwrap().into_raw();
-            crate::bridge::append_message(&message);
+            sink.append(crate::structs::Event::QrCode(qrcode_done));
             let (manager, _) = join_handle;
-            manager
+            manager.map(Some)
         }
 
         crate::structs::Cmd::Whoami => {
             let manager = manager.unwrap_or(presage::Manager::load_registered(config_store).await?);
             let whoami = manager.whoami().await?;
             let uuid = whoami.uuid.to_string();
-            let mut message = crate::bridge::Presage::from_account(account);
-            message.uuid = std::ffi::CString::new(uuid.to_string()).unwrap().into_raw();
-            crate::bridge::append_message(&message);
-            Ok(manager)
+            sink.append(crate::structs::Event::Uuid(uuid));
+            Ok(Some(manager))
         }
 
         crate::structs::Cmd::InitialSync => {
-            let mut manager = manager.expect("manager must be loaded");
+            let mut manager = manager.ok_or(presage::Error::NotYetRegisteredError)?;
             let messages = manager.receive_messages(presage::manager::ReceivingMode::InitialSync).await;
             match messages {
                 Ok(_) => {
                     // TODO: handle the messages. there might be something useful in there
-                    crate::core::purple_debug(account, 2, format!("InitialSync completed.\n"));
+                    tracing::info!("InitialSync completed.");
 
                     // also, fetch contacts and groups now
-                    manager = crate::contacts::get_contacts(account, Some(manager))?;
-                    manager = crate::contacts::get_groups(account, Some(manager))?;
+                    manager = crate::contacts::get_contacts(sink, Some(manager))?;
+                    manager = crate::contacts::get_groups(sink, Some(manager), 0)?;
 
                     // now that the initial sync has completed,
                     // the connection can be regarded as "connected" and ready to send messages
-                    let mut message = crate::bridge::Presage::from_account(account);
-                    message.connected = 1;
-                    crate::bridge::append_message(&message);
+                    sink.append(crate::structs::Event::Connected);
                 }
                 Err(err) => {
-                    crate::core::purple_error(account, 16, format!("InitialSync error {err:?}"));
+                    sink.error(16, format!("InitialSync error {err:?}"));
                 }
             }
-            Ok(manager)
-        }
-
-        crate::structs::Cmd::Receive => {
-            let manager = manager.expect("manager must be loaded");
-            let mut receiving_manager = manager.clone();
-            tokio::task::spawn_local(async move { crate::receive::receive(&mut receiving_manager, account).await });
-            Ok(manager)
+            Ok(Some(manager))
         }
 
         crate::structs::Cmd::Send {
+            request_id,
             recipient,
             message,
+            attachment,
             xfer,
         } => {
-            let mut manager = manager.expect("manager must be loaded");
-            // prepare a PurplePresage message for providing feed-back (send success or error)
-            let mut msg = crate::bridge::Presage::from_account(account);
-            msg.timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
-            msg.xfer = xfer; // in case of attachments, this is the reference to the respective purple Xfer
+            // the message is identified by its timestamp, so the feed-back must carry the one which is sent
+            let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("Time went backwards").as_millis() as u64;
+            // prepare a message for providing feed-back (send success or error)
+            let mut msg = crate::structs::Message {
+                request_id: request_id,
+                timestamp: timestamp,
+                ..Default::default()
+            };
             match recipient {
                 crate::structs::Recipient::Contact(uuid) => {
-                    msg.who = std::ffi::CString::new(uuid.to_string()).unwrap().into_raw();
+                    msg.who = Some(uuid.to_string());
                 }
                 crate::structs::Recipient::Group(master_key) => {
-                    msg.group = std::ffi::CString::new(hex::encode(master_key)).unwrap().into_raw();
+                    msg.group = Some(hex::encode(master_key));
                 }
             }
             // now do the actual sending and error-handling
-            match crate::send::send(&mut manager, recipient, message.clone(), xfer).await {
-                Ok(_) => {
-                    // NOTE: for Spectrum, send-acknowledgements should be PURPLE_MESSAGE_SEND only (without PURPLE_MESSAGE_REMOTE_SEND)
-                    msg.flags = 0x0001; // PURPLE_MESSAGE_SEND
-                    if let Some(body) = message {
-                        msg.body = std::ffi::CString::new(body).unwrap().into_raw();
+            // NOTE: the front-end waits for the outcome, so every failure must end up in the event below
+            // the outer result is about preparing the message, the inner one about sending it
+            let sent = match manager {
+                Some(mut manager) => {
+                    let uploaded = match attachment {
+                        Some(path) => crate::send::upload_attachment(&mut manager, &path).await.map(Some),
+                        None => Ok(None),
+                    };
+                    let trusted = match uploaded {
+                        Ok(pointer) => check_identities(&manager, &recipient, identities, sink).await.map(|trusted| (pointer, trusted)),
+                        Err(err) => Err(err),
+                    };
+                    match trusted {
+                        Ok((pointer, true)) => {
+                            let sent = crate::send::send(&mut manager, recipient.clone(), message.clone(), pointer, timestamp).await;
+                            // sending fetches the identities of devices not seen before
+                            if let Err(err) = check_identities(&manager, &recipient, identities, sink).await {
+                                tracing::warn!("{err}");
+                            }
+                            Ok(sent)
+                        }
+                        Ok((_, false)) => Err(String::from("The safety number has changed. The new identity must be accepted before messages can be sent.")),
+                        Err(err) => Err(err),
                     }
                 }
+                None => Err(String::from("Not connected to Signal.")),
+            };
+            let error = match sent {
                 Err(err) => {
-                    // TODO: remove this purple_debug once handling errors is reasonably well tested
-                    purple_debug(
-                        account,
-                        4,
-                        format!("{err} occurred while sending a message. The error message should appear in the conversation window.\n"),
-                    );
+                    tracing::warn!("{err} occurred while preparing a message.");
                     msg.flags = 0x0200; // PURPLE_MESSAGE_ERROR
-                    msg.body = std::ffi::CString::new(err.to_string()).unwrap().into_raw();
+                    Some(err)
                 }
+                Ok(Ok(_)) => {
+                    // NOTE: for Spectrum, send-acknowledgements should be PURPLE_MESSAGE_SEND only (without PURPLE_MESSAGE_REMOTE_SEND)
+                    msg.flags = 0x0001; // PURPLE_MESSAGE_SEND
+                    None
+                }
+                Ok(Err(err)) => {
+                    tracing::warn!("{err} occurred while sending a message. The error message should appear in the conversation window.");
+                    msg.flags = 0x0200; // PURPLE_MESSAGE_ERROR
+                    Some(err.to_string())
+                }
+            };
+            // feed the feed-back back into the front-end
+            if !xfer.is_null() {
+                // in case of attachments, this is the reference to the respective purple Xfer
+                sink.append(crate::structs::Event::XferResult {
+                    request_id: request_id,
+                    xfer: xfer,
+                    flags: msg.flags,
+                    error: error,
+                });
+            } else if let Some(body) = error.or(message) {
+                msg.body = body;
+                sink.append(crate::structs::Event::Text(msg));
             }
-            // feed the feed-back back into purple
-            crate::bridge::append_message(&msg);
-            Ok(manager)
+            Ok(None)
         }
 
-        crate::structs::Cmd::ListGroups => crate::contacts::get_groups(account, manager),
+        crate::structs::Cmd::ListGroups { request_id } => crate::contacts::get_groups(sink, manager, request_id).map(|_| None),
 
-        crate::structs::Cmd::GetGroupMembers { master_key_bytes } => crate::contacts::get_group_members(account, manager, master_key_bytes),
+        crate::structs::Cmd::GetGroupMembers {
+            request_id,
+            master_key_bytes,
+        } => crate::contacts::get_group_members(sink, manager, request_id, master_key_bytes).map(|_| None),
 
-        crate::structs::Cmd::Exit {} => {
-            purple_error(account, 16, String::from("Exit command reached inner loop."));
-            panic!("Exit command reached inner loop.");
+        crate::structs::Cmd::GetSafetyNumber { request_id, uuid } => {
+            // the error is reported to the request, the front-end need not link again
+            crate::identity::safety_number(sink, manager.as_ref(), request_id, uuid).await;
+            Ok(None)
+        }
+
+        crate::structs::Cmd::AcceptIdentity { request_id, uuid } => {
+            let error = match manager {
+                Some(manager) => crate::identity::accept(&manager, uuid, identities).await.err(),
+                None => Some(String::from("Not connected to Signal.")),
+            };
+            if error.is_none() {
+                tracing::info!("accepted new identity of {uuid}");
+            }
+            sink.append(crate::structs::Event::IdentityAccepted {
+                request_id: request_id,
+                who: uuid.to_string(),
+                error: error,
+            });
+            Ok(None)
+        }
+
+        crate::structs::Cmd::SendTyping { recipient, started } => {
+            match manager {
+                Some(mut manager) => {
+                    if let Err(err) = crate::send::send_typing(&mut manager, recipient, started).await {
+                        // not worth bothering the user about
+                        tracing::warn!("{err} occurred while sending a typing notification.");
+                    }
+                }
+                None => {
+                    tracing::warn!("not sending a typing notification without being registered.");
+                }
+            }
+            Ok(None)
+        }
+
+        crate::structs::Cmd::DownloadAttachment {
+            request_id,
+            pointer,
+            destination,
+        } => {
+            // the front-end waits for the outcome, so a missing session is reported by the download
+            crate::receive::download(manager.as_ref(), request_id, &pointer, destination.as_deref(), sink).await;
+            Ok(None)
+        }
+
+        crate::structs::Cmd::Receive
+        | crate::structs::Cmd::Exit
+        | crate::structs::Cmd::ChangePassphrase { .. }
+        | crate::structs::Cmd::ResolveMigrationConflict { .. }
+        | crate::structs::Cmd::ExportBackup { .. }
+        | crate::structs::Cmd::ImportBackup { .. }
+        | crate::structs::Cmd::Unlink { .. }
+        | crate::structs::Cmd::AbortDownload { .. } => {
+            // these control the lifetime of tasks and the store and are handled by mainloop
+            // NOTE: only the name is printed so the passphrase does not end up in the log
+            let name = subcommand.name();
+            sink.error(16, format!("{name} command reached inner loop."));
+            panic!("{name} command reached inner loop.");
         }
     }
 }
 
 /*
- * Retrieves commands from the channel.
+ * Checks the identities of a message's recipients before and after sending, see `crate::identity::check`.
+ *
+ * Returns whether the message may be sent.
+ */
+async fn check_identities<C: presage::store::Store>(
+    manager: &presage::Manager<C, presage::manager::Registered>,
+    recipient: &crate::structs::Recipient,
+    identities: &crate::identity::Identities,
+    sink: &crate::sink::Sink,
+) -> Result<bool, String> {
+    let uuids = match recipient {
+        crate::structs::Recipient::Contact(uuid) => vec![*uuid],
+        crate::structs::Recipient::Group(master_key) => match manager.store().group(*master_key) {
+            Ok(Some(group)) => group.members.into_iter().map(|member| member.uuid).collect(),
+            Ok(None) => vec![],
+            Err(err) => return Err(format!("Unable to read the group's members: {err}")),
+        },
+    };
+    let own_uuid = manager.registration_data().service_ids.aci;
+    let mut trusted = true;
+    for uuid in uuids.into_iter().filter(|uuid| *uuid != own_uuid) {
+        // every member is checked so all changes are reported at once
+        trusted &= crate::identity::check(manager, uuid, identities, sink).await?;
+    }
+    Ok(trusted)
+}
+
+/*
+ * The manager shared by all concurrently running commands.
+ *
+ * All tasks live in the same tokio LocalSet, so there is no need for locking.
+ * `None` means there is no registered session (yet or any more).
+ */
+type SharedManager<C> = std::rc::Rc<std::cell::RefCell<Option<presage::Manager<C, presage::manager::Registered>>>>;
+
+/*
+ * Reports a failed command.
+ *
+ * If the command carries a request id, the front-end waits for its outcome, so only the request fails.
+ * Otherwise, the error concerns the connection.
+ */
+fn report_failure(
+    sink: &crate::sink::Sink,
+    request_id: u64,
+    message: String,
+) {
+    if request_id != 0 {
+        sink.request_error(request_id, 16, message);
+    } else {
+        sink.error(16, message);
+    }
+}
+
+/*
+ * Runs a command with the current manager.
  *
  * Delegates work to `run`, but catches the errors for forwarding to the front-end.
  *
- * Based on presage-cli's main loop.
+ * Commands run concurrently, each with a copy of the manager. The shared manager is only replaced
+ * by commands which establish a session (these run one after another, see `mainloop`),
+ * and only cleared if the session is gone for good. So a failing command does not affect the others.
  */
-pub async fn mainloop(
-    config_store: presage_store_sled::SledStore,
-    mut rx: tokio::sync::mpsc::Receiver<crate::structs::Cmd>,
-    account: *const std::os::raw::c_void,
+async fn execute<C: presage::store::Store + 'static>(
+    cmd: crate::structs::Cmd,
+    config_store: C,
+    manager: &SharedManager<C>,
+    identities: &crate::identity::Identities,
+    sink: &crate::sink::Sink,
 ) {
-    let mut manager: Option<presage::Manager<presage_store_sled::SledStore, presage::manager::Registered>> = None;
+    let request_id = cmd.request_id();
+    let span = tracing::info_span!("command", name = cmd.name(), request_id = request_id);
+    tracing::debug!(parent: &span, "begins…");
+    let current_manager = manager.borrow().clone(); // must not hold the borrow across the await
+    match run(cmd, config_store, current_manager, identities, sink).instrument(span.clone()).await {
+        Ok(Some(m)) => {
+            *manager.borrow_mut() = Some(m);
+        }
+        Ok(None) => {
+            // the shared manager stays as it is
+        }
+        Err(presage::Error::NotYetRegisteredError) => {
+            // can happen during whoami
+            *manager.borrow_mut() = None;
+            if request_id != 0 {
+                sink.request_error(request_id, 16, String::from("Not connected to Signal."));
+            }
+            // tell the front-end we lost authorization
+            let uuid = String::from("");
+            sink.append(crate::structs::Event::Uuid(uuid));
+        }
+        Err(presage::Error::ServiceError(err)) => {
+            // can happen during whoami or send, possibly others, after main device has revoked the link
+            match err {
+                presage::libsignal_service::push_service::ServiceError::Unauthorized => {
+                    *manager.borrow_mut() = None;
+                    if request_id != 0 {
+                        sink.request_error(request_id, 16, String::from("Not connected to Signal."));
+                    }
+                    // tell the front-end we lost authorization
+                    let uuid = String::from("");
+                    sink.append(crate::structs::Event::Uuid(uuid));
+                }
+                _ => {
+                    report_failure(sink, request_id, format!("run unhandled ServiceError {err:?}"));
+                }
+            }
+        }
+        Err(err) => {
+            report_failure(sink, request_id, format!("run Err {err:?}"));
+        }
+    }
+    tracing::debug!(parent: &span, "finished.");
+}
+
+/*
+ * Starts a task which sends messages to one recipient.
+ *
+ * Messages to the same recipient are sent one after another so they arrive in order.
+ * Messages to different recipients do not wait for each other.
+ * Typing notifications are sent by the same task as the messages.
+ */
+fn spawn_send_worker<C: presage::store::Store + 'static>(
+    config_store: C,
+    manager: SharedManager<C>,
+    identities: crate::identity::Identities,
+    sink: crate::sink::Sink,
+    tasks: &mut Vec<tokio::task::JoinHandle<()>>,
+) -> tokio::sync::mpsc::UnboundedSender<crate::structs::Cmd> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tasks.push(tokio::task::spawn_local(async move {
+        while let Some(cmd) = rx.recv().await {
+            execute(cmd, config_store.clone(), &manager, &identities, &sink).await;
+        }
+    }));
+    tx
+}
+
+/*
+ * Retrieves commands from the channel.
+ *
+ * Commands which set up the session (linking, identification, initial sync) are run one after another.
+ * All other commands run concurrently so a slow transfer does not hold up the entire account.
+ *
+ * `first` is run before any command is read from the channel.
+ *
+ * Returns once the front-end asks to exit (or goes away) or sends a command which needs the store to be closed.
+ * In the latter case, the command is returned.
+ * All tasks are cancelled before returning, so the caller may close the store afterwards.
+ *
+ * This does not care about the concrete store. Opening the store is up to the caller.
+ * The store must trust new identities, see `crate::identity`. `identities` belongs to the store.
+ *
+ * Based on presage-cli's main loop.
+ */
+pub async fn mainloop<C: presage::store::Store + 'static>(
+    config_store: C,
+    rx: &mut tokio::sync::mpsc::Receiver<crate::structs::Cmd>,
+    identities: crate::identity::Identities,
+    sink: crate::sink::Sink,
+    first: Option<crate::structs::Cmd>,
+) -> Option<crate::structs::Cmd> {
+    let manager: SharedManager<C> = std::rc::Rc::new(std::cell::RefCell::new(None));
+    let mut send_workers: std::collections::HashMap<crate::structs::Recipient, tokio::sync::mpsc::UnboundedSender<crate::structs::Cmd>> = std::collections::HashMap::new();
+    let mut receiver: Option<tokio::task::JoinHandle<()>> = None;
+    let mut tasks: Vec<tokio::task::JoinHandle<()>> = vec![];
+    let mut downloads: std::collections::HashMap<u64, tokio::task::JoinHandle<()>> = std::collections::HashMap::new(); // by request id
+    let mut maintenance = None;
+    if let Some(cmd) = first {
+        execute(cmd, config_store.clone(), &manager, &identities, &sink).await;
+    }
     while let Some(cmd) = rx.recv().await {
+        // forget about tasks which are done already
+        tasks.retain(|task| !task.is_finished());
+        downloads.retain(|_, task| !task.is_finished());
+        // TODO: find out if config_store.clone() is the correct thing to do here
         match cmd {
             crate::structs::Cmd::Exit => {
                 break;
             }
-            _ => {
-                //purple_debug(account, 2, format!("run {:?} begins…\n", cmd));
-                // TODO: find out if config_store.clone() is the correct thing to do here
-                match run(cmd.clone(), config_store.clone(), manager, account).await {
-                    Ok(m) => {
-                        manager = Some(m);
-                    }
-                    Err(presage::Error::NotYetRegisteredError) => {
-                        // can happen during whoami
-                        manager = None;
-                        // tell the front-end we lost authorization
-                        let uuid = String::from("");
-                        let mut message = crate::bridge::Presage::from_account(account);
-                        message.uuid = std::ffi::CString::new(uuid.to_string()).unwrap().into_raw();
-                        crate::bridge::append_message(&message);
-                    }
-                    Err(presage::Error::ServiceError(err)) => {
-                        // can happen during whoami or send, possibly others, after main device has revoked the link
-                        manager = None;
-                        match err {
-                            presage::libsignal_service::push_service::ServiceError::Unauthorized => {
-                                // tell the front-end we lost authorization
-                                let uuid = String::from("");
-                                let mut message = crate::bridge::Presage::from_account(account);
-                                message.uuid = std::ffi::CString::new(uuid.to_string()).unwrap().into_raw();
-                                crate::bridge::append_message(&message);
-                            }
-                            _ => {
-                                purple_error(account, 16, format!("run unhandled ServiceError {err:?}"));
-                            }
+            crate::structs::Cmd::ChangePassphrase { .. } | crate::structs::Cmd::ExportBackup { .. } | crate::structs::Cmd::Unlink { .. } => {
+                // the store must be closed for these
+                maintenance = Some(cmd);
+                break;
+            }
+            crate::structs::Cmd::ImportBackup { request_id, .. } => {
+                // never overwrite a working session
+                if presage::Manager::load_registered(config_store.clone()).await.is_ok() {
+                    sink.append(crate::structs::Event::BackupImported {
+                        request_id: request_id,
+                        error: Some(String::from("This device is linked already. A backup can only be imported into a new account.")),
+                    });
+                } else {
+                    maintenance = Some(cmd);
+                    break;
+                }
+            }
+            crate::structs::Cmd::Receive => {
+                let current_manager = manager.borrow().clone();
+                match current_manager {
+                    Some(mut receiving_manager) => {
+                        if let Some(previous) = receiver.take() {
+                            // there must not be more than one receiver
+                            previous.abort();
                         }
+                        let receiving_identities = identities.clone();
+                        let receiving_sink = sink.clone();
+                        receiver = Some(tokio::task::spawn_local(async move { crate::receive::receive(&mut receiving_manager, &receiving_identities, &receiving_sink).await }));
                     }
-                    Err(err) => {
-                        manager = None;
-                        purple_error(account, 16, format!("run Err {err:?}"));
+                    None => {
+                        sink.error(16, String::from("Cannot receive messages without being registered."));
                     }
                 }
-                //purple_debug(account, 2, format!("run {:?} finished.\n", cmd));
+            }
+            crate::structs::Cmd::Send { ref recipient, .. } | crate::structs::Cmd::SendTyping { ref recipient, .. } => {
+                // typing notifications take the same route so they do not overtake the message being typed
+                let recipient = recipient.clone();
+                let worker = send_workers.entry(recipient.clone()).or_insert_with(|| spawn_send_worker(config_store.clone(), manager.clone(), identities.clone(), sink.clone(), &mut tasks));
+                if let Err(err) = worker.send(cmd) {
+                    // the worker has gone away (e.g. it panicked), replace it
+                    let worker = spawn_send_worker(config_store.clone(), manager.clone(), identities.clone(), sink.clone(), &mut tasks);
+                    let _ = worker.send(err.0);
+                    send_workers.insert(recipient, worker);
+                }
+            }
+            crate::structs::Cmd::ListGroups { .. } | crate::structs::Cmd::GetGroupMembers { .. } | crate::structs::Cmd::GetSafetyNumber { .. } => {
+                let task_store = config_store.clone();
+                let task_manager = manager.clone();
+                let task_identities = identities.clone();
+                let task_sink = sink.clone();
+                tasks.push(tokio::task::spawn_local(async move { execute(cmd, task_store, &task_manager, &task_identities, &task_sink).await }));
+            }
+            crate::structs::Cmd::DownloadAttachment { request_id, .. } => {
+                let task_store = config_store.clone();
+                let task_manager = manager.clone();
+                let task_identities = identities.clone();
+                let task_sink = sink.clone();
+                downloads.insert(request_id, tokio::task::spawn_local(async move { execute(cmd, task_store, &task_manager, &task_identities, &task_sink).await }));
+            }
+            crate::structs::Cmd::AbortDownload { request_id } => {
+                // the front-end is not interested in the result any more
+                // NOTE: if the download has finished already, the event is on its way regardless
+                if let Some(task) = downloads.remove(&request_id) {
+                    tracing::debug!("aborting download for request {request_id}");
+                    task.abort();
+                }
+            }
+            _ => {
+                execute(cmd, config_store.clone(), &manager, &identities, &sink).await;
             }
         }
     }
+
+    // shut down: cancel all tasks so they release their references to the manager and the store
+    tracing::info!("shutting down…");
+    drop(send_workers);
+    tasks.extend(receiver);
+    tasks.extend(downloads.into_values());
+    for task in &tasks {
+        task.abort();
+    }
+    for task in tasks {
+        // a cancelled task reports an error which is expected here
+        let _ = task.await;
+    }
+    drop(manager);
+    drop(config_store);
+    tracing::info!("store closed.");
+    maintenance
+}
+
+/*
+ * Runs a command which needs the store to be closed.
+ *
+ * The outcome is reported to the front-end.
+ * Returns the command to run once the store has been opened again.
+ */
+async fn maintain(
+    cmd: crate::structs::Cmd,
+    store_path: &str,
+    passphrase: &mut Option<String>,
+    sink: &crate::sink::Sink,
+) -> Option<crate::structs::Cmd> {
+    match cmd {
+        crate::structs::Cmd::ChangePassphrase {
+            request_id,
+            passphrase: new_passphrase,
+        } => {
+            let error = crate::store::change_passphrase(store_path, passphrase.as_deref(), &new_passphrase).err();
+            if error.is_none() {
+                tracing::info!("passphrase changed");
+                *passphrase = Some(new_passphrase);
+            }
+            sink.append(crate::structs::Event::PassphraseChanged {
+                request_id: request_id,
+                error: error,
+            });
+            // the session was running, the manager needs to be loaded again
+            Some(crate::structs::Cmd::Whoami)
+        }
+        crate::structs::Cmd::ExportBackup {
+            request_id,
+            path,
+            passphrase: backup_passphrase,
+            history,
+        } => {
+            let error = crate::backup::export(store_path, passphrase.as_deref(), &path, &backup_passphrase, history).err();
+            if error.is_none() {
+                tracing::info!("backup exported to {path}");
+            }
+            sink.append(crate::structs::Event::BackupExported {
+                request_id: request_id,
+                error: error,
+            });
+            Some(crate::structs::Cmd::Whoami)
+        }
+        crate::structs::Cmd::ImportBackup {
+            request_id,
+            path,
+            passphrase: backup_passphrase,
+        } => {
+            let error = crate::backup::import(store_path, &path, &backup_passphrase).err();
+            if error.is_none() {
+                tracing::info!("backup imported from {path}");
+            }
+            sink.append(crate::structs::Event::BackupImported {
+                request_id: request_id,
+                error: error,
+            });
+            // the front-end continues with whoami, no matter the outcome
+            None
+        }
+        crate::structs::Cmd::Unlink { request_id } => {
+            // NOTE: presage offers no way to remove this device from the account, this needs to be done on the main device
+            let error = crate::store::wipe(store_path).err();
+            if error.is_none() {
+                tracing::info!("store wiped");
+            }
+            sink.append(crate::structs::Event::Unlinked {
+                request_id: request_id,
+                error: error,
+            });
+            // the store is empty now, the front-end decides whether to link again
+            None
+        }
+        cmd => {
+            panic!("{} command does not need the store to be closed.", cmd.name());
+        }
+    }
+}
+
+/*
+ * Waits for the front-end to decide what to do about a store which cannot be migrated.
+ *
+ * Returns whether to back up the store before dropping it, or None if the front-end wants to exit.
+ */
+async fn wait_for_conflict_resolution(rx: &mut tokio::sync::mpsc::Receiver<crate::structs::Cmd>) -> Option<bool> {
+    while let Some(cmd) = rx.recv().await {
+        match cmd {
+            crate::structs::Cmd::ResolveMigrationConflict { backup } => return Some(backup),
+            crate::structs::Cmd::Exit => return None,
+            cmd => {
+                // e.g. whoami, it is repeated once the store is open
+                tracing::debug!("ignoring {} command while the store is closed", cmd.name());
+            }
+        }
+    }
+    None
 }
 
 /*
  * Opens the store and runs commands forever.
  *
+ * The store is encrypted if a passphrase is given.
+ * When the passphrase is changed, a backup is made or the store is wiped, the store is closed and opened again.
+ * If the store cannot be migrated to the current version, the front-end is asked whether to drop it.
+ * Unless `trust_new_identities` is set, messages to contacts with a changed identity are rejected until the identity has been accepted.
+ *
  * Based on presage-cli's main loop.
  */
 pub async fn main(
     store_path: String,
     passphrase: Option<String>,
-    rx: tokio::sync::mpsc::Receiver<crate::structs::Cmd>,
-    account: *const std::os::raw::c_void,
+    trust_new_identities: bool,
+    mut rx: tokio::sync::mpsc::Receiver<crate::structs::Cmd>,
+    sink: crate::sink::Sink,
 ) {
-    purple_debug(account, 2, format!("opening config database from {store_path}\n"));
-    let config_store =
-        presage_store_sled::SledStore::open_with_passphrase(store_path, passphrase, presage_store_sled::MigrationConflictStrategy::Raise, presage_store_sled::OnNewIdentity::Trust);
-    match config_store {
-        Err(err) => {
-            purple_error(account, 16, format!("config_store Err {err:?}"));
+    let mut passphrase = passphrase;
+    let mut first = None;
+    let mut drop_on_conflict = false;
+    loop {
+        tracing::info!("opening config database from {store_path}");
+        let migration_conflict_strategy = if drop_on_conflict {
+            presage_store_sled::MigrationConflictStrategy::Drop
+        } else {
+            presage_store_sled::MigrationConflictStrategy::Raise
+        };
+        if let Err(err) = crate::store::check_encryption(&store_path, passphrase.as_deref()) {
+            tracing::warn!("{err}");
+            sink.error(7, err); // PURPLE_CONNECTION_ERROR_INVALID_SETTINGS
+            return;
         }
-        Ok(config_store) => {
-            purple_debug(account, 2, String::from("config_store OK\n"));
-            mainloop(config_store, rx, account).await;
+        // rejecting new identities is up to crate::identity, the store needs to know about them
+        let config_store = presage_store_sled::SledStore::open_with_passphrase(store_path.clone(), passphrase.clone(), migration_conflict_strategy, presage_store_sled::OnNewIdentity::Trust);
+        drop_on_conflict = false;
+        match config_store {
+            Err(presage_store_sled::SledStoreError::MigrationConflict { .. }) => {
+                tracing::warn!("the store cannot be migrated");
+                sink.append(crate::structs::Event::MigrationConflict);
+                let Some(backup) = wait_for_conflict_resolution(&mut rx).await else {
+                    return;
+                };
+                let mut backup_path = None;
+                if backup {
+                    match crate::store::backup(&store_path) {
+                        Ok(path) => {
+                            tracing::info!("store backed up to {path}");
+                            backup_path = Some(path);
+                        }
+                        Err(err) => {
+                            // do not drop anything without a backup
+                            sink.error(16, err);
+                            return;
+                        }
+                    }
+                }
+                drop_on_conflict = true;
+                sink.append(crate::structs::Event::StoreReset { backup_path: backup_path });
+                // the store is empty now, whoami will start linking
+                first = Some(crate::structs::Cmd::Whoami);
+            }
+            Err(presage_store_sled::SledStoreError::StoreCipher(err)) => {
+                tracing::warn!("unable to decrypt the store: {err}");
+                sink.error(2, String::from("Unable to unlock the store. The passphrase is probably wrong.")); // PURPLE_CONNECTION_ERROR_AUTHENTICATION_FAILED
+                return;
+            }
+            Err(err) => {
+                sink.error(16, format!("config_store Err {err:?}"));
+                return;
+            }
+            Ok(config_store) => {
+                tracing::info!("config_store OK");
+                let identities = match crate::identity::Identities::open(&store_path, passphrase.as_deref(), trust_new_identities) {
+                    Ok(identities) => identities,
+                    Err(err) => {
+                        sink.error(16, err);
+                        return;
+                    }
+                };
+                let Some(cmd) = mainloop(config_store, &mut rx, identities, sink.clone(), first.take()).await else {
+                    return;
+                };
+                first = maintain(cmd, &store_path, &mut passphrase, &sink).await;
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    /*
+     * Opens an empty store like `main` does.
+     */
+    fn open_store(dir: &crate::testing::TestDir) -> presage_store_sled::SledStore {
+        presage_store_sled::SledStore::open_with_passphrase(dir.path("store"), None::<String>, presage_store_sled::MigrationConflictStrategy::Raise, presage_store_sled::OnNewIdentity::Trust).unwrap()
+    }
+
+    fn open_identities(dir: &crate::testing::TestDir) -> crate::identity::Identities {
+        crate::identity::Identities::open(&dir.path("store"), None, false).unwrap()
+    }
+
+    /*
+     * Runs the main loop until it returns, feeding it the commands one after another.
+     *
+     * Before exiting, waits for the given number of events so tasks running concurrently get the chance to finish.
+     */
+    async fn run_mainloop(
+        commands: Vec<crate::structs::Cmd>,
+        expected_events: usize,
+        dir: &crate::testing::TestDir,
+    ) -> (Option<crate::structs::Cmd>, Vec<crate::structs::Event>) {
+        let recording = std::rc::Rc::new(crate::sink::RecordingSink::default());
+        let sink: crate::sink::Sink = recording.clone();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let mut events = vec![];
+        let local = tokio::task::LocalSet::new();
+        let returned = local
+            .run_until(async {
+                let driver = async {
+                    for cmd in commands {
+                        tx.send(cmd).await.unwrap();
+                    }
+                    while events.len() < expected_events {
+                        events.extend(recording.take());
+                        tokio::task::yield_now().await;
+                    }
+                    // the main loop may have returned already
+                    let _ = tx.send(crate::structs::Cmd::Exit).await;
+                };
+                let (returned, _) = futures::future::join(super::mainloop(open_store(dir), &mut rx, open_identities(dir), sink, None), driver).await;
+                returned
+            })
+            .await;
+        events.extend(recording.take());
+        (returned, events)
+    }
+
+    #[tokio::test]
+    async fn commands_without_session() {
+        let dir = crate::testing::TestDir::new("mainloop-without-session");
+        let uuid = presage::libsignal_service::prelude::Uuid::from_u128(1);
+        let commands = vec![
+            crate::structs::Cmd::Whoami,
+            crate::structs::Cmd::Send {
+                request_id: 1,
+                recipient: crate::structs::Recipient::Contact(uuid),
+                message: Some(String::from("hello")),
+                attachment: None,
+                xfer: std::ptr::null(),
+            },
+            crate::structs::Cmd::AcceptIdentity { request_id: 2, uuid: uuid },
+            crate::structs::Cmd::GetSafetyNumber { request_id: 6, uuid: uuid },
+        ];
+        let (returned, events) = run_mainloop(commands, 4, &dir).await;
+        assert!(returned.is_none());
+        assert_eq!(events.len(), 4, "{events:?}");
+        // whoami and accepting run in the main loop, so their events are in order
+        assert!(matches!(&events[0], crate::structs::Event::Uuid(own_uuid) if own_uuid.is_empty()), "{events:?}");
+        assert!(
+            events.iter().any(|event| matches!(event, crate::structs::Event::Text(message) if message.request_id == 1 && message.flags == 0x0200 && message.body == "Not connected to Signal.")),
+            "{events:?}"
+        );
+        assert!(
+            events.iter().any(|event| matches!(event, crate::structs::Event::IdentityAccepted { request_id: 2, error: Some(_), .. })),
+            "{events:?}"
+        );
+        // only the request fails, the front-end is not asked to link again
+        assert!(
+            events.iter().any(|event| matches!(event, crate::structs::Event::Text(message) if message.request_id == 6 && message.flags == 0x0200)),
+            "{events:?}"
+        );
+        assert_eq!(events.iter().filter(|event| matches!(event, crate::structs::Event::Uuid(_))).count(), 1, "{events:?}");
+    }
+
+    #[tokio::test]
+    async fn requests_fail_without_session() {
+        let dir = crate::testing::TestDir::new("mainloop-request-error");
+        let (returned, events) = run_mainloop(vec![crate::structs::Cmd::ListGroups { request_id: 5 }], 2, &dir).await;
+        assert!(returned.is_none());
+        // the request is answered, so the front-end does not wait for it forever
+        assert!(matches!(&events[0], crate::structs::Event::Error { request_id: Some(5), .. }), "{events:?}");
+        assert!(matches!(&events[1], crate::structs::Event::Uuid(uuid) if uuid.is_empty()), "{events:?}");
+    }
+
+    #[tokio::test]
+    async fn maintenance_is_handed_back() {
+        let dir = crate::testing::TestDir::new("mainloop-maintenance");
+        let (returned, events) = run_mainloop(vec![crate::structs::Cmd::Unlink { request_id: 3 }], 0, &dir).await;
+        assert!(matches!(returned, Some(crate::structs::Cmd::Unlink { request_id: 3 })), "{returned:?}");
+        assert!(events.is_empty(), "{events:?}");
+    }
+
+    #[tokio::test]
+    async fn import_is_handed_back_without_session() {
+        let dir = crate::testing::TestDir::new("mainloop-import");
+        let import = crate::structs::Cmd::ImportBackup {
+            request_id: 4,
+            path: dir.path("backup.bin"),
+            passphrase: String::from("correct horse"),
+        };
+        let (returned, events) = run_mainloop(vec![import], 0, &dir).await;
+        assert!(matches!(returned, Some(crate::structs::Cmd::ImportBackup { request_id: 4, .. })), "{returned:?}");
+        assert!(events.is_empty(), "{events:?}");
+    }
+
+    #[tokio::test]
+    async fn exit_when_front_end_goes_away() {
+        let dir = crate::testing::TestDir::new("mainloop-gone");
+        let recording = std::rc::Rc::new(crate::sink::RecordingSink::default());
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        drop(tx);
+        let returned = tokio::task::LocalSet::new().run_until(super::mainloop(open_store(&dir), &mut rx, open_identities(&dir), recording.clone(), Some(crate::structs::Cmd::Whoami))).await;
+        assert!(returned.is_none());
+        // the first command runs nevertheless
+        assert!(matches!(recording.take().as_slice(), [crate::structs::Event::Uuid(uuid)] if uuid.is_empty()));
+    }
+}
@@ -0,0 +1,307 @@
+/*
+ * Handling of contacts' identities (safety numbers).
+ *
+ * The store trusts new identities on the protocol level. Whether messages may be sent to a contact
+ * whose identity has changed is decided here, so the decision can be changed without re-opening the store.
+ */
+
+use presage::libsignal_service::protocol::IdentityKeyStore; // for get_identity() and get_identity_key_pair()
+
+/*
+ * The address of a contact's primary device. The identity is the same for all devices.
+ */
+fn contact_address(uuid: presage::libsignal_service::prelude::Uuid) -> presage::libsignal_service::protocol::ProtocolAddress {
+    presage::libsignal_service::protocol::ProtocolAddress::new(uuid.to_string(), presage::libsignal_service::protocol::DeviceId::from(1))
+}
+
+/*
+ * The identities acknowledged for contacts and the changed identities already reported to the front-end.
+ *
+ * The store trusts every identity (see `crate::core::main`), so it always holds the identity a contact currently uses.
+ * What has been acknowledged is kept apart from the store, in a database of its own within the store's directory.
+ * If the store is encrypted, this database is encrypted with the same passphrase, like presage_store_sled does it.
+ */
+#[derive(Clone)]
+pub struct Identities {
+    trust_new: bool,
+    db: sled::Db,
+    cipher: Option<std::rc::Rc<presage_store_cipher::StoreCipher>>,
+}
+
+const ACKNOWLEDGED: &str = "acknowledged";
+const REPORTED: &str = "reported";
+
+/*
+ * Where the database is kept.
+ */
+pub fn path(store_path: &str) -> std::path::PathBuf {
+    std::path::Path::new(store_path).join("identities")
+}
+
+impl Identities {
+    /*
+     * Opens the database belonging to the store at `store_path`.
+     *
+     * The passphrase must be the store's.
+     * Unless `trust_new` is set, contacts with a changed identity need to be accepted before sending to them.
+     */
+    pub fn open(
+        store_path: &str,
+        passphrase: Option<&str>,
+        trust_new: bool,
+    ) -> Result<Self, String> {
+        let db = sled::open(path(store_path)).map_err(|err| format!("Unable to open the identities: {err}"))?;
+        let exported = db.get(crate::store::STORE_CIPHER_KEY).map_err(|err| format!("Unable to read the identities' key: {err}"))?;
+        let cipher = match (passphrase, exported) {
+            (None, None) => None,
+            (None, Some(_)) => {
+                return Err(String::from("The identities are encrypted, but the store is not."));
+            }
+            (Some(passphrase), Some(exported)) => Some(presage_store_cipher::StoreCipher::import(passphrase, &exported).map_err(|err| format!("Unable to unlock the identities: {err}"))?),
+            (Some(passphrase), None) => {
+                let empty = [ACKNOWLEDGED, REPORTED].iter().all(|name| db.open_tree(name).map(|tree| tree.is_empty()).unwrap_or(false));
+                if !empty {
+                    return Err(String::from("The identities are not encrypted, but the store is."));
+                }
+                // a new database
+                let cipher = presage_store_cipher::StoreCipher::new().map_err(|err| format!("Unable to create the identities' key: {err}"))?;
+                let exported = cipher.export(passphrase).map_err(|err| format!("Unable to encrypt the identities' key: {err}"))?;
+                db.insert(crate::store::STORE_CIPHER_KEY, exported).map_err(|err| format!("Unable to write the identities' key: {err}"))?;
+                Some(cipher)
+            }
+        };
+        Ok(Self {
+            trust_new: trust_new,
+            db: db,
+            cipher: cipher.map(std::rc::Rc::new),
+        })
+    }
+
+    fn key(
+        &self,
+        tree: &str,
+        uuid: presage::libsignal_service::prelude::Uuid,
+    ) -> Vec<u8> {
+        match &self.cipher {
+            Some(cipher) => cipher.hash_key(tree, uuid.as_bytes()).to_vec(),
+            None => uuid.as_bytes().to_vec(),
+        }
+    }
+
+    fn get(
+        &self,
+        tree: &str,
+        uuid: presage::libsignal_service::prelude::Uuid,
+    ) -> Result<Option<presage::libsignal_service::protocol::IdentityKey>, String> {
+        let value = self
+            .db
+            .open_tree(tree)
+            .and_then(|opened| opened.get(self.key(tree, uuid)))
+            .map_err(|err| format!("Unable to read the contact's {tree} identity: {err}"))?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let serialized: Vec<u8> = match &self.cipher {
+            Some(cipher) => cipher.decrypt_value(&value).map_err(|err| format!("Unable to decrypt the contact's {tree} identity: {err}"))?,
+            None => value.to_vec(),
+        };
+        presage::libsignal_service::protocol::IdentityKey::decode(&serialized).map(Some).map_err(|err| format!("Unable to read the contact's {tree} identity: {err}"))
+    }
+
+    fn set(
+        &self,
+        tree: &str,
+        uuid: presage::libsignal_service::prelude::Uuid,
+        identity: &presage::libsignal_service::protocol::IdentityKey,
+    ) -> Result<(), String> {
+        let serialized = identity.serialize().to_vec();
+        let value = match &self.cipher {
+            Some(cipher) => cipher.encrypt_value(&serialized).map_err(|err| format!("Unable to encrypt the contact's {tree} identity: {err}"))?,
+            None => serialized,
+        };
+        self.db
+            .open_tree(tree)
+            .and_then(|opened| opened.insert(self.key(tree, uuid), value))
+            .map_err(|err| format!("Unable to remember the contact's {tree} identity: {err}"))?;
+        Ok(())
+    }
+}
+
+/*
+ * Checks whether the identity a contact currently uses has been acknowledged.
+ *
+ * This is done whenever a message has been exchanged with the contact, so a change is noticed no matter who noticed it first.
+ * The first identity seen of a contact is acknowledged silently.
+ * A changed identity is reported to the front-end once. It is acknowledged right away if new identities are trusted.
+ * Otherwise, it needs to be accepted, see `accept`.
+ *
+ * Returns whether messages may be sent to the contact.
+ */
+pub async fn check<C: presage::store::Store>(
+    manager: &presage::Manager<C, presage::manager::Registered>,
+    uuid: presage::libsignal_service::prelude::Uuid,
+    identities: &Identities,
+    sink: &crate::sink::Sink,
+) -> Result<bool, String> {
+    let protocol_store = manager.store().aci_protocol_store();
+    let Some(current) = protocol_store.get_identity(&contact_address(uuid)).await.map_err(|err| format!("Unable to read the contact's identity: {err}"))? else {
+        // no message has been exchanged, yet
+        return Ok(true);
+    };
+    let acknowledged = identities.get(ACKNOWLEDGED, uuid)?;
+    if acknowledged.as_ref() == Some(&current) {
+        return Ok(true);
+    }
+    if acknowledged.is_none() {
+        identities.set(ACKNOWLEDGED, uuid, &current)?;
+        return Ok(true);
+    }
+    let reported = identities.get(REPORTED, uuid)?;
+    if reported.as_ref() != Some(&current) {
+        // the front-end compares this with the safety number the user has verified
+        let (digits, _) = compute_safety_number(manager, uuid).await?;
+        tracing::warn!("identity of {uuid} has changed");
+        sink.append(crate::structs::Event::IdentityChanged {
+            who: uuid.to_string(),
+            accepted: identities.trust_new,
+            digits: digits,
+        });
+        identities.set(REPORTED, uuid, &current)?;
+    }
+    if identities.trust_new {
+        identities.set(ACKNOWLEDGED, uuid, &current)?;
+    }
+    Ok(identities.trust_new)
+}
+
+/*
+ * Accepts the identity a contact currently uses.
+ *
+ * Messages can be sent to the contact again afterwards.
+ */
+pub async fn accept<C: presage::store::Store>(
+    manager: &presage::Manager<C, presage::manager::Registered>,
+    uuid: presage::libsignal_service::prelude::Uuid,
+    identities: &Identities,
+) -> Result<(), String> {
+    let protocol_store = manager.store().aci_protocol_store();
+    let current = protocol_store
+        .get_identity(&contact_address(uuid))
+        .await
+        .map_err(|err| format!("Unable to read the contact's identity: {err}"))?
+        .ok_or_else(|| String::from("The contact's identity is not known, yet. Exchange a message first."))?;
+    identities.set(ACKNOWLEDGED, uuid, &current)
+}
+
+/*
+ * Computes the safety number shared with a contact.
+ *
+ * Uses the identifiers (ACIs) and identity keys like the official apps do.
+ */
+async fn compute_safety_number<C: presage::store::Store>(
+    manager: &presage::Manager<C, presage::manager::Registered>,
+    uuid: presage::libsignal_service::prelude::Uuid,
+) -> Result<(String, Vec<u8>), String> {
+    const FINGERPRINT_VERSION: u32 = 2; // using ACIs as identifiers
+    const FINGERPRINT_ITERATIONS: u32 = 5200;
+    let protocol_store = manager.store().aci_protocol_store();
+    let own_key_pair = protocol_store.get_identity_key_pair().await.map_err(|err| format!("Unable to read own identity: {err}"))?;
+    let their_key = protocol_store
+        .get_identity(&contact_address(uuid))
+        .await
+        .map_err(|err| format!("Unable to read the contact's identity: {err}"))?
+        .ok_or_else(|| String::from("The contact's identity is not known, yet. Exchange a message first."))?;
+    let own_uuid = manager.registration_data().service_ids.aci;
+    let fingerprint = presage::libsignal_service::protocol::Fingerprint::new(
+        FINGERPRINT_VERSION,
+        FINGERPRINT_ITERATIONS,
+        own_uuid.as_bytes(),
+        own_key_pair.identity_key(),
+        uuid.as_bytes(),
+        &their_key,
+    )
+    .map_err(|err| format!("Unable to compute the safety number: {err}"))?;
+    let digits = fingerprint.display_string().map_err(|err| format!("Unable to compute the safety number: {err}"))?;
+    // twelve groups of five digits, as displayed by the official apps
+    let digits = digits.as_bytes().chunks(5).map(|chunk| String::from_utf8_lossy(chunk)).collect::<Vec<_>>().join(" ");
+    let scannable = fingerprint.scannable.serialize().map_err(|err| format!("Unable to encode the safety number: {err}"))?;
+    Ok((digits, scannable))
+}
+
+/*
+ * Reports the safety number shared with a contact.
+ *
+ * Failures are reported in the contact's conversation.
+ */
+pub async fn safety_number<C: presage::store::Store>(
+    sink: &crate::sink::Sink,
+    manager: Option<&presage::Manager<C, presage::manager::Registered>>,
+    request_id: u64,
+    uuid: presage::libsignal_service::prelude::Uuid,
+) {
+    let result = match manager {
+        Some(manager) => compute_safety_number(manager, uuid).await,
+        None => Err(String::from("Not connected to Signal.")),
+    };
+    match result {
+        Ok((digits, scannable)) => {
+            sink.append(crate::structs::Event::SafetyNumber {
+                request_id: request_id,
+                who: uuid.to_string(),
+                digits: digits,
+                scannable: scannable,
+            });
+        }
+        Err(err) => {
+            tracing::warn!("{err}");
+            sink.append(crate::structs::Event::Text(crate::structs::Message {
+                request_id: request_id,
+                timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("Time went backwards").as_millis() as u64,
+                flags: 0x0200, // PURPLE_MESSAGE_ERROR
+                who: Some(uuid.to_string()),
+                body: err,
+                ..Default::default()
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn identity(byte: u8) -> presage::libsignal_service::protocol::IdentityKey {
+        // a key type byte followed by a Curve25519 public key
+        let mut serialized = [byte; 33];
+        serialized[0] = 0x05;
+        presage::libsignal_service::protocol::IdentityKey::decode(&serialized).unwrap()
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let dir = crate::testing::TestDir::new("identities-encrypted");
+        let store = dir.path("store");
+        let uuid = presage::libsignal_service::prelude::Uuid::from_u128(1);
+        {
+            let identities = super::Identities::open(&store, Some("correct horse"), false).unwrap();
+            identities.set(super::ACKNOWLEDGED, uuid, &identity(1)).unwrap();
+        }
+        let identities = super::Identities::open(&store, Some("correct horse"), false).unwrap();
+        assert_eq!(identities.get(super::ACKNOWLEDGED, uuid).unwrap(), Some(identity(1)));
+        assert_eq!(identities.get(super::REPORTED, uuid).unwrap(), None);
+    }
+
+    #[test]
+    fn encryption_must_match_the_store() {
+        let dir = crate::testing::TestDir::new("identities-mismatch");
+        let uuid = presage::libsignal_service::prelude::Uuid::from_u128(1);
+        let plain = dir.path("plain");
+        {
+            let identities = super::Identities::open(&plain, None, false).unwrap();
+            identities.set(super::ACKNOWLEDGED, uuid, &identity(1)).unwrap();
+        }
+        assert!(super::Identities::open(&plain, Some("correct horse"), false).is_err());
+        let encrypted = dir.path("encrypted");
+        drop(super::Identities::open(&encrypted, Some("correct horse"), false).unwrap());
+        assert!(super::Identities::open(&encrypted, None, false).is_err());
+        assert!(super::Identities::open(&encrypted, Some("wrong"), false).is_err());
+    }
+}
@@ -1,9 +1,21 @@
 #![no_main]
 
+#[cfg(feature = "purple")]
 mod bridge;
+#[cfg(feature = "purple")]
 mod bridge_commands;
+#[cfg(feature = "purple")]
+mod bridge_logging;
+mod attachment;
+mod backup;
 mod contacts;
-mod core;
+pub mod core;
+mod identity;
 mod receive;
+pub mod redact;
 mod send;
-mod structs;
+pub mod sink;
+mod store;
+pub mod structs;
+#[cfg(test)]
+mod testing;
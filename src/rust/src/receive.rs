@@ -1,6 +1,181 @@
 // TODO: rename to receive since it does also receive attachments
 
+use futures::AsyncReadExt; // for AsyncRead.read()
 use futures::StreamExt; // for Stream.next()
+use presage::libsignal_service::prelude::ProtobufMessage; // for AttachmentPointer.encode_to_vec()
+use std::io::Write; // for File.write_all()
+use tracing::Instrument; // for Future.instrument()
+
+/*
+ * Attachments are read from the network in pieces of up to this size.
+ */
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/*
+ * The front-end is told about the progress of a download no more often than this.
+ */
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/*
+ * How the back-end introduces itself to Signal's servers.
+ */
+const USER_AGENT: &str = "purple-presage";
+
+/*
+ * Fills in the conversation a message belongs to.
+ *
+ * `sent` is set for messages sent from another one of our devices (sync messages).
+ * For these, `who` is the recipient in case of a contact and left empty in case of a group.
+ */
+fn describe_thread<C: presage::store::Store>(
+    manager: &presage::Manager<C, presage::manager::Registered>,
+    content: &presage::libsignal_service::content::Content,
+    thread: &presage::store::Thread,
+    sent: bool,
+) -> crate::structs::Message {
+    let format_contact = |uuid| {
+        manager
+            .store()
+            .contact_by_id(uuid)
+            .ok()
+            .flatten()
+            .filter(|c| !c.name.is_empty())
+            .map(|c| c.name)
+            .unwrap_or_else(|| uuid.to_string())
+    };
+    let group_get_title = |key| manager.store().group(key).ok().flatten().map(|g| g.title).unwrap_or_else(|| "<missing group>".to_string());
+
+    let mut message = crate::structs::Message {
+        timestamp: content.metadata.timestamp,
+        ..Default::default()
+    };
+    match (sent, thread) {
+        // NOTE: for Spectrum, synced messages sent from other own device must set flags PURPLE_MESSAGE_SEND and PURPLE_MESSAGE_REMOTE_SEND
+        (false, presage::store::Thread::Contact(sender)) => {
+            message.flags = 0x0002; // PURPLE_MESSAGE_RECV
+            message.who = Some(sender.to_string());
+            message.name = Some(format_contact(sender));
+        }
+        (true, presage::store::Thread::Contact(recipient)) => {
+            message.flags = 0x0001 | 0x10000; // PURPLE_MESSAGE_SEND | PURPLE_MESSAGE_REMOTE_SEND
+            message.who = Some(recipient.to_string());
+        }
+        (false, presage::store::Thread::Group(key)) => {
+            message.flags = 0x0002; // PURPLE_MESSAGE_RECV
+            message.who = Some(content.metadata.sender.uuid.to_string());
+            message.name = Some(format_contact(&content.metadata.sender.uuid));
+            message.group = Some(hex::encode(key));
+            message.title = Some(group_get_title(*key));
+        }
+        (true, presage::store::Thread::Group(key)) => {
+            message.flags = 0x0001 | 0x10000; // PURPLE_MESSAGE_SEND | PURPLE_MESSAGE_REMOTE_SEND
+            message.group = Some(hex::encode(key));
+            message.title = Some(group_get_title(*key));
+        }
+    };
+    message
+}
+
+/*
+ * Tells the front-end that a contact started or stopped typing.
+ *
+ * Typing messages refer to a group by its identifier, so the master key is looked up among the known groups.
+ */
+fn forward_typing<C: presage::store::Store>(
+    manager: &presage::Manager<C, presage::manager::Registered>,
+    content: &presage::libsignal_service::content::Content,
+    typing_message: &presage::proto::TypingMessage,
+    sink: &crate::sink::Sink,
+) {
+    let group = match typing_message.group_id.as_deref() {
+        None => None,
+        Some(group_id) => {
+            let master_key = manager
+                .store()
+                .groups()
+                .ok()
+                .and_then(|groups| groups.flatten().map(|(master_key, _)| master_key).find(|master_key| crate::structs::group_identifier(master_key)[..] == *group_id));
+            let Some(master_key) = master_key else {
+                tracing::debug!("ignoring typing message for an unknown group");
+                return;
+            };
+            Some(hex::encode(master_key))
+        }
+    };
+    sink.append(crate::structs::Event::Typing {
+        who: content.metadata.sender.uuid.to_string(),
+        group: group,
+        started: typing_message.action() == presage::proto::typing_message::Action::Started,
+    });
+}
+
+/*
+ * Tells the front-end that a contact has received, read or viewed messages sent by the user.
+ *
+ * Receipts only carry the timestamps of the messages. The conversation is found by looking up the messages in the store,
+ * first in the direct conversation with the contact, then in the groups.
+ * Receipts for messages which are not in the store are ignored.
+ */
+fn forward_receipts<C: presage::store::Store>(
+    manager: &presage::Manager<C, presage::manager::Registered>,
+    content: &presage::libsignal_service::content::Content,
+    receipt_message: &presage::proto::ReceiptMessage,
+    sink: &crate::sink::Sink,
+) {
+    let kind = match receipt_message.r#type() {
+        presage::proto::receipt_message::Type::Delivery => crate::structs::ReceiptKind::Delivered,
+        presage::proto::receipt_message::Type::Read => crate::structs::ReceiptKind::Read,
+        presage::proto::receipt_message::Type::Viewed => crate::structs::ReceiptKind::Viewed,
+    };
+    let sender = content.metadata.sender.uuid;
+    let threads: Vec<presage::store::Thread> = std::iter::once(presage::store::Thread::Contact(sender))
+        .chain(manager.store().groups().into_iter().flat_map(|groups| groups.flatten().map(|(master_key, _)| presage::store::Thread::Group(master_key))))
+        .collect();
+    for timestamp in &receipt_message.timestamp {
+        let found = threads.iter().find_map(|thread| manager.store().message(thread, *timestamp).ok().flatten().map(|message| (thread, message)));
+        let Some((thread, message)) = found else {
+            tracing::debug!("ignoring receipt for unknown message {timestamp}");
+            continue;
+        };
+        let body = match message.body {
+            presage::libsignal_service::content::ContentBody::DataMessage(presage::libsignal_service::content::DataMessage {
+                body: Some(body), ..
+            })
+            | presage::libsignal_service::content::ContentBody::SynchronizeMessage(presage::libsignal_service::content::SyncMessage {
+                sent:
+                    Some(presage::proto::sync_message::Sent {
+                        message: Some(presage::libsignal_service::content::DataMessage {
+                            body: Some(body), ..
+                        }),
+                        ..
+                    }),
+                ..
+            }) => body.split("\n").next().map(str::to_string),
+            _ => None,
+        };
+        // the receipt is from the contact, so this is described like a message received from them
+        let described = describe_thread(manager, content, thread, false);
+        sink.append(crate::structs::Event::Receipt {
+            kind: kind,
+            timestamp: *timestamp,
+            who: sender.to_string(),
+            name: described.name,
+            group: described.group,
+            title: described.title,
+            body: body,
+        });
+    }
+}
+
+/*
+ * The kind of a message, for logging.
+ *
+ * The contents are left out. They contain keys (e.g. of attachments, profiles and groups) and personal data.
+ */
+fn kind_of(body: &presage::libsignal_service::content::ContentBody) -> String {
+    let debug = format!("{body:?}");
+    debug.split(['(', ' ', '{']).next().unwrap_or_default().to_string()
+}
 
 /*
  * Prepares a received message's text for the front-end.
@@ -10,11 +185,11 @@ use futures::StreamExt; // for Stream.next()
 fn print_message<C: presage::store::Store>(
     manager: &presage::Manager<C, presage::manager::Registered>,
     content: &presage::libsignal_service::content::Content,
-    account: *const std::os::raw::c_void,
+    sink: &crate::sink::Sink,
 ) {
-    crate::core::purple_debug(account, 2, String::from("print_message called…\n"));
+    tracing::trace!("print_message called…");
     let Ok(thread) = presage::store::Thread::try_from(content) else {
-        crate::core::purple_error(account, 16, String::from("failed to derive thread from content"));
+        sink.error(16, String::from("failed to derive thread from content"));
         return;
     };
 
@@ -80,7 +255,7 @@ fn print_message<C: presage::store::Store>(
             } => Some(body.to_string()),
             // Default (catch all other cases)
             c => {
-                crate::core::purple_debug(account, 2, format!("DataMessage without body {c:?}\n"));
+                tracing::debug!("DataMessage without body, sent at {:?} with {} attachments", c.timestamp, c.attachments.len());
                 // NOTE: This happens when receiving a file, but not providing a text
                 // TODO: suppress this debug message if data message contained an attachment
                 // NOTE: flags: Some(4) with a timestamp (and a profile_key?) may indicate "message sent"
@@ -90,18 +265,6 @@ fn print_message<C: presage::store::Store>(
         }
     };
 
-    let format_contact = |uuid| {
-        manager
-            .store()
-            .contact_by_id(uuid)
-            .ok()
-            .flatten()
-            .filter(|c| !c.name.is_empty())
-            .map(|c| c.name)
-            .unwrap_or_else(|| uuid.to_string())
-    };
-    let group_get_title = |key| manager.store().group(key).ok().flatten().map(|g| g.title).unwrap_or_else(|| "<missing group>".to_string());
-
     enum Msg<'a> {
         Received(&'a presage::store::Thread, String),
         Sent(&'a presage::store::Thread, String),
@@ -118,59 +281,58 @@ fn print_message<C: presage::store::Store>(
             ..
         }) => format_data_message(&thread, data_message).map(|body| Msg::Sent(&thread, body)),
         presage::libsignal_service::content::ContentBody::CallMessage(_) => Some(Msg::Received(&thread, "is calling!".into())),
-        // TODO: forward these properly
-        presage::libsignal_service::content::ContentBody::TypingMessage(_) => None, //Some(Msg::Received(&thread, "is typing...".into())), // too annyoing for now. also does not differentiate between "started typing" and "stopped typing"
-        presage::libsignal_service::content::ContentBody::ReceiptMessage(_) => None, //Some(Msg::Received(&thread, "received a message.".into())), // works, but too annyoing for now
+        presage::libsignal_service::content::ContentBody::TypingMessage(typing_message) => {
+            forward_typing(manager, content, typing_message, sink);
+            None
+        }
+        presage::libsignal_service::content::ContentBody::ReceiptMessage(receipt_message) => {
+            forward_receipts(manager, content, receipt_message, sink);
+            None
+        }
         c => {
-            crate::core::purple_debug(account, 2, format!("Unsupported message {c:?}\n"));
+            tracing::debug!("Unsupported message {} sent at {}", kind_of(c), content.metadata.timestamp);
             None
         }
     } {
-        let mut message = crate::bridge::Presage::from_account(account);
-        message.timestamp = content.metadata.timestamp;
-        match msg {
-            // NOTE: for Spectrum, synced messages sent from other own device must set flags PURPLE_MESSAGE_SEND and PURPLE_MESSAGE_REMOTE_SEND
-            Msg::Received(presage::store::Thread::Contact(sender), body) => {
-                message.flags = 0x0002; // PURPLE_MESSAGE_RECV
-                message.who = std::ffi::CString::new(sender.to_string()).unwrap().into_raw();
-                message.name = std::ffi::CString::new(format_contact(sender)).unwrap().into_raw();
-                message.body = std::ffi::CString::new(body).unwrap().into_raw();
-            }
-            Msg::Sent(presage::store::Thread::Contact(recipient), body) => {
-                message.flags = 0x0001 | 0x10000; // PURPLE_MESSAGE_SEND | PURPLE_MESSAGE_REMOTE_SEND
-                message.who = std::ffi::CString::new(recipient.to_string()).unwrap().into_raw();
-                message.body = std::ffi::CString::new(body).unwrap().into_raw();
-            }
-            Msg::Received(presage::store::Thread::Group(key), body) => {
-                message.flags = 0x0002; // PURPLE_MESSAGE_RECV
-                message.who = std::ffi::CString::new(content.metadata.sender.uuid.to_string()).unwrap().into_raw();
-                message.name = std::ffi::CString::new(format_contact(&content.metadata.sender.uuid)).unwrap().into_raw();
-                message.group = std::ffi::CString::new(hex::encode(key)).unwrap().into_raw();
-                message.title = std::ffi::CString::new(group_get_title(*key)).unwrap().into_raw();
-                message.body = std::ffi::CString::new(body).unwrap().into_raw();
-            }
-            Msg::Sent(presage::store::Thread::Group(key), body) => {
-                message.flags = 0x0001 | 0x10000; // PURPLE_MESSAGE_SEND | PURPLE_MESSAGE_REMOTE_SEND
-                message.group = std::ffi::CString::new(hex::encode(key)).unwrap().into_raw();
-                message.title = std::ffi::CString::new(group_get_title(*key)).unwrap().into_raw();
-                message.body = std::ffi::CString::new(body).unwrap().into_raw();
-            }
+        let (sent, thread, body) = match msg {
+            Msg::Received(thread, body) => (false, thread, body),
+            Msg::Sent(thread, body) => (true, thread, body),
         };
-        crate::bridge::append_message(&message);
+        let mut message = describe_thread(manager, content, thread, sent);
+        message.body = body;
+        sink.append(crate::structs::Event::Text(message));
+    }
+}
+
+/*
+ * Reduces a file name chosen by the sender to its last component so it cannot point to another directory.
+ *
+ * Returns None if nothing usable remains.
+ */
+fn sanitize_file_name(file_name: &str) -> Option<String> {
+    let last = file_name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or_default();
+    let name: String = last.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name.to_string())
     }
 }
 
 /*
  * Prepares a received message (text and attachments) for further processing.
  *
+ * Attachments are not downloaded here. The front-end asks for them with `Cmd::DownloadAttachment`.
+ *
  * Based on presage-cli's `process_incoming_message`.
  */
-async fn process_incoming_message<C: presage::store::Store>(
-    manager: &mut presage::Manager<C, presage::manager::Registered>,
+fn process_incoming_message<C: presage::store::Store>(
+    manager: &presage::Manager<C, presage::manager::Registered>,
     content: &presage::libsignal_service::content::Content,
-    account: *const std::os::raw::c_void,
+    sink: &crate::sink::Sink,
 ) {
-    print_message(manager, content, account);
+    print_message(manager, content, sink);
 
     if let presage::libsignal_service::content::ContentBody::DataMessage(presage::libsignal_service::content::DataMessage { attachments, .. })
     | presage::libsignal_service::content::ContentBody::SynchronizeMessage(presage::libsignal_service::content::SyncMessage {
@@ -181,21 +343,12 @@ async fn process_incoming_message<C: presage::store::Store>(
         ..
     }) = &content.body
     {
+        let Ok(thread) = presage::store::Thread::try_from(content) else {
+            sink.error(16, String::from("failed to derive thread from content"));
+            return;
+        };
+        let sent = matches!(content.body, presage::libsignal_service::content::ContentBody::SynchronizeMessage(_));
         for attachment_pointer in attachments {
-            let mut message = crate::bridge::Presage::from_account(account);
-            message.timestamp = content.metadata.timestamp;
-            // TODO: `who` and `group` should be filled with the Receiver (group or contact) information
-            // so they end up in the correct conversation
-            // relevant for sync messages in particular
-            message.who = std::ffi::CString::new(content.metadata.sender.uuid.to_string()).unwrap().into_raw();
-
-            let Ok(attachment_data) = manager.get_attachment(attachment_pointer).await else {
-                message.flags = 0x0200; // PURPLE_MESSAGE_ERROR
-                message.body = std::ffi::CString::new(String::from("Failed to fetch attachment.")).unwrap().into_raw();
-                crate::bridge::append_message(&message);
-                continue;
-            };
-
             let mimetype = attachment_pointer.content_type.as_deref().unwrap_or("application/octet-stream");
             let extension = match mimetype {
                 "image/jpeg" => "jpg",
@@ -207,45 +360,272 @@ async fn process_incoming_message<C: presage::store::Store>(
                 }
             };
 
-            let filename = match attachment_pointer.attachment_identifier.clone().unwrap() {
-                presage::proto::attachment_pointer::AttachmentIdentifier::CdnId(id) => id.to_string(),
-                presage::proto::attachment_pointer::AttachmentIdentifier::CdnKey(key) => key,
+            let Some(attachment_identifier) = attachment_pointer.attachment_identifier.clone() else {
+                let mut message = describe_thread(manager, content, &thread, sent);
+                message.flags |= 0x0200; // PURPLE_MESSAGE_ERROR
+                message.body = String::from("Received an attachment which cannot be fetched.");
+                sink.append(crate::structs::Event::Text(message));
+                continue;
+            };
+            // prefer the name the sender has chosen, fall back to the identifier on the server
+            let filename = attachment_pointer.file_name.as_deref().and_then(sanitize_file_name).unwrap_or_else(|| {
+                let identifier = match attachment_identifier {
+                    presage::proto::attachment_pointer::AttachmentIdentifier::CdnId(id) => id.to_string(),
+                    presage::proto::attachment_pointer::AttachmentIdentifier::CdnKey(key) => key,
+                };
+                format!("{identifier}.{extension}")
+            });
+
+            if let Some(caption) = attachment_pointer.caption.as_ref().filter(|caption| !caption.is_empty()) {
+                let mut message = describe_thread(manager, content, &thread, sent);
+                message.body = caption.clone();
+                sink.append(crate::structs::Event::Text(message));
+            }
+
+            let message = describe_thread(manager, content, &thread, sent);
+            sink.append(crate::structs::Event::Attachment(crate::structs::Attachment {
+                timestamp: message.timestamp,
+                flags: message.flags,
+                who: message.who,
+                name: message.name,
+                group: message.group,
+                title: message.title,
+                filename: filename,
+                content_type: mimetype.to_string(),
+                size: attachment_pointer.size() as u64,
+                width: attachment_pointer.width(),
+                height: attachment_pointer.height(),
+                voice_note: attachment_pointer.flags() & presage::proto::attachment_pointer::Flags::VoiceMessage as u32 != 0,
+                thumbnail: attachment_pointer.thumbnail.clone().unwrap_or_default(),
+                pointer: attachment_pointer.encode_to_vec(),
+            }));
+        }
+    }
+}
+
+/*
+ * A download which has not finished, yet.
+ *
+ * The file is removed unless the download is finished with `keep`.
+ * This also happens if the task doing the download is aborted.
+ */
+struct PartialFile {
+    path: std::path::PathBuf,
+    file: Option<std::fs::File>,
+}
+
+/*
+ * Inserts a number before the extension, e.g. "photo (2).jpg".
+ */
+fn numbered(
+    path: &std::path::Path,
+    number: usize,
+) -> std::path::PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!("{stem} ({number}).{}", extension.to_string_lossy())),
+        None => path.with_file_name(format!("{stem} ({number})")),
+    }
+}
+
+impl PartialFile {
+    /*
+     * Creates a new file at `destination` or a temporary file if there is no destination.
+     *
+     * Existing files are never touched. A number is added to the name instead.
+     */
+    fn create(destination: Option<&std::path::Path>) -> std::io::Result<Self> {
+        if let Some(parent) = destination.and_then(std::path::Path::parent) {
+            std::fs::create_dir_all(parent)?;
+        }
+        for attempt in 0..100 {
+            let path = match destination {
+                // the name must not be guessable since the directory is shared with other users
+                None => std::env::temp_dir().join(format!("purple-presage-{:016x}.part", rand::random::<u64>())),
+                Some(destination) if attempt == 0 => destination.to_path_buf(),
+                Some(destination) => numbered(destination, attempt + 1),
             };
-            message.name = std::ffi::CString::new(format!("{filename}.{extension}")).unwrap().into_raw();
-            let boxed_slice = attachment_data.into_boxed_slice();
-            message.size = boxed_slice.len() as u64; // TODO: blobsize should be a C type compatible with usize
-            message.blob = Box::into_raw(boxed_slice) as *const std::os::raw::c_uchar;
-            crate::bridge::append_message(&message);
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    return Ok(Self {
+                        path: path,
+                        file: Some(file),
+                    })
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "too many files with the same name"))
+    }
+
+    fn write_all(
+        &mut self,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        self.file.as_mut().expect("file must be open").write_all(data)
+    }
+
+    /*
+     * Closes the file and hands it over to the caller.
+     */
+    fn keep(mut self) -> std::path::PathBuf {
+        self.file = None;
+        std::mem::take(&mut self.path)
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.path);
         }
     }
 }
 
+/*
+ * Downloads an attachment into a file.
+ *
+ * The attachment is decrypted and written while it is being downloaded, so it is never in memory as a whole.
+ * `progress` is told how many bytes of the file have been written so far, at most every PROGRESS_INTERVAL.
+ * If anything goes wrong (including the integrity check at the very end), the file is removed. The same happens if the task is aborted.
+ *
+ * presage's `Manager::get_attachment` cannot be used for this: it reads the entire attachment into memory
+ * and decrypts it there (libsignal-service's `decrypt_in_place` needs all of it at once).
+ * So the encrypted attachment is fetched from the CDN with a push service of its own and decrypted by `crate::attachment::Decryptor`.
+ */
+async fn download_to_file<C: presage::store::Store>(
+    manager: Option<&presage::Manager<C, presage::manager::Registered>>,
+    pointer: &presage::proto::AttachmentPointer,
+    destination: Option<&std::path::Path>,
+    progress: impl Fn(usize),
+) -> Result<(std::path::PathBuf, usize), String> {
+    let manager = manager.ok_or_else(|| String::from("Not connected to Signal."))?;
+    let mut decryptor = crate::attachment::Decryptor::new(pointer.key(), pointer.digest.as_deref(), pointer.size.map(|size| size as usize))?;
+    // no credentials are needed for the CDN
+    let mut service = presage::libsignal_service::push_service::PushService::new(manager.registration_data().signal_servers, None, USER_AGENT);
+    let mut stream = service.get_attachment(pointer).await.map_err(|err| format!("Unable to fetch the attachment: {err}"))?;
+    let mut file = PartialFile::create(destination).map_err(|err| format!("Unable to create the file: {err}"))?;
+    let mut buffer = vec![0u8; DOWNLOAD_CHUNK_SIZE];
+    let mut written = 0;
+    let mut reported = std::time::Instant::now();
+    loop {
+        // this is also where an abort takes effect
+        let length = stream.read(&mut buffer).await.map_err(|err| format!("Unable to fetch the attachment: {err}"))?;
+        if length == 0 {
+            break;
+        }
+        let plaintext = decryptor.update(&buffer[..length]);
+        file.write_all(&plaintext).map_err(|err| format!("Unable to write the attachment: {err}"))?;
+        written += plaintext.len();
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            progress(written);
+            reported = std::time::Instant::now();
+        }
+    }
+    let plaintext = decryptor.finish()?;
+    file.write_all(&plaintext).map_err(|err| format!("Unable to write the attachment: {err}"))?;
+    written += plaintext.len();
+    Ok((file.keep(), written))
+}
+
+/*
+ * Downloads an attachment for the front-end.
+ *
+ * If there is no destination, the front-end takes ownership of the temporary file.
+ * The actual path may differ from the destination in case a file with that name exists already.
+ * Without a session, the download fails. Either way, the front-end learns about the outcome.
+ */
+pub async fn download<C: presage::store::Store>(
+    manager: Option<&presage::Manager<C, presage::manager::Registered>>,
+    request_id: u64,
+    pointer: &presage::proto::AttachmentPointer,
+    destination: Option<&str>,
+    sink: &crate::sink::Sink,
+) {
+    let progress = |size| {
+        sink.append(crate::structs::Event::DownloadProgress {
+            request_id: request_id,
+            size: size,
+        })
+    };
+    let event = match download_to_file(manager, pointer, destination.map(std::path::Path::new), progress).await {
+        Ok((path, size)) => {
+            tracing::debug!("downloaded {size} bytes");
+            crate::structs::Event::AttachmentDownloaded {
+                request_id: request_id,
+                path: Some(path.to_string_lossy().into_owned()),
+                temporary: destination.is_none(),
+                size: size,
+                error: None,
+            }
+        }
+        Err(error) => {
+            tracing::warn!("{error}");
+            crate::structs::Event::AttachmentDownloaded {
+                request_id: request_id,
+                path: None,
+                temporary: false,
+                size: 0,
+                error: Some(error),
+            }
+        }
+    };
+    sink.append(event);
+}
+
 /*
  * Receives messages from Signal servers.
  *
  * Blocks forever.
+ * Changed identities of senders are reported, see `crate::identity::check`.
  *
  * Based on presage-cli's `receive`.
  */
 pub async fn receive<C: presage::store::Store>(
     manager: &mut presage::Manager<C, presage::manager::Registered>,
-    account: *const std::os::raw::c_void,
+    identities: &crate::identity::Identities,
+    sink: &crate::sink::Sink,
 ) {
-    //crate::core::purple_debug(account, 2, String::from("receive on separate thread begins…\n"));
+    tracing::debug!("receive begins…");
+    let own_uuid = manager.registration_data().service_ids.aci;
     let messages = manager.receive_messages(presage::manager::ReceivingMode::Forever).await;
     match messages {
         Ok(messages) => {
-            //crate::core::purple_debug(account, 2, String::from("receive got messages\n"));
+            tracing::debug!("receive got messages");
             futures::pin_mut!(messages);
             while let Some(content) = messages.next().await {
                 // NOTE: This blocks until there is a message to be handled. Blocking forever seems to be by design.
-                //crate::core::purple_debug(account, 2, String::from("receive got a message's content\n"));
-                process_incoming_message(manager, &content, account).await;
+                let span = tracing::debug_span!("envelope", timestamp = content.metadata.timestamp, sender = %content.metadata.sender.uuid);
+                tracing::trace!(parent: &span, "receive got a message's content");
+                span.in_scope(|| process_incoming_message(manager, &content, sink));
+                // receiving a message may have brought a new identity of the sender
+                let sender = content.metadata.sender.uuid;
+                if sender != own_uuid {
+                    if let Err(err) = crate::identity::check(manager, sender, identities, sink).instrument(span).await {
+                        tracing::warn!("{err}");
+                    }
+                }
             }
         }
         Err(err) => {
-            crate::core::purple_error(account, 16, err.to_string());
+            sink.error(16, err.to_string());
         }
     }
-    crate::core::purple_error(account, 0, String::from("Receiver has finished. Disconnected?"));
+    sink.error(0, String::from("Receiver has finished. Disconnected?"));
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn kind_leaves_out_the_contents() {
+        let body = presage::libsignal_service::content::ContentBody::DataMessage(presage::libsignal_service::content::DataMessage {
+            body: Some(String::from("Hello")),
+            profile_key: Some(vec![0xab; 32]),
+            ..Default::default()
+        });
+        assert_eq!(super::kind_of(&body), "DataMessage");
+        let body = presage::libsignal_service::content::ContentBody::NullMessage(Default::default());
+        assert_eq!(super::kind_of(&body), "NullMessage");
+    }
 }
@@ -0,0 +1,62 @@
+/*
+ * Removes personal data from diagnostics so debug logs can be shared in bug reports.
+ *
+ * - uuids are replaced by a short hash, so the same uuid can still be recognized throughout a log,
+ * - phone numbers are masked except for the first and last two digits,
+ * - message bodies, titles and names in debug dumps are elided.
+ */
+pub fn redact(text: &str) -> String {
+    static PATTERNS: std::sync::OnceLock<[regex::Regex; 4]> = std::sync::OnceLock::new();
+    let [uuid, phone_number, national_number, content] = PATTERNS.get_or_init(|| {
+        [
+            regex::Regex::new(r"(?i)\b[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}\b").unwrap(),
+            regex::Regex::new(r"\+\d{6,15}\b").unwrap(),
+            regex::Regex::new(r"NationalNumber \{ value: \d+ \}").unwrap(),
+            regex::Regex::new(r#"\b(body|text|title|description|caption|name|file_name): Some\("(?:[^"\\]|\\.)*"\)"#).unwrap(),
+        ]
+    });
+    let text = uuid.replace_all(text, |captures: &regex::Captures| {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hash::hash(&captures[0].to_lowercase(), &mut hasher);
+        format!("uuid#{:08x}", std::hash::Hasher::finish(&hasher) as u32)
+    });
+    let text = phone_number.replace_all(&text, |captures: &regex::Captures| {
+        let digits = &captures[0];
+        format!("{}{}{}", &digits[..3], "*".repeat(digits.len() - 5), &digits[digits.len() - 2..])
+    });
+    let text = national_number.replace_all(&text, "NationalNumber { value: *** }");
+    let text = content.replace_all(&text, "$1: Some(<elided>)");
+    text.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn uuids_are_replaced_by_a_stable_hash() {
+        let redacted = super::redact("from 0b7d4c5e-1a2b-4c3d-8e9f-0123456789ab to 0B7D4C5E-1A2B-4C3D-8E9F-0123456789AB");
+        assert!(!redacted.contains("0123456789"), "{redacted}");
+        let hashes: Vec<&str> = redacted.split(' ').filter(|word| word.starts_with("uuid#")).collect();
+        assert_eq!(hashes.len(), 2, "{redacted}");
+        assert_eq!(hashes[0], hashes[1], "the same uuid must be recognizable regardless of case");
+        assert_ne!(super::redact("fd0e2a57-6d1c-4f0e-9a3b-c1d2e3f4a5b6"), hashes[0], "different uuids must not look the same");
+    }
+
+    #[test]
+    fn phone_numbers_are_masked() {
+        assert_eq!(super::redact("phone_number: +4915112345678,"), "phone_number: +49*********78,");
+        assert_eq!(super::redact("NationalNumber { value: 15112345678 }"), "NationalNumber { value: *** }");
+        // too short for a phone number
+        assert_eq!(super::redact("+12345"), "+12345");
+    }
+
+    #[test]
+    fn contents_are_elided() {
+        assert_eq!(
+            super::redact(r#"DataMessage { body: Some("Hello \"you\""), title: Some("Holiday"), timestamp: Some(1700000000000) }"#),
+            "DataMessage { body: Some(<elided>), title: Some(<elided>), timestamp: Some(1700000000000) }"
+        );
+        assert_eq!(super::redact("body: None"), "body: None");
+        // only whole field names
+        assert_eq!(super::redact(r#"nobody: Some("x")"#), r#"nobody: Some("x")"#);
+    }
+}
@@ -1,36 +1,51 @@
 use mime_sniffer::MimeTypeSniffer;
 
+/*
+ * Reads a file and uploads it as an attachment.
+ *
+ * Failures are described in a way suitable for the user.
+ */
+pub async fn upload_attachment<C: presage::store::Store + 'static>(
+    manager: &mut presage::Manager<C, presage::manager::Registered>,
+    path: &str,
+) -> Result<presage::proto::AttachmentPointer, String> {
+    let blob = std::fs::read(path).map_err(|err| format!("Unable to read {path}: {err}"))?;
+    // not every file type is known to the sniffer, fall back to the file name and then to the generic type
+    let content_type = blob
+        .sniff_mime_type()
+        .map(str::to_owned)
+        .or_else(|| mime_guess::from_path(path).first().map(|mime| mime.to_string()))
+        .unwrap_or_else(|| String::from("application/octet-stream"));
+    let attachment = make_attachment(blob, content_type, std::path::PathBuf::from(path));
+    let mut pointers = manager.upload_attachments(vec![attachment]).await.map_err(|err| format!("Unable to upload the attachment: {err}"))?;
+    match pointers.pop() {
+        Some(Ok(pointer)) => Ok(pointer),
+        Some(Err(err)) => Err(format!("Unable to upload the attachment: {err}")),
+        None => Err(String::from("Unable to upload the attachment: The server did not answer.")),
+    }
+}
+
 /*
  * Sends a text message to a contact identified by their uuid or to a group identified by its key.
  *
+ * An attachment must have been uploaded before, see `upload_attachment`.
+ * The timestamp identifies the message, e.g. in receipts.
+ *
  * Taken from presage-cli
  */
 pub async fn send<C: presage::store::Store + 'static>(
     manager: &mut presage::Manager<C, presage::manager::Registered>,
     recipient: crate::structs::Recipient,
     body: Option<String>,
-    xfer: *const std::os::raw::c_void,
+    attachment: Option<presage::proto::AttachmentPointer>,
+    timestamp: u64,
 ) -> Result<(), presage::Error<<C>::Error>> {
-    let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("Time went backwards").as_millis() as u64;
     let mut data_message = presage::libsignal_service::content::DataMessage {
         timestamp: Some(timestamp),
+        attachments: attachment.into_iter().collect(),
         ..Default::default()
     };
 
-    if xfer != std::ptr::null() {
-        let path = crate::bridge::xfer_get_local_filename(xfer);
-        let blob = std::fs::read(path.clone()).expect("Unable to read file.");
-        let content_type = blob.sniff_mime_type().expect("Unable to guess content type.");
-        let attachment = make_attachment(blob.clone(), content_type.to_string(), std::path::PathBuf::from(path));
-        let upload_attachments_result = manager.upload_attachments(vec![attachment]).await?;
-        let pointer = upload_attachments_result
-            .first()
-            .expect("At least one attachment pointer should be available")
-            .as_ref()
-            .expect("Failed to upload attachments"); // TODO: fail less hard if this happens
-        data_message.attachments.push(pointer.clone());
-    }
-
     data_message.body = body;
     match recipient {
         crate::structs::Recipient::Contact(uuid) => {
@@ -57,6 +72,45 @@ pub async fn send<C: presage::store::Store + 'static>(
     Ok(())
 }
 
+/*
+ * Tells a contact or a group that the user started or stopped typing.
+ */
+pub async fn send_typing<C: presage::store::Store + 'static>(
+    manager: &mut presage::Manager<C, presage::manager::Registered>,
+    recipient: crate::structs::Recipient,
+    started: bool,
+) -> Result<(), presage::Error<<C>::Error>> {
+    let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("Time went backwards").as_millis() as u64;
+    let action = if started {
+        presage::proto::typing_message::Action::Started
+    } else {
+        presage::proto::typing_message::Action::Stopped
+    };
+    let mut typing_message = presage::proto::TypingMessage {
+        timestamp: Some(timestamp),
+        action: Some(action as i32),
+        group_id: None,
+    };
+    match recipient {
+        crate::structs::Recipient::Contact(uuid) => {
+            manager
+                .send_message(
+                    presage::libsignal_service::ServiceAddress::new_aci(uuid),
+                    presage::libsignal_service::content::ContentBody::TypingMessage(typing_message),
+                    timestamp,
+                )
+                .await?;
+        }
+        crate::structs::Recipient::Group(master_key) => {
+            typing_message.group_id = Some(crate::structs::group_identifier(&master_key).to_vec());
+            manager
+                .send_message_to_group(&master_key, presage::libsignal_service::content::ContentBody::TypingMessage(typing_message), timestamp)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
 /*
  * Constructs the AttachmentSpec out of bytes
  *
@@ -0,0 +1,121 @@
+/*
+ * Receives the events produced by the back-end.
+ *
+ * The core does not know which front-end it is talking to.
+ * libpurple is served by `crate::bridge::PurpleSink`. Other front-ends (or tests) bring their own implementation.
+ */
+pub trait EventSink {
+    fn append(
+        &self,
+        event: crate::structs::Event,
+    );
+
+    /*
+    Look at these debug levels from Purple:
+
+    typedef enum
+    {
+        PURPLE_DEBUG_ALL = 0,  /**< All debug levels.              */
+        PURPLE_DEBUG_MISC,     /**< General chatter.               */
+        PURPLE_DEBUG_INFO,     /**< General operation Information. */
+        PURPLE_DEBUG_WARNING,  /**< Warnings.                      */
+        PURPLE_DEBUG_ERROR,    /**< Errors.                        */
+        PURPLE_DEBUG_FATAL     /**< Fatal errors.                  */
+    } PurpleDebugLevel;
+
+    TODO: Automatically convert from libpurple/debug.h.
+    */
+    fn debug(
+        &self,
+        level: i32,
+        message: String,
+    ) {
+        self.append(crate::structs::Event::Log { level, message });
+    }
+
+    /*
+    Look at these error levels from Purple:
+
+    typedef enum
+    {
+        PURPLE_CONNECTION_ERROR_NETWORK_ERROR = 0,
+        PURPLE_CONNECTION_ERROR_INVALID_USERNAME = 1,
+        PURPLE_CONNECTION_ERROR_AUTHENTICATION_FAILED = 2,
+        PURPLE_CONNECTION_ERROR_AUTHENTICATION_IMPOSSIBLE = 3,
+        PURPLE_CONNECTION_ERROR_NO_SSL_SUPPORT = 4,
+        PURPLE_CONNECTION_ERROR_ENCRYPTION_ERROR = 5,
+        PURPLE_CONNECTION_ERROR_NAME_IN_USE = 6,
+        PURPLE_CONNECTION_ERROR_INVALID_SETTINGS = 7,
+        PURPLE_CONNECTION_ERROR_CERT_NOT_PROVIDED = 8,
+        PURPLE_CONNECTION_ERROR_CERT_UNTRUSTED = 9,
+        PURPLE_CONNECTION_ERROR_CERT_EXPIRED = 10,
+        PURPLE_CONNECTION_ERROR_CERT_NOT_ACTIVATED = 11,
+        PURPLE_CONNECTION_ERROR_CERT_HOSTNAME_MISMATCH = 12,
+        PURPLE_CONNECTION_ERROR_CERT_FINGERPRINT_MISMATCH = 13,
+        PURPLE_CONNECTION_ERROR_CERT_SELF_SIGNED = 14,
+        PURPLE_CONNECTION_ERROR_CERT_OTHER_ERROR = 15,
+        PURPLE_CONNECTION_ERROR_OTHER_ERROR = 16
+    } PurpleConnectionError;
+
+    TODO: Automatically convert from libpurple/connection.h.
+    */
+    fn error(
+        &self,
+        level: i32,
+        message: String,
+    ) {
+        self.append(crate::structs::Event::Error { request_id: None, level, message });
+    }
+
+    /*
+     * Reports that a request failed. Only the request is affected, the connection stays up.
+     */
+    fn request_error(
+        &self,
+        request_id: u64,
+        level: i32,
+        message: String,
+    ) {
+        self.append(crate::structs::Event::Error {
+            request_id: Some(request_id),
+            level,
+            message,
+        });
+    }
+}
+
+/*
+ * The sink as it is handed around in the core.
+ *
+ * All back-end tasks live in the same tokio LocalSet, so reference counting need not be atomic.
+ */
+pub type Sink = std::rc::Rc<dyn EventSink>;
+
+/*
+ * Keeps the events for inspection by tests.
+ */
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingSink {
+    events: std::cell::RefCell<Vec<crate::structs::Event>>,
+}
+
+#[cfg(test)]
+impl RecordingSink {
+    /*
+     * Hands out the events recorded so far and forgets about them.
+     */
+    pub fn take(&self) -> Vec<crate::structs::Event> {
+        self.events.take()
+    }
+}
+
+#[cfg(test)]
+impl EventSink for RecordingSink {
+    fn append(
+        &self,
+        event: crate::structs::Event,
+    ) {
+        self.events.borrow_mut().push(event);
+    }
+}
@@ -0,0 +1,148 @@
+/*
+ * Maintenance of the on-disk store which cannot be done while it is open.
+ */
+
+pub const STORE_CIPHER_KEY: &str = "store_cipher"; // taken from presage_store_sled
+
+/*
+ * Checks whether the store is encrypted as the account's settings say.
+ *
+ * Opening a store with the wrong setting fails somewhere within presage_store_sled, with a message which does not help the user.
+ * A store which does not exist yet is fine either way.
+ */
+pub fn check_encryption(
+    store_path: &str,
+    passphrase: Option<&str>,
+) -> Result<(), String> {
+    if !std::path::Path::new(store_path).join("db").exists() {
+        return Ok(());
+    }
+    let db = sled::open(store_path).map_err(|err| format!("Unable to open the store: {err}"))?;
+    let encrypted = db.contains_key(STORE_CIPHER_KEY).map_err(|err| format!("Unable to read the store's key: {err}"))?;
+    match (encrypted, passphrase) {
+        (false, Some(_)) => Err(String::from("The local store is not encrypted, but a passphrase has been given. Encryption can only be enabled for a new store. Please disable encryption of the local store in the account's settings.")),
+        (true, None) => Err(String::from("The local store is encrypted. Please enable encryption of the local store in the account's settings and enter its passphrase on login.")),
+        _ => Ok(()),
+    }
+}
+
+/*
+ * Re-encrypts the key of an encrypted database with a new passphrase.
+ *
+ * Returns the key as it is to be written, nothing if the database is not encrypted.
+ */
+fn reencrypt_key(
+    db: &sled::Db,
+    old_passphrase: &str,
+    new_passphrase: &str,
+) -> Result<Option<Vec<u8>>, String> {
+    let Some(exported) = db.get(STORE_CIPHER_KEY).map_err(|err| format!("Unable to read the store's key: {err}"))? else {
+        return Ok(None);
+    };
+    let cipher = presage_store_cipher::StoreCipher::import(old_passphrase, &exported).map_err(|err| format!("Unable to unlock the store: {err}"))?;
+    let exported = cipher.export(new_passphrase).map_err(|err| format!("Unable to encrypt the store's key: {err}"))?;
+    Ok(Some(exported))
+}
+
+fn write_key(
+    db: &sled::Db,
+    exported: Vec<u8>,
+) -> Result<(), String> {
+    db.insert(STORE_CIPHER_KEY, exported).map_err(|err| format!("Unable to write the store's key: {err}"))?;
+    db.flush().map_err(|err| format!("Unable to write the store's key: {err}"))?;
+    Ok(())
+}
+
+/*
+ * Encrypts the store's key with a new passphrase.
+ *
+ * The store must be closed. Only the key needs to be re-encrypted, the data stays as it is.
+ * The same goes for the identities kept next to the store, see `crate::identity::Identities`.
+ * Based on what presage_store_sled does when opening an encrypted store.
+ */
+pub fn change_passphrase(
+    store_path: &str,
+    old_passphrase: Option<&str>,
+    new_passphrase: &str,
+) -> Result<(), String> {
+    let Some(old_passphrase) = old_passphrase else {
+        return Err(String::from("The store is not encrypted. Encryption can only be enabled for a new store."));
+    };
+    let db = sled::open(store_path).map_err(|err| format!("Unable to open the store: {err}"))?;
+    let identities = sled::open(crate::identity::path(store_path)).map_err(|err| format!("Unable to open the identities: {err}"))?;
+    // both keys are re-encrypted before anything is written, so a wrong passphrase leaves everything as it was
+    let exported = reencrypt_key(&db, old_passphrase, new_passphrase)?.ok_or_else(|| String::from("The store is not encrypted. Encryption can only be enabled for a new store."))?;
+    let identities_exported = reencrypt_key(&identities, old_passphrase, new_passphrase)?;
+    write_key(&db, exported)?;
+    if let Some(identities_exported) = identities_exported {
+        write_key(&identities, identities_exported)?;
+    }
+    Ok(())
+}
+
+/*
+ * Copies a directory with all its contents.
+ */
+pub fn copy_dir(
+    from: &std::path::Path,
+    to: &std::path::Path,
+) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Copies the store next to itself, suffixed with the current time.
+ *
+ * The store must be closed. Returns the path of the backup.
+ */
+pub fn backup(store_path: &str) -> Result<String, String> {
+    let suffix = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let backup_path = format!("{store_path}.backup-{suffix}");
+    copy_dir(std::path::Path::new(store_path), std::path::Path::new(&backup_path)).map_err(|err| format!("Unable to back up the store to {backup_path}: {err}"))?;
+    Ok(backup_path)
+}
+
+/*
+ * Removes the store with all its contents.
+ *
+ * The store must be closed.
+ */
+pub fn wipe(store_path: &str) -> Result<(), String> {
+    match std::fs::remove_dir_all(store_path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(format!("Unable to remove the store: {err}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn encryption_is_checked() {
+        let dir = crate::testing::TestDir::new("store-encryption");
+        let plain = dir.path("plain");
+        let encrypted = dir.path("encrypted");
+        // not created yet
+        assert!(super::check_encryption(&plain, None).is_ok());
+        assert!(super::check_encryption(&plain, Some("correct horse")).is_ok());
+        sled::open(&plain).unwrap().flush().unwrap();
+        let db = sled::open(&encrypted).unwrap();
+        db.insert(super::STORE_CIPHER_KEY, b"exported key".to_vec()).unwrap();
+        db.flush().unwrap();
+        drop(db);
+        assert!(super::check_encryption(&plain, None).is_ok());
+        assert!(super::check_encryption(&plain, Some("correct horse")).is_err());
+        assert!(super::check_encryption(&encrypted, Some("correct horse")).is_ok());
+        assert!(super::check_encryption(&encrypted, None).is_err());
+    }
+}
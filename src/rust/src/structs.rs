@@ -1,5 +1,8 @@
 /*
  *  Taken from presage-cli
+ *
+ * Commands which produce a result carry a `request_id` chosen by the front-end.
+ * It is echoed in the resulting events so the front-end can tell which request they belong to.
  */
 #[derive(Debug, Clone)]
 pub enum Cmd {
@@ -12,18 +15,290 @@ pub enum Cmd {
     InitialSync,
     Receive,
     Send {
+        request_id: u64,
         recipient: Recipient,
         message: Option<String>,
-        xfer: *const std::os::raw::c_void,
+        attachment: Option<String>,        // path to a file to send
+        xfer: *const std::os::raw::c_void, // opaque reference to the front-end's transfer, echoed in the result
+    },
+    ListGroups {
+        request_id: u64,
     },
-    ListGroups,
     GetGroupMembers {
+        request_id: u64,
         master_key_bytes: [u8; 32],
     },
+    ChangePassphrase {
+        request_id: u64,
+        passphrase: String,
+    },
+    ResolveMigrationConflict {
+        backup: bool, // copy the store before dropping it
+    },
+    ExportBackup {
+        request_id: u64,
+        path: String,
+        passphrase: String,
+        history: bool, // include messages
+    },
+    ImportBackup {
+        request_id: u64,
+        path: String,
+        passphrase: String,
+    },
+    Unlink {
+        request_id: u64,
+    },
+    AcceptIdentity {
+        request_id: u64,
+        uuid: presage::libsignal_service::prelude::Uuid,
+    },
+    GetSafetyNumber {
+        request_id: u64,
+        uuid: presage::libsignal_service::prelude::Uuid,
+    },
+    DownloadAttachment {
+        request_id: u64,
+        pointer: presage::proto::AttachmentPointer,
+        destination: Option<String>, // where to save the attachment, a temporary file if None
+    },
+    AbortDownload {
+        request_id: u64, // the request which started the download
+    },
+    SendTyping {
+        recipient: Recipient,
+        started: bool, // false if the user stopped typing
+    },
 }
 
-#[derive(Debug, Clone)]
+impl Cmd {
+    /*
+     * Name of the command for diagnostics. Does not include any payload.
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cmd::LinkDevice { .. } => "link_device",
+            Cmd::Exit => "exit",
+            Cmd::Whoami => "whoami",
+            Cmd::InitialSync => "initial_sync",
+            Cmd::Receive => "receive",
+            Cmd::Send { .. } => "send",
+            Cmd::ListGroups { .. } => "list_groups",
+            Cmd::GetGroupMembers { .. } => "get_group_members",
+            Cmd::ChangePassphrase { .. } => "change_passphrase",
+            Cmd::ResolveMigrationConflict { .. } => "resolve_migration_conflict",
+            Cmd::ExportBackup { .. } => "export_backup",
+            Cmd::ImportBackup { .. } => "import_backup",
+            Cmd::Unlink { .. } => "unlink",
+            Cmd::AcceptIdentity { .. } => "accept_identity",
+            Cmd::GetSafetyNumber { .. } => "get_safety_number",
+            Cmd::DownloadAttachment { .. } => "download_attachment",
+            Cmd::AbortDownload { .. } => "abort_download",
+            Cmd::SendTyping { .. } => "send_typing",
+        }
+    }
+
+    /*
+     * The request id chosen by the front-end. Zero for commands which do not have one.
+     */
+    pub fn request_id(&self) -> u64 {
+        match self {
+            Cmd::Send { request_id, .. } | Cmd::ListGroups { request_id } | Cmd::GetGroupMembers { request_id, .. } | Cmd::ChangePassphrase { request_id, .. } | Cmd::ExportBackup { request_id, .. } | Cmd::ImportBackup { request_id, .. } | Cmd::Unlink { request_id } | Cmd::AcceptIdentity { request_id, .. } | Cmd::GetSafetyNumber { request_id, .. } | Cmd::DownloadAttachment { request_id, .. } => *request_id,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Recipient {
     Contact(presage::libsignal_service::prelude::Uuid),
     Group(presage::libsignal_service::zkgroup::GroupMasterKeyBytes),
 }
+
+/*
+ * Parses a hex-encoded group master key.
+ *
+ * Taken from presage-cli.
+ */
+pub fn parse_group_master_key(value: &str) -> Result<presage::libsignal_service::zkgroup::GroupMasterKeyBytes, String> {
+    let master_key_bytes = hex::decode(value).map_err(|err| format!("unable to decode hex string: {err}"))?;
+    master_key_bytes.try_into().map_err(|_| String::from("master key should be 32 bytes long"))
+}
+
+/*
+ * Derives the identifier of a group from its master key.
+ *
+ * Typing messages refer to groups by this identifier rather than by the master key.
+ */
+pub fn group_identifier(master_key: &presage::libsignal_service::zkgroup::GroupMasterKeyBytes) -> presage::libsignal_service::zkgroup::GroupIdentifierBytes {
+    let master_key = presage::libsignal_service::zkgroup::groups::GroupMasterKey::new(*master_key);
+    presage::libsignal_service::zkgroup::groups::GroupSecretParams::derive_from_master_key(master_key).get_group_identifier()
+}
+
+/*
+ * Decodes an attachment pointer as handed out in `Event::Attachment`.
+ */
+pub fn parse_attachment_pointer(value: &[u8]) -> Result<presage::proto::AttachmentPointer, String> {
+    <presage::proto::AttachmentPointer as presage::libsignal_service::prelude::ProtobufMessage>::decode(value).map_err(|err| format!("unable to decode attachment pointer: {err}"))
+}
+
+/*
+ * Events reported by the back-end to the front-end via `crate::sink::EventSink`.
+ */
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(tag = "kind", content = "data", rename_all = "snake_case"))]
+pub enum Event {
+    Log {
+        level: i32, // PurpleDebugLevel
+        message: String,
+    },
+    Error {
+        request_id: Option<u64>, // the request which failed, None if the connection is affected
+        level: i32,              // PurpleConnectionError
+        message: String,
+    },
+    QrCode(String), // URL for linking, empty if linking has finished
+    Uuid(String),   // own uuid, empty if this device is not registered
+    Connected,
+    Text(Message),
+    Attachment(Attachment),
+    XferResult {
+        request_id: u64,
+        #[cfg_attr(feature = "serde", serde(skip))]
+        xfer: *const std::os::raw::c_void,
+        flags: u64,
+        error: Option<String>,
+    },
+    Groups {
+        request_id: u64, // zero if not requested explicitly
+        groups: Vec<Group>,
+    },
+    Contacts(Vec<Contact>),
+    Members {
+        request_id: u64,
+        group: Group,
+    },
+    PassphraseChanged {
+        request_id: u64,
+        error: Option<String>, // None on success
+    },
+    MigrationConflict, // the store cannot be opened without dropping its contents
+    StoreReset {
+        backup_path: Option<String>,
+    },
+    BackupExported {
+        request_id: u64,
+        error: Option<String>, // None on success
+    },
+    BackupImported {
+        request_id: u64,
+        error: Option<String>, // None on success
+    },
+    Unlinked {
+        request_id: u64,
+        error: Option<String>, // None on success
+    },
+    IdentityChanged {
+        who: String,    // the contact whose identity has changed
+        accepted: bool, // false if the new identity must be accepted before messages can be sent
+        digits: String, // the new safety number
+    },
+    IdentityAccepted {
+        request_id: u64,
+        who: String,
+        error: Option<String>, // None on success
+    },
+    SafetyNumber {
+        request_id: u64,
+        who: String,
+        digits: String,     // for comparing visually, in groups of five
+        #[cfg_attr(feature = "serde", serde(serialize_with = "hex::serde::serialize"))]
+        scannable: Vec<u8>, // for encoding as QR code
+    },
+    DownloadProgress {
+        request_id: u64,
+        size: usize, // of the file written so far
+    },
+    AttachmentDownloaded {
+        request_id: u64,
+        path: Option<String>, // None on failure
+        temporary: bool,      // the receiver must take care of removing the file
+        size: usize,
+        error: Option<String>, // None on success
+    },
+    Typing {
+        who: String,
+        group: Option<String>, // None if the contact is typing in a direct conversation
+        started: bool,         // false if the contact stopped typing
+    },
+    Receipt {
+        kind: ReceiptKind,
+        timestamp: u64, // of the message the receipt is about
+        who: String,    // the contact who sent the receipt
+        name: Option<String>,
+        group: Option<String>, // None if the message was sent in a direct conversation
+        title: Option<String>,
+        body: Option<String>, // first line of the message, None if it has no text
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "snake_case"))]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+    Viewed, // e.g. a voice message has been played
+}
+
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Message {
+    pub request_id: u64, // zero for incoming messages
+    pub timestamp: u64,
+    pub flags: u64, // PurpleMessageFlags
+    pub who: Option<String>,
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub title: Option<String>,
+    pub body: String,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Attachment {
+    pub timestamp: u64,
+    pub flags: u64,          // PurpleMessageFlags, tells received from synced attachments
+    pub who: Option<String>, // sender, recipient of a synced attachment or empty for a synced attachment in a group
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub title: Option<String>,
+    pub filename: String, // as chosen by the sender, without any directories
+    pub content_type: String,
+    pub size: u64,   // in bytes, zero if unknown
+    pub width: u32,  // of images and videos in pixels, zero if unknown
+    pub height: u32, // of images and videos in pixels, zero if unknown
+    pub voice_note: bool,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "hex::serde::serialize"))]
+    pub thumbnail: Vec<u8>, // small preview image sent along with e.g. videos, empty if there is none
+    #[cfg_attr(feature = "serde", serde(serialize_with = "hex::serde::serialize"))]
+    pub pointer: Vec<u8>, // encoded AttachmentPointer, see `Cmd::DownloadAttachment`
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Group {
+    pub key: String,
+    pub title: String,
+    pub description: String,
+    pub revision: u32,
+    pub members: Vec<String>,
+    pub population: usize,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Contact {
+    pub uuid: String,
+    pub name: Option<String>,
+    pub phone_number: Option<String>,
+}
@@ -0,0 +1,30 @@
+/*
+ * Helpers shared by the tests.
+ */
+
+/*
+ * A directory of its own for each test, removed when the test ends.
+ */
+pub struct TestDir(std::path::PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("presage-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(
+        &self,
+        name: &str,
+    ) -> String {
+        self.0.join(name).to_str().unwrap().to_owned()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}